@@ -0,0 +1,145 @@
+//! RFC6143 §7.2.2. VNC Authentication
+
+use std::{fmt::Write, process::Stdio, time::Duration};
+
+use anyhow::Context;
+use des::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Des,
+};
+use log::warn;
+use tokio::{io::AsyncWriteExt, time};
+
+use crate::source;
+
+pub(crate) const CHALLENGE_LEN: usize = 16;
+
+/// Only the first 8 bytes of password are used
+pub(crate) const MAX_PASSWORD_LEN: usize = 8;
+
+/// Deadline for `--auth-command` to decide, rejecting the client after it
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How responses to VNC Authentication challenges are checked.
+#[derive(Debug, Clone, Copy)]
+pub enum Verifier<'a> {
+    /// Compare with the response expected from this password.
+    Password(&'a str),
+    /// Run this shell command with the challenge and the response written
+    /// to its stdin, as two lines of hex. Accepted if it exits with 0.
+    Command(&'a str),
+}
+
+impl Verifier<'_> {
+    /// Whether response is accepted, failing closed on errors of command.
+    pub(crate) async fn verify(
+        &self,
+        challenge: &[u8; CHALLENGE_LEN],
+        response: &[u8; CHALLENGE_LEN],
+    ) -> bool {
+        let command = match *self {
+            Self::Password(password) => return verify(password, challenge, response),
+            Self::Command(command) => command,
+        };
+        match time::timeout(COMMAND_TIMEOUT, run_command(command, challenge, response)).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(err)) => {
+                warn!("Authentication command failed: {:#}", err);
+                false
+            }
+            Err(_) => {
+                warn!("Authentication command timed out");
+                false
+            }
+        }
+    }
+}
+
+async fn run_command(
+    command: &str,
+    challenge: &[u8; CHALLENGE_LEN],
+    response: &[u8; CHALLENGE_LEN],
+) -> anyhow::Result<bool> {
+    let mut input = String::new();
+    for line in [challenge, response] {
+        for byte in line {
+            write!(input, "{:02x}", byte)?;
+        }
+        input.push('\n');
+    }
+    // Killed if timed out
+    let mut child = source::shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Spawn authentication command")?;
+    let mut stdin = child.stdin.take().context("Open stdin of command")?;
+    // Command may decide without reading it all
+    if let Err(err) = stdin.write_all(input.as_bytes()).await {
+        warn!("Failed to write to authentication command: {}", err);
+    }
+    drop(stdin);
+    let status = child.wait().await.context("Wait authentication command")?;
+    Ok(status.success())
+}
+
+pub(crate) fn new_challenge() -> anyhow::Result<[u8; CHALLENGE_LEN]> {
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    getrandom::fill(&mut challenge)
+        .map_err(|err| anyhow::anyhow!("Generate challenge: {}", err))?;
+    Ok(challenge)
+}
+
+/// Encrypt challenge with DES, keyed by password truncated or zero-padded
+/// to 8 bytes, with bits of each key byte reversed like the reference
+/// implementation does.
+pub(crate) fn response(password: &str, challenge: &[u8; CHALLENGE_LEN]) -> [u8; CHALLENGE_LEN] {
+    let mut key = [0u8; 8];
+    for (k, p) in key.iter_mut().zip(password.bytes()) {
+        *k = p.reverse_bits();
+    }
+    let des = Des::new(&key.into());
+    let mut response = *challenge;
+    for block in response.chunks_exact_mut(8) {
+        des.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    response
+}
+
+/// Check client's response in constant time.
+pub(crate) fn verify(
+    password: &str,
+    challenge: &[u8; CHALLENGE_LEN],
+    response: &[u8; CHALLENGE_LEN],
+) -> bool {
+    let expected = self::response(password, challenge);
+    expected
+        .iter()
+        .zip(response)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reference responses, DES-ECB keyed by bit-reversed password bytes.
+    #[test]
+    fn des_key_vectors() {
+        let challenge = b"0123456789abcdef";
+        let vectors = [
+            ("secret", "752440ee2bfcc2a0d9013fd20371e23b"),
+            // Truncated to "password"
+            ("password12", "5645abeb5f1e6475e8feb11beb66ea19"),
+        ];
+        for (password, expected) in vectors {
+            let response = response(password, challenge);
+            let hex: String = response.iter().map(|b| format!("{:02x}", b)).collect();
+            assert_eq!(hex, expected, "{}", password);
+            assert!(verify(password, challenge, &response));
+            assert!(!verify("wrong", challenge, &response));
+        }
+    }
+}
@@ -1,23 +1,732 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+};
 
-use clap::Parser;
+use clap::{ArgAction, ArgGroup, Parser, ValueEnum};
+use image::{imageops::FilterType, Rgb};
+use log::LevelFilter;
 
-#[derive(Parser, Debug)]
+use crate::{rfp, server::Options};
+
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
-pub(crate) struct Args {
-    /// TCP address to listen
-    #[arg(short, long, default_value = "[::]:5900")]
-    pub(crate) listen: SocketAddr,
+#[command(group(
+    ArgGroup::new("source")
+        .required(true)
+        .args(["background", "exec", "slideshow", "solid", "gradient", "display"])
+))]
+pub struct Args {
+    /// Take options missing from command line from this TOML file, keyed
+    /// by their long names (e.g. max-fps = 30). Environment variables
+    /// like VNCDISPLAY_MAX_FPS=30 take precedence over the file, with
+    /// multiple values separated by commas (semicolons for layers).
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// TCP address to listen, or "unix:PATH" for a Unix domain socket,
+    /// can be given multiple times, ignored under systemd socket activation
+    /// [default: [::]:5900 without --connect or --websocket]
+    #[arg(short, long, value_parser = parse_listen)]
+    pub listen: Vec<ListenAddr>,
+
+    /// TCP address to accept WebSocket clients such as noVNC on,
+    /// can be given multiple times
+    #[cfg(feature = "websocket")]
+    #[arg(long, value_name = "ADDR")]
+    pub websocket: Vec<SocketAddr>,
+
+    /// Serve another picture on another address, given as ADDR=PICTURE
+    /// with ADDR like --listen and PICTURE like --background. Repeat for
+    /// each display, all sharing the rest of options such as --name.
+    #[arg(
+        long,
+        value_name = "ADDR=PICTURE",
+        value_parser = parse_display,
+        conflicts_with_all = ["listen", "connect", "once"]
+    )]
+    pub display: Vec<DisplaySpec>,
+
+    /// Exit if any --listen address fails, instead of skipping it
+    #[arg(long)]
+    pub strict_listen: bool,
+
+    /// Advertise the first TCP --listen address as _rfb._tcp over
+    /// mDNS/DNS-SD, named NAME or the desktop name
+    #[cfg(feature = "mdns")]
+    #[arg(long, value_name = "NAME", num_args = 0..=1)]
+    pub advertise: Option<Option<String>>,
+
+    /// Connect to a viewer in listening mode (usually on port 5500),
+    /// reconnecting after each session ends
+    #[arg(long, value_name = "HOST:PORT")]
+    pub connect: Option<String>,
+
+    /// Exit after the session with --connect viewer ends
+    #[arg(long, requires = "connect")]
+    pub connect_once: bool,
 
-    /// Background picture
+    /// Serve the first client only, dropping others connecting meanwhile,
+    /// then exit with failure if its session failed
+    #[arg(long, conflicts_with = "connect")]
+    pub once: bool,
+
+    /// Background picture, "-" to read it from stdin, or an HTTP(S) URL to
+    /// fetch it from (built with the `remote` feature)
     #[arg(short, long)]
-    pub(crate) background: PathBuf,
+    pub background: Option<PathBuf>,
+
+    /// Background picture read from stdin or fetched, see --background
+    #[arg(skip)]
+    pub background_data: Option<Vec<u8>>,
+
+    /// Reload background picture when it's changed on disk
+    #[arg(long, requires = "background")]
+    pub watch: bool,
+
+    /// Fill background with this color (RRGGBB), instead of a picture
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color, requires = "size")]
+    pub solid: Option<Rgb<u8>>,
+
+    /// Fill background with vertical gradient between two colors
+    #[arg(long, value_name = "TOP,BOTTOM", value_parser = parse_gradient, requires = "size")]
+    pub gradient: Option<(Rgb<u8>, Rgb<u8>)>,
+
+    /// Size of background filled by --solid or --gradient
+    #[arg(
+        long,
+        value_name = "WxH",
+        value_parser = parse_size,
+        conflicts_with_all = ["background", "exec", "slideshow"]
+    )]
+    pub size: Option<(u16, u16)>,
+
+    /// Run shell command and show binary PPM frames written to its stdout
+    #[arg(long)]
+    pub exec: Option<String>,
+
+    /// Restart the command after it exits, instead of keeping its last frame
+    #[arg(long, requires = "exec")]
+    pub exec_restart: bool,
+
+    /// Cycle through pictures in this directory, in order of file names.
+    /// Smaller ones are centered on the size of the largest.
+    #[arg(long, value_name = "DIR")]
+    pub slideshow: Option<PathBuf>,
+
+    /// Show each picture of slideshow for this seconds
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "slideshow"
+    )]
+    pub slide_interval: u64,
+
+    /// Composite transparent background picture over this color (RRGGBB)
+    #[arg(long, value_parser = parse_color)]
+    pub flatten_color: Option<Rgb<u8>>,
+
+    /// Scale background picture to this size
+    #[arg(long, value_name = "WxH", value_parser = parse_size, conflicts_with = "exec")]
+    pub resolution: Option<(u16, u16)>,
+
+    /// How to scale background picture of a different aspect ratio
+    #[arg(long, value_enum, default_value_t, requires = "resolution")]
+    pub aspect: Aspect,
+
+    /// Resampling filter for scaling background picture
+    #[arg(long, value_enum, default_value_t, requires = "resolution")]
+    pub scale_filter: ScaleFilter,
+
+    /// Color of bars around letterboxed background picture (RRGGBB)
+    #[arg(long, value_parser = parse_color, default_value = "000000", requires = "resolution")]
+    pub fill_color: Rgb<u8>,
+
+    /// Composite picture onto background at X,Y [default: 0,0], honoring its
+    /// transparency. Repeat to stack layers in order, each clipped to
+    /// background
+    #[arg(long, value_name = "PATH[@X,Y]", value_parser = parse_layer, conflicts_with = "exec")]
+    pub layer: Vec<Layer>,
+
+    /// Draw QR code of URL on background, "{ip}" and "{port}" are replaced
+    /// with the first listening address (socket path and nothing for Unix
+    /// socket)
+    #[arg(long, num_args = 0..=1, default_missing_value = "vnc://{ip}:{port}")]
+    pub qr: Option<String>,
+
+    /// Side length of QR code in pixels [default: 4 pixels per module]
+    #[arg(long, requires = "qr")]
+    pub qr_size: Option<u32>,
+
+    /// Corner of background to put QR code on
+    #[arg(long, value_enum, default_value_t, requires = "qr")]
+    pub qr_position: Corner,
+
+    /// Draw text on background. "{date}" and "{time}" are replaced with
+    /// current UTC time, and the text is redrawn every second.
+    #[arg(long, value_name = "TEMPLATE")]
+    pub overlay_text: Option<String>,
+
+    /// Draw a clock on background, same as --overlay-text "{time}"
+    #[arg(long, conflicts_with = "overlay_text")]
+    pub overlay_clock: bool,
+
+    /// Corner of background to put text on
+    #[arg(long, value_enum, default_value = "top-left")]
+    pub overlay_position: Corner,
+
+    /// Font size of text in pixels
+    #[arg(long, value_name = "PIXELS", default_value_t = 24, value_parser = clap::value_parser!(u16).range(1..))]
+    pub overlay_size: u16,
+
+    /// Color of text (RRGGBB)
+    #[arg(long, value_parser = parse_color, default_value = "ffffff")]
+    pub overlay_color: Rgb<u8>,
 
     /// Pointer picture
     #[arg(short, long)]
-    pub(crate) pointer: Option<PathBuf>,
+    pub pointer: Option<PathBuf>,
+
+    /// Point of pointer picture at the pointer position [default: center]
+    #[arg(long, value_name = "X,Y", value_parser = parse_position, requires = "pointer")]
+    pub pointer_hotspot: Option<(u16, u16)>,
+
+    /// Place client's cursor at X,Y with the PointerPos pseudo-encoding,
+    /// for clients supporting it. Works with or without --pointer, which
+    /// only sets the cursor shape.
+    #[arg(long, value_name = "X,Y", value_parser = parse_position)]
+    pub cursor_pos: Option<(u16, u16)>,
+
+    /// Re-read background & pointer pictures for client pressing this key,
+    /// given as X keysym (e.g. 0xffc2 for F5)
+    #[arg(
+        long,
+        value_name = "KEYSYM",
+        value_parser = parse_keysym,
+        conflicts_with_all = ["exec", "slideshow", "watch"]
+    )]
+    pub reload_key: Option<u32>,
+
+    /// Disconnect client when it presses this key, given as X keysym
+    #[arg(long, value_name = "KEYSYM", value_parser = parse_keysym)]
+    pub quit_key: Option<u32>,
+
+    /// Drop keyboard, pointer & clipboard input from clients, and mark the
+    /// desktop name with "(read-only)". Client's cursor is no longer kept
+    /// where it points, but stays at --cursor-pos if given.
+    #[arg(long, conflicts_with_all = ["reload_key", "quit_key", "dump_clipboard"])]
+    pub read_only: bool,
+
+    /// Disconnect clients sending extension messages that are known but
+    /// not supported (xvp, gii & QEMU audio), instead of skipping them
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Desktop name [default: VNC Display]
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// Read desktop name from this file each time a client connects,
+    /// unless --name is given
+    #[arg(long, value_name = "PATH")]
+    pub name_file: Option<PathBuf>,
+
+    /// Send this text to client's clipboard after connected
+    #[arg(long)]
+    pub clipboard: Option<String>,
+
+    /// Send content of this file to client's clipboard after connected
+    #[arg(long, value_name = "PATH", conflicts_with = "clipboard")]
+    pub clipboard_file: Option<PathBuf>,
+
+    /// Append text received from client's clipboard to this file
+    #[arg(long, value_name = "PATH")]
+    pub dump_clipboard: Option<PathBuf>,
+
+    /// Truncate text received from client's clipboard to this bytes,
+    /// clients sending more than 1 MiB are disconnected anyway
+    #[arg(long, value_name = "BYTES", default_value_t = rfp::MAX_CUT_TEXT_LEN)]
+    pub max_clipboard_len: usize,
+
+    /// Require VNC authentication with this password (up to 8 bytes)
+    #[arg(long)]
+    pub password: Option<String>,
+
+    /// Require VNC authentication, accepting clients for which this shell
+    /// command exits with 0. The 16-byte challenge and the client's DES
+    /// response are written to its stdin as two lines of hex; it's killed
+    /// and the client rejected after 10 seconds. Checking the response
+    /// takes the plain password, so the command can't be backed by hashed
+    /// ones like PAM's, and anyone reading its input can brute-force the
+    /// password offline.
+    #[arg(long, value_name = "CMD", conflicts_with = "password")]
+    pub auth_command: Option<String>,
+
+    /// Offer only VeNCrypt security type, encrypting sessions with TLS by
+    /// this PEM certificate chain. Clients have to trust it, as X509None
+    /// (or X509Vnc with --password) is the only subtype.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Maximum number of concurrent clients
+    #[arg(long)]
+    pub max_clients: Option<usize>,
+
+    /// Number of connections waiting for a free client slot,
+    /// connections beyond it are closed
+    #[arg(long, default_value_t = 0)]
+    pub accept_queue: usize,
+
+    /// Close connections waited in the queue for this seconds
+    #[arg(long, value_name = "SECONDS", requires = "accept_queue")]
+    pub queue_timeout: Option<u64>,
+
+    /// Ring client's bell every this seconds
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub bell_interval: Option<u64>,
+
+    /// Send Raw updates in bands of at most this rows, each encoded just
+    /// before it's written, bounding memory & write size on large screens
+    #[arg(long, value_name = "ROWS", default_value_t = 256, value_parser = clap::value_parser!(u16).range(1..))]
+    pub raw_band_rows: u16,
+
+    /// Send full updates to each client at most this times per second,
+    /// requests beyond it are merged and delayed
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_fps: Option<u32>,
+
+    /// Pace writes to each client at this bytes per second, sending large
+    /// updates out in chunks of a tenth of it
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_bandwidth: Option<u64>,
+
+    /// Disconnect clients sending nothing for this seconds, 0 to disable.
+    /// Not counting time waiting on a requested update
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    pub idle_timeout: u64,
+
+    /// Show each client a solid frame after it sends no keyboard, pointer,
+    /// or clipboard input for this seconds, until it does again
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "read_only"
+    )]
+    pub blank_after: Option<u64>,
+
+    /// Color of the frame shown while blanked (RRGGBB)
+    #[arg(long, value_parser = parse_color, default_value = "000000", requires = "blank_after")]
+    pub blank_color: Rgb<u8>,
+
+    /// On Ctrl-C or SIGTERM, wait for clients to disconnect for at most
+    /// this seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub shutdown_timeout: u64,
+
+    /// TCP address answering "OK" once ready to serve, for health checks
+    #[arg(long, value_name = "ADDR")]
+    pub health_addr: Option<SocketAddr>,
+
+    /// TCP address to serve Prometheus metrics on, at /metrics
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "ADDR")]
+    pub metrics: Option<SocketAddr>,
+
+    /// Format of log lines on stderr
+    #[arg(long, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+
+    /// Log more, repeated for info, debug & trace [default: warn].
+    /// RUST_LOG takes precedence if set.
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Log errors only
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Warn if event loop is stuck for longer than this milliseconds
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub watchdog_ms: Option<u64>,
+
+    /// Adjust defaults for a known client
+    #[arg(long, value_enum, default_value_t)]
+    pub client_profile: ClientProfile,
+
+    /// Pixel format offered to clients, used until they ask for another
+    #[arg(long, value_enum, default_value_t)]
+    pub pixel_format: PixelLayout,
+
+    /// Send colors at 16-bit (5-6-5) precision even to clients asking for
+    /// more, dropping the low bits so updates compress better on slow links
+    #[arg(long)]
+    pub force_16bpp: bool,
+
+    /// Send RRE to clients supporting it if the screen has at most 16 colors
+    /// on connection, whatever their order of preference
+    #[arg(long)]
+    pub auto_encoding: bool,
+
+    /// How changed area is partitioned into rectangles on incremental updates
+    #[arg(long, value_enum, default_value_t)]
+    pub rect_strategy: RectStrategy,
+}
+
+/// Listening address unless given --listen, --connect or --websocket
+pub const DEFAULT_LISTEN: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 5900);
+
+/// Address to accept clients on
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(feature = "websocket")]
+    WebSocket(SocketAddr),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(addr) => write!(f, "ws://{}", addr),
+        }
+    }
+}
+
+/// Picture served on its own address
+#[derive(Clone, Debug)]
+pub struct DisplaySpec {
+    pub addr: ListenAddr,
+    pub picture: PathBuf,
+}
+
+/// Picture composited onto background
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub path: PathBuf,
+    pub position: (u16, u16),
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// JSON object per line, with key-values as fields
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum RectStrategy {
+    /// One rectangle bounding all changes
+    Bounding,
+    /// One rectangle per changed tile
+    Tiles,
+    /// Bounding rectangle if changes are dense, tiles otherwise
+    #[default]
+    Auto,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum PixelLayout {
+    /// 32 bits, red in the third byte of little-endian pixel
+    #[default]
+    Rgb888,
+    /// 32 bits, red in the first byte of little-endian pixel
+    Bgr888,
+    /// 16 bits, 5 for red & blue, 6 for green
+    Rgb565,
+}
+
+impl From<PixelLayout> for rfp::PixelFormat {
+    fn from(layout: PixelLayout) -> Self {
+        match layout {
+            PixelLayout::Rgb888 => *rfp::PIXEL_FORMAT_RGB888,
+            PixelLayout::Bgr888 => *rfp::PIXEL_FORMAT_BGR888,
+            PixelLayout::Rgb565 => *rfp::PIXEL_FORMAT_RGB565,
+        }
+    }
+}
+
+impl Args {
+    /// Settings for serving clients.
+    pub fn options(&self) -> Options {
+        Options {
+            name: self.name.clone().unwrap_or_else(|| Options::default().name),
+            name_file: self.name_file.clone().filter(|_| self.name.is_none()),
+            password: self.password.clone(),
+            auth_command: self.auth_command.clone(),
+            clipboard: self.clipboard.clone(),
+            dump_clipboard: self.dump_clipboard.clone(),
+            max_clipboard_len: self.max_clipboard_len,
+            max_clients: self.max_clients,
+            accept_queue: self.accept_queue,
+            queue_timeout: self.queue_timeout,
+            bell_interval: self.bell_interval,
+            cursor_pos: self.cursor_pos,
+            reload_key: self.reload_key,
+            quit_key: self.quit_key,
+            read_only: self.read_only,
+            strict: self.strict,
+            client_profile: self.client_profile,
+            rect_strategy: self.rect_strategy,
+            auto_encoding: self.auto_encoding,
+            raw_band_rows: self.raw_band_rows,
+            pixel_format: self.pixel_format.into(),
+            force_16bpp: self.force_16bpp,
+            max_fps: self.max_fps,
+            max_bandwidth: self.max_bandwidth,
+            idle_timeout: self.idle_timeout,
+            blank_after: self.blank_after,
+            blank_color: self.blank_color,
+            shutdown_timeout: self.shutdown_timeout,
+            connect: self.connect.clone(),
+            connect_once: self.connect_once,
+            once: self.once,
+            // Loaded from files by caller, see --tls-cert
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Log level by --verbose & --quiet.
+    pub fn log_level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::Error,
+            (false, 0) => LevelFilter::Warn,
+            (false, 1) => LevelFilter::Info,
+            (false, 2) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        }
+    }
+
+    /// Text to draw on background, with time placeholders if any.
+    pub fn overlay_template(&self) -> Option<String> {
+        let template = match self.overlay_text.as_ref() {
+            Some(text) => text.clone(),
+            None if self.overlay_clock => "{time}".into(),
+            None => return None,
+        };
+        Some(self.expand_placeholders(&template))
+    }
+
+    /// Replace "{ip}" and "{port}" with the first listening address.
+    pub fn expand_placeholders(&self, template: &str) -> String {
+        let (ip, port) = match self.listen.first() {
+            Some(ListenAddr::Tcp(addr)) => (addr.ip().to_string(), addr.port().to_string()),
+            #[cfg(unix)]
+            Some(ListenAddr::Unix(path)) => (path.display().to_string(), String::new()),
+            #[cfg(feature = "websocket")]
+            Some(ListenAddr::WebSocket(addr)) => (addr.ip().to_string(), addr.port().to_string()),
+            None => Default::default(),
+        };
+        template.replace("{ip}", &ip).replace("{port}", &port)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum Aspect {
+    /// Keep aspect ratio, fill the rest with --fill-color
+    #[default]
+    Fit,
+    /// Keep aspect ratio, crop the overflow
+    Fill,
+    /// Ignore aspect ratio
+    Stretch,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum ScaleFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl From<ScaleFilter> for FilterType {
+    fn from(filter: ScaleFilter) -> Self {
+        match filter {
+            ScaleFilter::Nearest => Self::Nearest,
+            ScaleFilter::Triangle => Self::Triangle,
+            ScaleFilter::CatmullRom => Self::CatmullRom,
+            ScaleFilter::Gaussian => Self::Gaussian,
+            ScaleFilter::Lanczos3 => Self::Lanczos3,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+/// Interoperability tweaks for known clients
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum ClientProfile {
+    /// No adjustment
+    #[default]
+    Default,
+    /// macOS Screen Sharing: offer 32-bit RGB888, send Raw only (no
+    /// CopyRect either), and push the first frame once it set encodings
+    Macos,
+    /// TigerVNC: offer 32-bit RGB888, and push the first frame once it set
+    /// encodings
+    Tigervnc,
+    /// Remmina (libvncclient): push the first frame once it set encodings
+    Remmina,
+}
+
+/// Settings overridden by a client profile
+#[derive(Debug, Clone, Copy)]
+pub struct Profile {
+    /// Pixel format offered on ServerInit, instead of --pixel-format
+    pub pixel_format: Option<PixelLayout>,
+    /// Send a full update after the first SetEncodings, without a request
+    pub push_initial_frame: bool,
+    /// Send Raw rectangles only, whatever the client asks for
+    pub raw_only: bool,
+}
+
+impl ClientProfile {
+    pub fn overrides(self) -> Profile {
+        let neutral = Profile {
+            pixel_format: None,
+            push_initial_frame: false,
+            raw_only: false,
+        };
+        match self {
+            Self::Default => neutral,
+            Self::Macos => Profile {
+                pixel_format: Some(PixelLayout::Rgb888),
+                push_initial_frame: true,
+                raw_only: true,
+            },
+            Self::Tigervnc => Profile {
+                pixel_format: Some(PixelLayout::Rgb888),
+                push_initial_frame: true,
+                ..neutral
+            },
+            Self::Remmina => Profile {
+                push_initial_frame: true,
+                ..neutral
+            },
+        }
+    }
+}
+
+/// Parse TCP address, or Unix socket path with "unix:" prefix
+fn parse_listen(value: &str) -> Result<ListenAddr, String> {
+    let Some(path) = value.strip_prefix("unix:") else {
+        let addr = value.parse::<SocketAddr>().map_err(|err| err.to_string())?;
+        return Ok(ListenAddr::Tcp(addr));
+    };
+    if path.is_empty() {
+        return Err("socket path must not be empty".into());
+    }
+    #[cfg(unix)]
+    return Ok(ListenAddr::Unix(path.into()));
+    #[cfg(not(unix))]
+    Err("Unix socket is not supported on this platform".into())
+}
+
+/// Parse display in ADDR=PICTURE format
+fn parse_display(value: &str) -> Result<DisplaySpec, String> {
+    let (addr, picture) = value
+        .split_once('=')
+        .ok_or("display must be in ADDR=PICTURE format")?;
+    if picture.is_empty() {
+        return Err("picture path must not be empty".into());
+    }
+    Ok(DisplaySpec {
+        addr: parse_listen(addr)?,
+        picture: picture.into(),
+    })
+}
+
+/// Parse color in hex RRGGBB format, with optional leading "#"
+fn parse_color(value: &str) -> Result<Rgb<u8>, String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("color must be in RRGGBB format".into());
+    }
+    let rgb = u32::from_str_radix(hex, 16).map_err(|err| err.to_string())?;
+    let [_, r, g, b] = rgb.to_be_bytes();
+    Ok(Rgb([r, g, b]))
+}
+
+/// Parse two colors in RRGGBB,RRGGBB format
+fn parse_gradient(value: &str) -> Result<(Rgb<u8>, Rgb<u8>), String> {
+    let (top, bottom) = value
+        .split_once(',')
+        .ok_or("gradient must be in RRGGBB,RRGGBB format")?;
+    Ok((parse_color(top.trim())?, parse_color(bottom.trim())?))
+}
+
+/// Parse non-zero size in WxH format
+fn parse_size(value: &str) -> Result<(u16, u16), String> {
+    let (width, height) = value
+        .split_once(['x', 'X'])
+        .ok_or("size must be in WxH format")?;
+    let parse = |v: &str| match v.trim().parse::<u16>() {
+        Ok(0) => Err("size must not be zero".to_string()),
+        result => result.map_err(|err| err.to_string()),
+    };
+    Ok((parse(width)?, parse(height)?))
+}
+
+/// Parse position in X,Y format
+fn parse_position(value: &str) -> Result<(u16, u16), String> {
+    let (x, y) = value
+        .split_once(',')
+        .ok_or("position must be in X,Y format")?;
+    let parse = |v: &str| v.trim().parse::<u16>().map_err(|err| err.to_string());
+    Ok((parse(x)?, parse(y)?))
+}
+
+/// Parse layer in PATH[@X,Y] format, "@" being part of path if not followed
+/// by a position
+fn parse_layer(value: &str) -> Result<Layer, String> {
+    let (path, position) = match value.rsplit_once('@') {
+        Some((path, position)) if !path.is_empty() => match parse_position(position) {
+            Ok(position) => (path, position),
+            Err(_) => (value, (0, 0)),
+        },
+        _ => (value, (0, 0)),
+    };
+    if path.is_empty() {
+        return Err("layer path must not be empty".into());
+    }
+    Ok(Layer {
+        path: path.into(),
+        position,
+    })
+}
 
-    /// Desktop name
-    #[arg(short, long, default_value = "VNC Display")]
-    pub(crate) name: String,
+/// Parse X keysym in decimal, or hex with "0x" prefix
+fn parse_keysym(value: &str) -> Result<u32, String> {
+    match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|err| err.to_string())
 }
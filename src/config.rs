@@ -0,0 +1,273 @@
+//! Options from `VNCDISPLAY_*` environment variables and a TOML file, for
+//! those missing from the command line.
+
+use std::{
+    collections::HashSet,
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{error::ErrorKind, parser::ValueSource, Arg, ArgAction, Command, CommandFactory};
+use toml::{Table, Value};
+
+use crate::cli::Args;
+
+/// Prefix of environment variables, e.g. `VNCDISPLAY_MAX_FPS` for `--max-fps`
+pub const ENV_PREFIX: &str = "VNCDISPLAY_";
+
+/// Parse command line of this process with its environment, exiting on
+/// errors like [`clap::Parser::parse`] does. Returns warnings to be logged
+/// along with arguments.
+pub fn parse_args() -> (Args, Vec<String>) {
+    let vars = env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    parse_args_from(env::args_os(), vars).unwrap_or_else(|err| err.exit())
+}
+
+/// Parse arguments, falling back to `VNCDISPLAY_*` ones among environment
+/// variables given, then to the --config file.
+pub fn parse_args_from(
+    args: impl IntoIterator<Item = impl Into<OsString>>,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(Args, Vec<String>), clap::Error> {
+    let mut cmd = Args::command();
+    cmd.build();
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    // Only to find out what's on command line, errors are reported later
+    let given = cmd
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)?;
+
+    // Long option names in kebab case, along with variable names
+    let vars: Vec<_> = vars
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let key = name
+                .strip_prefix(ENV_PREFIX)?
+                .to_lowercase()
+                .replace('_', "-");
+            Some((key, value, name))
+        })
+        .collect();
+    let path = given.get_one::<PathBuf>("config").cloned().or_else(|| {
+        let (_, path, _) = vars.iter().find(|(key, _, _)| key == "config")?;
+        Some(path.into())
+    });
+    let file = match path {
+        Some(path) => read_config(&path).map_err(|msg| cmd.error(ErrorKind::Io, msg))?,
+        None => Table::new(),
+    };
+    let vars: Vec<_> = vars
+        .into_iter()
+        .map(|(key, value, name)| {
+            let source = format!("environment variable {}", name);
+            let arg = cmd.get_arguments().find(|arg| arg.get_long() == Some(&key));
+            let value = match arg.and_then(env_delimiter) {
+                Some(delimiter) => Value::Array(
+                    value
+                        .split(delimiter)
+                        .map(|value| Value::String(value.into()))
+                        .collect(),
+                ),
+                None => Value::String(value),
+            };
+            (key, value, source)
+        })
+        .collect();
+    let file = file
+        .into_iter()
+        .filter(|(key, _)| key != "config")
+        .map(|(key, value)| (key.replace('_', "-"), value, format!("config key {}", key)));
+
+    let mut warnings = Vec::new();
+    let mut extra = Vec::new();
+    let mut taken: HashSet<_> = cmd
+        .get_arguments()
+        .filter(|arg| given.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .map(|arg| arg.get_id().clone())
+        .collect();
+    for (key, value, source) in vars.into_iter().chain(file) {
+        let Some(arg) = cmd.get_arguments().find(|arg| arg.get_long() == Some(&key)) else {
+            warnings.push(format!("Unknown option in {}", source));
+            continue;
+        };
+        // Overridden by command line, or by environment for file
+        if taken.contains(arg.get_id()) || conflicts_with(&cmd, arg, &taken) {
+            continue;
+        }
+        match tokens(arg, &value) {
+            Ok(tokens) => extra.extend(tokens),
+            Err(msg) => {
+                let msg = format!("Invalid value of {}: {}", source, msg);
+                return Err(cmd.error(ErrorKind::InvalidValue, msg));
+            }
+        }
+        taken.insert(arg.get_id().clone());
+    }
+
+    // Program name, then options from file & environment, then command line
+    let mut args = args.into_iter();
+    let merged = args.next().into_iter().chain(extra).chain(args);
+    let matches = cmd.try_get_matches_from_mut(merged)?;
+    let args = <Args as clap::FromArgMatches>::from_arg_matches(&matches)
+        .map_err(|err| err.format(&mut cmd))?;
+    Ok((args, warnings))
+}
+
+fn read_config(path: &Path) -> Result<Table, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read config {}: {}", path.display(), err))?;
+    text.parse()
+        .map_err(|err| format!("Failed to parse config {}: {}", path.display(), err))
+}
+
+/// Whether arg can't be used with any of those, alone or in the same group.
+fn conflicts_with(cmd: &Command, arg: &Arg, ids: &HashSet<clap::Id>) -> bool {
+    let listed = |arg: &Arg, id: &clap::Id| {
+        cmd.get_arg_conflicts_with(arg)
+            .iter()
+            .any(|other| other.get_id() == id)
+    };
+    let grouped = |id: &clap::Id| {
+        cmd.get_groups().any(|group| {
+            !group.clone().is_multiple()
+                && group.get_args().any(|other| other == arg.get_id())
+                && group.get_args().any(|other| other == id)
+        })
+    };
+    cmd.get_arguments()
+        .filter(|other| ids.contains(other.get_id()))
+        .any(|other| {
+            listed(arg, other.get_id()) || listed(other, arg.get_id()) || grouped(other.get_id())
+        })
+}
+
+/// Separator of values in an environment variable, for options that can be
+/// given multiple times. Positions of layers have commas, so semicolons.
+fn env_delimiter(arg: &Arg) -> Option<char> {
+    match arg.get_action() {
+        ArgAction::Append if arg.get_id() == "layer" => Some(';'),
+        ArgAction::Append => Some(','),
+        _ => None,
+    }
+}
+
+/// Command line tokens for value of arg, arrays repeating it.
+fn tokens(arg: &Arg, value: &Value) -> Result<Vec<OsString>, String> {
+    let long = format!("--{}", arg.get_long().unwrap_or_default());
+    let flag = !arg.get_action().takes_values();
+    let counted = matches!(arg.get_action(), ArgAction::Count);
+    let optional = arg.get_num_args().is_some_and(|num| num.min_values() == 0);
+    let value = match value {
+        Value::Array(values) => {
+            let mut tokens = Vec::new();
+            for value in values {
+                tokens.extend(self::tokens(arg, value)?);
+            }
+            return Ok(tokens);
+        }
+        // Counted flags like --verbose, repeated
+        Value::Integer(n) if counted => {
+            let n = usize::try_from(*n).map_err(|_| format!("expect a count, got {}", n))?;
+            return Ok(vec![long.into(); n]);
+        }
+        Value::String(text) if counted => match text.parse() {
+            Ok(n) => return Ok(vec![long.into(); n]),
+            Err(_) => return Err(format!("expect a count, got {:?}", text)),
+        },
+        Value::Boolean(true) if flag || optional => return Ok(vec![long.into()]),
+        Value::Boolean(false) if flag => return Ok(vec![]),
+        // Flags from environment
+        Value::String(text) if flag => match text.as_str() {
+            "1" | "true" | "yes" | "on" => return Ok(vec![long.into()]),
+            "" | "0" | "false" | "no" | "off" => return Ok(vec![]),
+            _ => return Err(format!("expect true or false, got {:?}", text)),
+        },
+        Value::String(text) => text.clone(),
+        Value::Integer(n) => n.to_string(),
+        Value::Float(n) => n.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Datetime(time) => time.to_string(),
+        Value::Table(_) => return Err("tables are not supported".into()),
+    };
+    Ok(vec![format!("{}={}", long, value).into()])
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    const NO_VARS: [(String, String); 0] = [];
+
+    #[test]
+    fn config_file_as_flags() {
+        let path = env::temp_dir().join(format!("vncdisplay-{}.toml", std::process::id()));
+        let config = r#"
+            solid = "336699"
+            size = "320x240"
+            listen = ["127.0.0.1:5901", "unix:/tmp/vncdisplay.sock"]
+            max_fps = 15
+            name = "From file"
+            strict-listen = true
+            cursor-pos = "3,4"
+            verbose = 2
+            no-such-option = 1
+        "#;
+        fs::write(&path, config).unwrap();
+        let path = path.to_str().unwrap();
+        let (from_file, warnings) =
+            parse_args_from(["vncdisplay", "--config", path], NO_VARS).unwrap();
+        assert_eq!(warnings, ["Unknown option in config key no-such-option"]);
+        #[rustfmt::skip]
+        let flags = Args::parse_from([
+            "vncdisplay", "--config", path, "--solid", "336699", "--size", "320x240",
+            "-l", "127.0.0.1:5901", "-l", "unix:/tmp/vncdisplay.sock", "--max-fps", "15",
+            "-n", "From file", "--strict-listen", "--cursor-pos", "3,4", "-vv",
+        ]);
+        assert_eq!(format!("{:?}", from_file), format!("{:?}", flags));
+
+        // Command line first, then environment, then file
+        let vars = [
+            ("VNCDISPLAY_NAME".into(), "From env".into()),
+            ("VNCDISPLAY_MAX_FPS".into(), "20".into()),
+        ];
+        let args = ["vncdisplay", "--config", path, "--max-fps", "30"];
+        let (merged, _) = parse_args_from(args, vars).unwrap();
+        assert_eq!(merged.max_fps, Some(30));
+        assert_eq!(merged.name.as_deref(), Some("From env"));
+        assert_eq!(merged.size, Some((320, 240)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn environment_without_arguments() {
+        let vars = [
+            ("VNCDISPLAY_BACKGROUND", "/srv/picture.png"),
+            ("VNCDISPLAY_LISTEN", "[::1]:5900,unix:/tmp/vncdisplay.sock"),
+            ("VNCDISPLAY_LAYER", "a.png@1,2;b.png"),
+            ("VNCDISPLAY_NAME", "From env"),
+            ("VNCDISPLAY_READ_ONLY", "true"),
+            ("VNCDISPLAY_RECT_STRATEGY", "tiles"),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.into(), value.into()));
+        let (from_env, warnings) = parse_args_from(["vncdisplay"], vars).unwrap();
+        assert!(warnings.is_empty());
+        #[rustfmt::skip]
+        let flags = Args::parse_from([
+            "vncdisplay", "-b", "/srv/picture.png", "-l", "[::1]:5900",
+            "-l", "unix:/tmp/vncdisplay.sock", "--layer", "a.png@1,2", "--layer", "b.png",
+            "-n", "From env", "--read-only", "--rect-strategy", "tiles",
+        ]);
+        assert_eq!(format!("{:?}", from_env), format!("{:?}", flags));
+
+        let invalid = [("VNCDISPLAY_MAX_FPS".into(), "fast".into())];
+        let err = parse_args_from(["vncdisplay", "-b", "a.png"], invalid).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ValueValidation);
+    }
+}
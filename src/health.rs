@@ -0,0 +1,47 @@
+//! Health check endpoint for probes of load balancers & orchestrators.
+//!
+//! Every connection gets "OK\n" then closed, no protocol spoken. It only
+//! starts after the screen is ready and listeners are bound, so refused
+//! connections mean not (yet) ready.
+
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+const RESPONSE: &[u8] = b"OK\n";
+
+async fn serve(listener: TcpListener) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await.context("Accept connection")?;
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream).await {
+                debug!("Error on health check from {}: {}", peer, err);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> anyhow::Result<()> {
+    stream.write_all(RESPONSE).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Bind address and answer health checks on a background task.
+pub async fn spawn(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Listen for health checks on {}", addr))?;
+    info!("Health check on {}", addr);
+    tokio::spawn(async move {
+        if let Err(err) = serve(listener).await {
+            warn!("Health check stopped: {:#}", err);
+        }
+    });
+    Ok(())
+}
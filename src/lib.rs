@@ -0,0 +1,34 @@
+//! A VNC server showing pictures, usable as a library for serving your own
+//! frames.
+
+mod auth;
+pub mod cli;
+pub mod config;
+pub mod health;
+pub mod listener;
+pub mod logging;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod overlay;
+#[cfg(any(test, feature = "client"))]
+pub mod rfb_client;
+pub mod rfp;
+pub mod screen;
+mod server;
+pub mod source;
+#[cfg(feature = "tls")]
+pub mod vencrypt;
+pub mod watchdog;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+pub use auth::Verifier;
+pub use listener::Listener;
+pub use rfp::{
+    handshake, read_message, write_frame, ClientMessage, Encoding, FrameRectangle, PixelFormat,
+};
+pub use screen::{FrameSource, Screen};
+pub use server::{serve, serve_pipe, serve_until, serve_with, Options};
+pub use source::Frames;
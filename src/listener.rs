@@ -0,0 +1,173 @@
+//! Sockets accepting clients, TCP, Unix domain, or WebSocket.
+
+use std::io;
+#[cfg(unix)]
+use std::{fs, os::unix::fs::FileTypeExt, path::PathBuf};
+
+#[cfg(unix)]
+use anyhow::{bail, Context};
+#[cfg(unix)]
+use log::{debug, info, warn};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+use crate::cli::ListenAddr;
+#[cfg(feature = "websocket")]
+use crate::websocket::WebSocket;
+
+/// Connection with a client, whatever the socket type.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for S {}
+
+/// Bound socket. Unix socket file we created is removed on drop.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+    #[cfg(feature = "websocket")]
+    WebSocket(TcpListener),
+}
+
+impl Listener {
+    /// Bind to address, replacing stale Unix socket file left by a crashed
+    /// server.
+    pub async fn bind(addr: &ListenAddr) -> anyhow::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let is_socket =
+                    fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+                if is_socket {
+                    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                        bail!("Another server is listening on {}", path.display());
+                    }
+                    debug!("Remove stale socket {}", path.display());
+                    fs::remove_file(path).context("Remove stale socket")?;
+                }
+                Ok(Self::Unix(UnixListener::bind(path)?, Some(path.clone())))
+            }
+            #[cfg(feature = "websocket")]
+            ListenAddr::WebSocket(addr) => Ok(Self::WebSocket(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    /// Local port of a plain TCP socket.
+    pub fn tcp_port(&self) -> Option<u16> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+            _ => None,
+        }
+    }
+
+    /// Accept a client, along with its address for logging.
+    pub async fn accept(&self) -> io::Result<(Box<dyn Stream>, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                // Clients are usually unnamed
+                let (stream, peer) = listener.accept().await?;
+                let peer = match peer.as_pathname() {
+                    Some(peer) => format!("unix:{}", peer.display()),
+                    None => match listener.local_addr()?.as_pathname() {
+                        Some(path) => format!("client on unix:{}", path.display()),
+                        None => "client on unix socket".into(),
+                    },
+                };
+                Ok((Box::new(stream), peer))
+            }
+            #[cfg(feature = "websocket")]
+            Self::WebSocket(listener) => {
+                // Upgraded on first use, not to block accepting others
+                let (stream, peer) = listener.accept().await?;
+                Ok((
+                    Box::new(WebSocket::accept(stream)),
+                    format!("ws://{}", peer),
+                ))
+            }
+        }
+    }
+}
+
+/// Sockets passed by systemd socket activation, None if not activated.
+///
+/// systemd sets `LISTEN_PID` to our PID and `LISTEN_FDS` to the count of
+/// sockets, which start from fd 3. Both are unset afterwards so that
+/// children don't take them.
+pub fn activated() -> anyhow::Result<Option<Vec<Listener>>> {
+    #[cfg(unix)]
+    {
+        use std::{
+            env,
+            os::fd::{FromRawFd, OwnedFd},
+        };
+
+        const LISTEN_FDS_START: i32 = 3;
+
+        let pid = env::var("LISTEN_PID").ok();
+        if pid.as_deref() != Some(std::process::id().to_string().as_str()) {
+            return Ok(None);
+        }
+        let count: i32 = env::var("LISTEN_FDS")
+            .context("LISTEN_PID without LISTEN_FDS")?
+            .parse()
+            .context("Invalid LISTEN_FDS")?;
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        let mut listeners = Vec::new();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count) {
+            // Safety: fds from here on are passed to us by systemd
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            // Only a TCP socket has an IP address
+            let tcp = std::net::TcpListener::from(fd);
+            if let Ok(addr) = tcp.local_addr() {
+                tcp.set_nonblocking(true)?;
+                info!("Listen on {} from systemd", addr);
+                listeners.push(Listener::Tcp(TcpListener::from_std(tcp)?));
+                continue;
+            }
+            let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+            let addr = unix
+                .local_addr()
+                .context("Socket from systemd is neither TCP nor Unix domain")?;
+            unix.set_nonblocking(true)?;
+            match addr.as_pathname() {
+                Some(path) => info!("Listen on unix:{} from systemd", path.display()),
+                None => info!("Listen on Unix domain socket from systemd"),
+            }
+            // The socket file belongs to systemd
+            listeners.push(Listener::Unix(UnixListener::from_std(unix)?, None));
+        }
+        Ok(Some(listeners))
+    }
+    #[cfg(not(unix))]
+    Ok(None)
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Self::Unix(_, Some(path)) = self {
+            if let Err(err) = fs::remove_file(&*path) {
+                warn!("Failed to remove socket {}: {}", path.display(), err);
+            }
+        }
+    }
+}
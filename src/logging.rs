@@ -0,0 +1,66 @@
+//! Log lines on stderr, filtered by `RUST_LOG` or the level given.
+
+use std::{fmt::Write as _, io::Write};
+
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    LevelFilter,
+};
+
+use crate::cli::LogFormat;
+
+/// Set up the global logger, with level overridden by `RUST_LOG` if set.
+pub fn init(format: LogFormat, level: LevelFilter) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).parse_default_env();
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let mut line = format!(
+                r#"{{"timestamp":"{}","level":"{}","target":{},"message":{}"#,
+                buf.timestamp(),
+                record.level(),
+                json_string(record.target()),
+                json_string(&record.args().to_string()),
+            );
+            // Key-values become fields of their own
+            _ = record.key_values().visit(&mut JsonFields(&mut line));
+            line.push('}');
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.to_string()
+        } else if let Some(n) = value.to_i64() {
+            n.to_string()
+        } else if let Some(b) = value.to_bool() {
+            b.to_string()
+        } else {
+            json_string(&value.to_string())
+        };
+        write!(self.0, ",{}:{}", json_string(key.as_str()), value)?;
+        Ok(())
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str(r#"\""#),
+            '\\' => quoted.push_str(r"\\"),
+            '\n' => quoted.push_str(r"\n"),
+            c if c.is_control() => _ = write!(quoted, r"\u{:04x}", c as u32),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
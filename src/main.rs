@@ -1,99 +1,258 @@
-use anyhow::Context;
-use clap::Parser;
-use flate2::write::ZlibEncoder;
-use log::{debug, info};
-use rfp::FrameRectangle;
-use tokio::net::{TcpListener, TcpStream};
+use std::{
+    collections::HashSet,
+    fs,
+    future::{self, Future},
+    io,
+    sync::Arc,
+    time::Duration,
+};
 
-mod cli;
-mod rfp;
-mod screen;
-
-use screen::Screen;
+use anyhow::{bail, Context};
+use log::{info, warn};
+use tokio::{signal, sync::watch, task::JoinSet};
+use vncdisplay::{
+    cli::{self, Args},
+    config, health, listener, logging, overlay,
+    screen::{self, FrameSource, Screen},
+    source, watchdog, Listener,
+};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    let args = cli::Args::parse();
-    env_logger::init();
+    let (mut args, warnings) = config::parse_args();
+    logging::init(args.log_format, args.log_level());
+    for warning in warnings {
+        warn!("{}", warning);
+    }
 
-    let screen = Screen::create(args.background, args.pointer)
-        .context("Create screen from background picture")?;
+    let activated = listener::activated().context("Take sockets from systemd")?;
+    #[cfg(feature = "websocket")]
+    args.listen
+        .extend(args.websocket.drain(..).map(cli::ListenAddr::WebSocket));
+    if activated.is_some() && !args.listen.is_empty() {
+        warn!("Listen on sockets from systemd only, ignoring --listen");
+        args.listen.clear();
+    }
+    let default_listen = args.connect.is_none() && args.display.is_empty();
+    if args.listen.is_empty() && default_listen && activated.is_none() {
+        args.listen.push(cli::ListenAddr::Tcp(cli::DEFAULT_LISTEN));
+    }
+    if let Some(path) = args.clipboard_file.take() {
+        let text = fs::read_to_string(path).context("Read clipboard file")?;
+        args.clipboard = Some(text);
+    }
+    if args
+        .clipboard
+        .as_ref()
+        .is_some_and(|text| text.chars().any(|c| c > '\u{ff}'))
+    {
+        warn!("Characters beyond Latin-1 in clipboard are replaced with \"?\"");
+    }
+    let args = Arc::new(args);
 
-    info!("Listen on {}", args.listen);
-    let listener = TcpListener::bind(args.listen).await?;
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let peer = match stream.peer_addr() {
-            Ok(peer) => peer,
-            Err(err) => {
-                info!("Connect error: {}", err);
-                continue;
+    // Screens with addresses to listen on, a single one without --display
+    let mut displays = Vec::new();
+    if args.display.is_empty() {
+        let screen = create_screen(Args::clone(&args)).await?;
+        displays.push((screen, args.listen.clone()));
+    } else {
+        if activated.is_some() || !args.listen.is_empty() {
+            bail!("Cannot listen on sockets from systemd or --websocket with --display");
+        }
+        let mut addrs = HashSet::new();
+        for display in args.display.iter() {
+            if !addrs.insert(display.addr.to_string()) {
+                bail!("Duplicate --display address {}", display.addr);
             }
-        };
-        debug!("Connected with {}", peer);
+            let display_args = Args {
+                background: Some(display.picture.clone()),
+                ..Args::clone(&args)
+            };
+            let screen = create_screen(display_args)
+                .await
+                .with_context(|| format!("Create screen for {}", display.addr))?;
+            displays.push((screen, vec![display.addr.clone()]));
+        }
+    }
+    #[cfg(feature = "tls")]
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(vncdisplay::vencrypt::server_config(cert, key).context("Load TLS certificate")?)
+        }
+        _ => None,
+    };
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = args.metrics {
+        vncdisplay::metrics::spawn(addr).await?;
+    }
+    if let Some(ms) = args.watchdog_ms {
+        watchdog::spawn(Duration::from_millis(ms)).context("Start watchdog")?;
+    }
 
-        let screen = screen.clone();
-        let name = args.name.clone();
-        tokio::spawn(async move {
-            match handle_client(stream, screen, &name).await {
-                Ok(()) => debug!("Disconnected with {}", peer),
-                Err(err) => info!("Error on handle {}: {}", peer, err),
+    let mut activated = activated;
+    let mut servers = Vec::new();
+    for (screen, addrs) in displays {
+        let mut listeners = activated.take().unwrap_or_default();
+        for addr in addrs.iter() {
+            match Listener::bind(addr).await {
+                Ok(listener) => {
+                    info!("Listen on {}", addr);
+                    listeners.push(listener);
+                }
+                // Nothing else to listen on for the display
+                Err(err) if args.strict_listen || !args.display.is_empty() => {
+                    return Err(err.context(format!("Listen on {}", addr)));
+                }
+                Err(err) => warn!("Failed to listen on {}: {:#}", addr, err),
             }
-        });
+        }
+        if listeners.is_empty() && args.connect.is_none() {
+            bail!("Failed to listen on any address");
+        }
+        servers.push((listeners, screen));
     }
+    // Withdrawn on drop, after serving ends
+    #[cfg(feature = "mdns")]
+    let _advertisement = match &args.advertise {
+        Some(name) => {
+            // Of the first display
+            let (listeners, screen) = &servers[0];
+            let Some(port) = listeners.iter().find_map(Listener::tcp_port) else {
+                bail!("Nothing to advertise without a TCP listening address");
+            };
+            let name = name.clone().unwrap_or_else(|| args.options().name);
+            let advertisement =
+                vncdisplay::mdns::Advertisement::register(&name, port, screen.dimensions())
+                    .context("Advertise over mDNS")?;
+            Some(advertisement)
+        }
+        None => None,
+    };
+    // Ready now, with screen created & listeners bound
+    if let Some(addr) = args.health_addr {
+        health::spawn(addr).await?;
+    }
+    let options = args.options();
+    #[cfg(feature = "tls")]
+    let options = vncdisplay::Options { tls, ..options };
+    let shutdown = shutdown_signal().context("Listen for signals")?;
+    // All displays stop on the same signal
+    let (stop, stopping) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown.await;
+        stop.send_replace(true);
+    });
+    let mut serving = JoinSet::new();
+    for (listeners, screen) in servers {
+        let mut stopping = stopping.clone();
+        let stopped = async move {
+            let _ = stopping.wait_for(|&stop| stop).await;
+        };
+        serving.spawn(vncdisplay::serve_until(
+            listeners,
+            screen,
+            options.clone(),
+            stopped,
+        ));
+    }
+    while let Some(served) = serving.join_next().await {
+        served.context("Join serving task")??;
+    }
+    Ok(())
 }
 
-async fn handle_client(
-    mut stream: TcpStream,
-    mut screen: Screen,
-    name: &str,
-) -> anyhow::Result<()> {
-    let dims = screen.dimensions;
-    rfp::handshake(&mut stream, dims, name)
-        .await
-        .context("RFP handshaking with client")?;
-    let mut zlib: Option<ZlibEncoder<Vec<u8>>> = None;
-    let mut pointer_supported = false;
-    let mut buf = vec![0u8; 0];
-    while let Some(msg) = rfp::read_message(&mut stream, &mut buf).await? {
-        match msg {
-            rfp::ClientMessage::SetPixelFormat(format) => {
-                debug!("Client set pixel format: {:?}", format);
-                screen
-                    .set_pixel_format(format)
-                    .context("Unsupported pixel format")?;
-            }
-            rfp::ClientMessage::SetEncodings(encodings) => {
-                debug!("Client set encodings: {:?}", encodings);
-                if encodings.contains(&rfp::Encoding::Zrle) {
-                    let encoder = ZlibEncoder::new(Vec::new(), Default::default());
-                    zlib = Some(encoder);
-                }
-                if encodings.contains(&rfp::Encoding::Cursor) {
-                    pointer_supported = true;
-                }
+/// Screen showing the background source given, read & decoded right away.
+async fn create_screen(mut args: Args) -> anyhow::Result<Screen> {
+    if let Some(path) = args.background.as_deref() {
+        args.background_data = screen::fetch_background(path).await?;
+        if args.background_data.is_some() && args.watch {
+            bail!("Cannot watch background picture from stdin or URL");
+        }
+    }
+    let args = Arc::new(args);
+
+    let overlay_template = args.overlay_template();
+    let timed = overlay_template.as_deref().is_some_and(overlay::is_timed);
+    if timed && (args.exec.is_some() || args.slideshow.is_some() || args.watch) {
+        bail!("Time in overlay text needs a static background");
+    }
+
+    let screen = if let Some(command) = args.exec.clone() {
+        let frames = source::exec(command, args.exec_restart).await?;
+        let pointer = screen::load_pointer(&args)?;
+        Screen::from_frames(frames, pointer)?
+    } else if let Some(dir) = args.slideshow.as_deref() {
+        let interval = Duration::from_secs(args.slide_interval);
+        let frames = source::slideshow(dir, interval, |image| screen::compose(image, &args))?;
+        let pointer = screen::load_pointer(&args)?;
+        Screen::from_frames(frames, pointer)?
+    } else if let Some(path) = args.background.clone().filter(|_| args.watch) {
+        let compose = {
+            let args = args.clone();
+            move |image| screen::compose(image, &args)
+        };
+        let frames = source::watch(path, compose).context("Watch background picture")?;
+        let pointer = screen::load_pointer(&args)?;
+        Screen::from_frames(frames, pointer)?
+    } else if let Some(template) = overlay_template.filter(|_| timed) {
+        let background = screen::load_background(&args).context("Load background")?;
+        let (size, color, corner) = (
+            args.overlay_size.into(),
+            args.overlay_color,
+            args.overlay_position,
+        );
+        let frames = source::clock(background, move |frame, now| {
+            let text = overlay::expand_time(&template, now);
+            overlay::draw_text(frame, &text, size, color, corner);
+        });
+        let pointer = screen::load_pointer(&args)?;
+        Screen::from_frames(frames, pointer)?
+    } else if let Some(frames) = args
+        .background
+        .as_deref()
+        .map(|path| match args.background_data.as_deref() {
+            Some(data) => source::animate(data, |image| screen::compose(image, &args)),
+            None => {
+                let data = fs::read(path).context("Read background picture")?;
+                source::animate(&data, |image| screen::compose(image, &args))
             }
-            rfp::ClientMessage::FramebufferUpdateRequest { incremental, .. } => {
-                debug!("Receive client message: {:?}", msg);
-                if incremental {
-                    continue; // Our screen is immuable
-                }
-                let rect = if let Some(encoder) = zlib.as_mut() {
-                    FrameRectangle::new_zrle_frame(screen.dimensions, screen.draw_zrle(encoder)?)
-                } else {
-                    FrameRectangle::new_raw_frame(screen.dimensions, screen.draw_raw()?)
-                };
-                if let Some(pointer) = screen.draw_cursor().take_if(|_| pointer_supported) {
-                    let pointer = FrameRectangle::new_cursor(screen.pointer_size(), pointer);
-                    rfp::write_frame(&mut stream, &[rect, pointer]).await?;
-                } else {
-                    rfp::write_frame(&mut stream, &[rect]).await?;
+        })
+        .transpose()
+        .context("Decode animated background picture")?
+        .flatten()
+    {
+        let pointer = screen::load_pointer(&args)?;
+        Screen::from_frames(frames, pointer)?
+    } else {
+        Screen::create(&args).context("Create screen from background picture")?
+    };
+    if let Some((x, y)) = args.cursor_pos {
+        let (width, height) = screen.dimensions();
+        if x >= width || y >= height {
+            bail!("Cursor position ({}, {}) out of screen", x, y);
+        }
+    }
+    Ok(screen)
+}
+
+/// Resolve on Ctrl-C, or SIGTERM on Unix.
+fn shutdown_signal() -> io::Result<impl Future<Output = ()>> {
+    #[cfg(unix)]
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    Ok(async move {
+        #[cfg(unix)]
+        let terminated = terminate.recv();
+        #[cfg(not(unix))]
+        let terminated = future::pending::<Option<()>>();
+        tokio::select! {
+            result = signal::ctrl_c() => {
+                if let Err(err) = result {
+                    warn!("Failed to wait for Ctrl-C: {}", err);
+                    return future::pending().await;
                 }
+                info!("Interrupted, shutting down");
             }
-            rfp::ClientMessage::KeyEvent
-            | rfp::ClientMessage::PointerEvent
-            | rfp::ClientMessage::ClientCutText => continue, // ignore
+            _ = terminated => info!("Terminated, shutting down"),
         }
-    }
-    Ok(())
+    })
 }
@@ -0,0 +1,61 @@
+//! Advertising the server as `_rfb._tcp` over mDNS/DNS-SD, for viewers with
+//! Bonjour or Avahi discovery.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+const SERVICE_TYPE: &str = "_rfb._tcp.local.";
+/// Wait for goodbye packets on unregistering at most this long
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Registered service, withdrawn on drop.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    /// Publish instance `name` on `port` of this host, with the resolution
+    /// in its TXT record.
+    pub fn register(name: &str, port: u16, (width, height): (u16, u16)) -> anyhow::Result<Self> {
+        let host = gethostname::gethostname();
+        let host = host.to_string_lossy();
+        let host = host.split('.').next().unwrap_or_default();
+        let host = format!(
+            "{}.local.",
+            if host.is_empty() { "vncdisplay" } else { host }
+        );
+        let resolution = format!("{}x{}", width, height);
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            name,
+            &host,
+            (),
+            port,
+            &[("resolution", resolution.as_str())][..],
+        )
+        .context("Create mDNS service")?
+        .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+        let daemon = ServiceDaemon::new().context("Start mDNS daemon")?;
+        daemon.register(service).context("Register mDNS service")?;
+        info!("Advertise {} on port {}", fullname, port);
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        match self.daemon.unregister(&self.fullname) {
+            Ok(status) => match status.recv_timeout(UNREGISTER_TIMEOUT) {
+                Ok(status) => debug!("Unregister {}: {:?}", self.fullname, status),
+                Err(err) => warn!("Failed to unregister {}: {}", self.fullname, err),
+            },
+            Err(err) => warn!("Failed to unregister {}: {}", self.fullname, err),
+        }
+        _ = self.daemon.shutdown();
+    }
+}
@@ -0,0 +1,323 @@
+//! Prometheus metrics, served over plain HTTP at `/metrics`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+use crate::rfp::Encoding;
+
+/// Upper bounds of encode time buckets, in seconds
+const ENCODE_BUCKETS: Buckets = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+/// Upper bounds of round-trip time buckets, in seconds
+const RTT_BUCKETS: Buckets = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+const MAX_REQUEST_LEN: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static ACTIVE_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static FRAMES_SENT: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static ENCODE_TIME: Mutex<BTreeMap<String, Histogram>> = Mutex::new(BTreeMap::new());
+static RTT: Mutex<Histogram> = Mutex::new(Histogram::new());
+
+type Buckets = [f64; 8];
+
+#[derive(Default)]
+struct Histogram {
+    /// Count of each bucket, plus the +Inf one, not cumulative
+    counts: [u64; 9],
+    sum: Duration,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            counts: [0; 9],
+            sum: Duration::ZERO,
+        }
+    }
+
+    fn observe(&mut self, buckets: &Buckets, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = buckets
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(buckets.len());
+        self.counts[bucket] += 1;
+        self.sum += elapsed;
+    }
+
+    /// Lines of samples, with labels like `encoding="Raw"` if any.
+    fn render(&self, text: &mut String, name: &str, buckets: &Buckets, labels: &str) {
+        let (prefix, labels) = match labels {
+            "" => (String::new(), String::new()),
+            labels => (format!("{},", labels), format!("{{{}}}", labels)),
+        };
+        let mut count = 0;
+        let bounds = buckets.iter().map(|le| le.to_string());
+        for (le, n) in bounds.chain(["+Inf".into()]).zip(self.counts) {
+            count += n;
+            _ = writeln!(text, "{}_bucket{{{}le=\"{}\"}} {}", name, prefix, le, count);
+        }
+        let sum = self.sum.as_secs_f64();
+        _ = writeln!(text, "{}_sum{} {}", name, labels, sum);
+        _ = writeln!(text, "{}_count{} {}", name, labels, count);
+    }
+}
+
+/// Counted as an active connection until dropped.
+pub struct Connection(());
+
+impl Connection {
+    pub fn open() -> Self {
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn frame_sent() {
+    FRAMES_SENT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn bytes_sent(len: u64) {
+    BYTES_SENT.fetch_add(len, Ordering::Relaxed);
+}
+
+pub fn encoded(encoding: Encoding, elapsed: Duration) {
+    let mut histograms = ENCODE_TIME.lock().unwrap();
+    let histogram = histograms.entry(format!("{:?}", encoding)).or_default();
+    histogram.observe(&ENCODE_BUCKETS, elapsed);
+}
+
+/// Round-trip time measured by a fence on any connection.
+pub fn round_trip(rtt: Duration) {
+    RTT.lock().unwrap().observe(&RTT_BUCKETS, rtt);
+}
+
+/// Current metrics in Prometheus text exposition format.
+pub fn render() -> String {
+    let mut text = String::new();
+    let counters = [
+        (
+            "vncdisplay_connections_total",
+            "counter",
+            "Clients connected",
+            &CONNECTIONS,
+        ),
+        (
+            "vncdisplay_active_connections",
+            "gauge",
+            "Clients being served",
+            &ACTIVE_CONNECTIONS,
+        ),
+        (
+            "vncdisplay_frames_sent_total",
+            "counter",
+            "Framebuffer updates sent",
+            &FRAMES_SENT,
+        ),
+        (
+            "vncdisplay_bytes_sent_total",
+            "counter",
+            "Bytes sent to clients",
+            &BYTES_SENT,
+        ),
+    ];
+    for (name, kind, help, value) in counters {
+        _ = writeln!(text, "# HELP {} {}", name, help);
+        _ = writeln!(text, "# TYPE {} {}", name, kind);
+        _ = writeln!(text, "{} {}", name, value.load(Ordering::Relaxed));
+    }
+
+    let name = "vncdisplay_encode_seconds";
+    _ = writeln!(text, "# HELP {} Time spent encoding rectangles", name);
+    _ = writeln!(text, "# TYPE {} histogram", name);
+    for (encoding, histogram) in ENCODE_TIME.lock().unwrap().iter() {
+        let labels = format!("encoding=\"{}\"", encoding);
+        histogram.render(&mut text, name, &ENCODE_BUCKETS, &labels);
+    }
+
+    let name = "vncdisplay_rtt_seconds";
+    _ = writeln!(text, "# HELP {} Round-trip time to clients", name);
+    _ = writeln!(text, "# TYPE {} histogram", name);
+    RTT.lock()
+        .unwrap()
+        .render(&mut text, name, &RTT_BUCKETS, "");
+    text
+}
+
+/// Answer HTTP requests for metrics, forever.
+pub async fn serve(listener: TcpListener) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await.context("Accept connection")?;
+        tokio::spawn(async move {
+            match time::timeout(REQUEST_TIMEOUT, respond(stream)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => debug!("Error on metrics request from {}: {}", peer, err),
+                Err(_) => debug!("Metrics request from {} timed out", peer),
+            }
+        });
+    }
+}
+
+/// Serve one request, then close the connection.
+async fn respond(mut stream: TcpStream) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_LEN {
+            return reply(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+        let mut chunk = [0u8; 1024];
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+    let request = String::from_utf8_lossy(&buf);
+    let mut line = request.lines().next().unwrap_or("").split(' ');
+    let (method, path) = (line.next().unwrap_or(""), line.next().unwrap_or(""));
+    match (method, path.split('?').next()) {
+        ("GET", Some("/metrics")) => reply(&mut stream, "200 OK", &render()).await,
+        ("GET", _) => reply(&mut stream, "404 Not Found", "").await,
+        _ => reply(&mut stream, "405 Method Not Allowed", "").await,
+    }
+}
+
+async fn reply(stream: &mut TcpStream, status: &str, body: &str) -> anyhow::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Bind address and serve metrics on a background task.
+pub async fn spawn(addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Listen for metrics on {}", addr))?;
+    info!("Metrics on http://{}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(err) = serve(listener).await {
+            warn!("Metrics server stopped: {:#}", err);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+    use crate::{rfb_client::Client, rfp::RfpVersion, serve_pipe, Options, Screen};
+
+    async fn scrape(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        response
+    }
+
+    fn value(metrics: &str, name: &str) -> u64 {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn scrape_after_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+        let before = scrape(addr).await;
+
+        let screen = Screen::from_image(RgbImage::new(16, 16), None).unwrap();
+        let (pipe, session) = serve_pipe(screen, Options::default());
+        let mut client = Client::connect(pipe, RfpVersion::V3_8).await.unwrap();
+        client.set_encodings(&[Encoding::Raw]).await.unwrap();
+        client
+            .request_update(false, (0, 0), (16, 16))
+            .await
+            .unwrap();
+        client.read_update().await.unwrap();
+        let during = scrape(addr).await;
+        drop(client);
+        session.await.unwrap().unwrap();
+
+        for name in [
+            "vncdisplay_connections_total",
+            "vncdisplay_frames_sent_total",
+            "vncdisplay_bytes_sent_total",
+        ] {
+            assert!(value(&during, name) > value(&before, name), "{}", name);
+        }
+        assert!(value(&during, "vncdisplay_active_connections") > 0);
+        let raw = "vncdisplay_encode_seconds_count{encoding=\"Raw\"}";
+        assert!(value(&during, raw) > 0);
+    }
+
+    #[tokio::test]
+    async fn round_trip_time_from_fence() {
+        let count = "vncdisplay_rtt_seconds_count";
+        let before = value(&render(), count);
+        let screen = Screen::from_image(RgbImage::new(16, 16), None).unwrap();
+        let (pipe, _session) = serve_pipe(screen, Options::default());
+        let mut client = Client::connect(pipe, RfpVersion::V3_8).await.unwrap();
+        // Server sends a fence request right after it's enabled
+        client
+            .set_encodings(&[Encoding::Raw, Encoding::Fence])
+            .await
+            .unwrap();
+        // Client replies to the fence while reading updates, and the
+        // server reads the reply before the next request
+        for _ in 0..10 {
+            if value(&render(), count) > before {
+                break;
+            }
+            client
+                .request_update(false, (0, 0), (16, 16))
+                .await
+                .unwrap();
+            client.read_update().await.unwrap();
+        }
+        assert!(value(&render(), count) > before);
+        assert!(render().contains("vncdisplay_rtt_seconds_bucket{le=\"+Inf\"}"));
+    }
+}
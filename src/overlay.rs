@@ -0,0 +1,124 @@
+//! Pictures and text composited onto background.
+
+use std::time::SystemTime;
+
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use anyhow::{bail, Context};
+use image::{Rgb, RgbImage};
+use qrcode::{Color, QrCode};
+
+use crate::cli::Corner;
+
+/// Pixels per module when size is not given
+const QR_DEFAULT_SCALE: u32 = 4;
+/// Quiet zone around QR code, in modules
+const QR_QUIET_ZONE: u32 = 4;
+
+/// Space between text and edges of background, in ems
+const TEXT_MARGIN: f32 = 0.5;
+
+/// Draw QR code of data, with its quiet zone, on a corner of image.
+pub fn draw_qr(
+    image: &mut RgbImage,
+    data: &str,
+    size: Option<u32>,
+    corner: Corner,
+) -> anyhow::Result<()> {
+    let code = QrCode::new(data).context("Encode QR code")?;
+    let modules = code.width() as u32 + QR_QUIET_ZONE * 2;
+    let size = size.unwrap_or(modules * QR_DEFAULT_SCALE);
+    if size < modules {
+        bail!("QR code needs at least {} pixels", modules);
+    }
+    if size > image.width() || size > image.height() {
+        bail!("QR code of {} pixels exceeds background", size);
+    }
+    let (left, top) = match corner {
+        Corner::TopLeft => (0, 0),
+        Corner::TopRight => (image.width() - size, 0),
+        Corner::BottomLeft => (0, image.height() - size),
+        Corner::BottomRight => (image.width() - size, image.height() - size),
+    };
+
+    let colors = code.to_colors();
+    for y in 0..size {
+        for x in 0..size {
+            // Nearest module, quiet zone included
+            let module_x = (x * modules / size).checked_sub(QR_QUIET_ZONE);
+            let module_y = (y * modules / size).checked_sub(QR_QUIET_ZONE);
+            let dark = match (module_x, module_y) {
+                (Some(mx), Some(my)) if mx < code.width() as u32 && my < code.width() as u32 => {
+                    colors[(my * code.width() as u32 + mx) as usize] == Color::Dark
+                }
+                _ => false,
+            };
+            let pixel = if dark { Rgb([0, 0, 0]) } else { Rgb([255; 3]) };
+            image.put_pixel(left + x, top + y, pixel);
+        }
+    }
+    Ok(())
+}
+
+/// Whether text changes over time.
+pub fn is_timed(template: &str) -> bool {
+    template.contains("{date}") || template.contains("{time}")
+}
+
+/// Replace "{date}" and "{time}" with given time in UTC.
+pub fn expand_time(template: &str, now: SystemTime) -> String {
+    // YYYY-MM-DDTHH:MM:SSZ
+    let timestamp = humantime::format_rfc3339_seconds(now).to_string();
+    template
+        .replace("{date}", &timestamp[..10])
+        .replace("{time}", &timestamp[11..19])
+}
+
+/// Draw lines of text on a corner of image, clipped to the image.
+pub fn draw_text(image: &mut RgbImage, text: &str, size: f32, color: Rgb<u8>, corner: Corner) {
+    let font = FontRef::try_from_slice(notosans::REGULAR_TTF).expect("bundled font");
+    let font = font.as_scaled(PxScale::from(size));
+    let lines: Vec<&str> = text.lines().collect();
+    let line_width =
+        |line: &str| -> f32 { line.chars().map(|c| font.h_advance(font.glyph_id(c))).sum() };
+    let width = lines
+        .iter()
+        .map(|line| line_width(line))
+        .fold(0.0, f32::max);
+    let height = font.height() * lines.len() as f32
+        + font.line_gap() * (lines.len().saturating_sub(1)) as f32;
+    let margin = size * TEXT_MARGIN;
+    let (image_width, image_height) = (image.width() as f32, image.height() as f32);
+    let (left, top) = match corner {
+        Corner::TopLeft => (margin, margin),
+        Corner::TopRight => (image_width - margin - width, margin),
+        Corner::BottomLeft => (margin, image_height - margin - height),
+        Corner::BottomRight => (image_width - margin - width, image_height - margin - height),
+    };
+
+    let mut baseline = top + font.ascent();
+    for line in lines {
+        let mut x = left;
+        for c in line.chars() {
+            let mut glyph = font.scaled_glyph(c);
+            glyph.position = point(x, baseline);
+            x += font.h_advance(glyph.id);
+            let Some(outline) = font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|gx, gy, coverage| {
+                let px = bounds.min.x as i64 + gx as i64;
+                let py = bounds.min.y as i64 + gy as i64;
+                if px < 0 || py < 0 || px >= image.width() as i64 || py >= image.height() as i64 {
+                    return;
+                }
+                let alpha = coverage.clamp(0.0, 1.0);
+                let pixel = image.get_pixel_mut(px as u32, py as u32);
+                for (bg, fg) in pixel.0.iter_mut().zip(color.0) {
+                    *bg = (fg as f32 * alpha + *bg as f32 * (1.0 - alpha)).round() as u8;
+                }
+            });
+        }
+        baseline += font.height() + font.line_gap();
+    }
+}
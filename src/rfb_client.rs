@@ -0,0 +1,767 @@
+//! Minimal RFB client, for loopback testing the server, over TCP or the
+//! pipe of [`crate::serve_pipe`]. Decode updates back into an RGB picture.
+
+use std::{
+    io::{Read, Write},
+    iter, mem,
+};
+
+use anyhow::{bail, Context};
+use byteorder_lite::{ReadBytesExt, BE, LE};
+use flate2::write::ZlibDecoder;
+use image::{GenericImage, GenericImageView, ImageFormat, Rgb, RgbImage};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    rfp::{Encoding, PixelFormat, RfpVersion, FENCE_REQUEST},
+    screen::{tiles, ZRLE_TILE_SIZE},
+};
+
+pub struct Client<S> {
+    stream: S,
+    pub name: String,
+    format: PixelFormat,
+    framebuffer: RgbImage,
+    colour_map: Vec<Rgb<u8>>,
+    zlib: ZlibDecoder<Vec<u8>>,
+    tight_zlib: [ZlibDecoder<Vec<u8>>; 4],
+}
+
+impl<S> Client<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Handshake with server using given protocol version.
+    /// Only "None" security type is supported.
+    pub async fn connect(mut stream: S, version: RfpVersion) -> anyhow::Result<Self> {
+        // 7.1.1. ProtocolVersion Handshake
+        let mut buf = [0u8; 12];
+        stream
+            .read_exact(&mut buf)
+            .await
+            .context("Read server protocol version")?;
+        let version_bytes: &[u8; 12] = match version {
+            RfpVersion::V3_3 => b"RFB 003.003\n",
+            RfpVersion::V3_7 => b"RFB 003.007\n",
+            RfpVersion::V3_8 => b"RFB 003.008\n",
+        };
+        stream.write_all(version_bytes).await?;
+
+        // 7.1.2. Security Handshake
+        if version == RfpVersion::V3_3 {
+            match stream.read_u32().await? {
+                0 => bail!("Connection failed: {}", read_reason(&mut stream).await?),
+                1 => (),
+                n => bail!("Unsupported security type: {}", n),
+            }
+        } else {
+            let len = stream.read_u8().await?;
+            if len == 0 {
+                bail!("Connection failed: {}", read_reason(&mut stream).await?);
+            }
+            let mut types = vec![0u8; len.into()];
+            stream.read_exact(&mut types).await?;
+            if !types.contains(&1) {
+                bail!("No supported security type: {:?}", types);
+            }
+            stream.write_u8(1).await?;
+            // 7.1.3. SecurityResult
+            if version == RfpVersion::V3_8 && stream.read_u32().await? != 0 {
+                bail!("Security failed: {}", read_reason(&mut stream).await?);
+            }
+        }
+
+        // 7.3.1. ClientInit
+        stream.write_u8(1).await?; // shared
+
+        // 7.3.2. ServerInit
+        let width = stream.read_u16().await?;
+        let height = stream.read_u16().await?;
+        let mut buf = [0u8; 16];
+        stream.read_exact(&mut buf).await?;
+        let format = PixelFormat::read_from(&mut &buf[..])?;
+        let name = read_reason(&mut stream).await?;
+
+        Ok(Self {
+            stream,
+            name,
+            format,
+            framebuffer: RgbImage::new(width.into(), height.into()),
+            colour_map: Vec::new(),
+            zlib: ZlibDecoder::new(Vec::new()),
+            tight_zlib: std::array::from_fn(|_| ZlibDecoder::new(Vec::new())),
+        })
+    }
+
+    /// Pixels of indexed pixel format are stored as `Rgb([index, 0, 0])`,
+    /// look up them here.
+    pub fn framebuffer(&self) -> &RgbImage {
+        &self.framebuffer
+    }
+
+    pub fn colour_map(&self) -> &[Rgb<u8>] {
+        &self.colour_map
+    }
+
+    /// Pixel format offered by server, or the one set since.
+    pub fn format(&self) -> &PixelFormat {
+        &self.format
+    }
+
+    pub async fn set_pixel_format(&mut self, format: PixelFormat) -> anyhow::Result<()> {
+        // 7.5.1. SetPixelFormat
+        self.stream.write_all(&[0, 0, 0, 0]).await?;
+        self.stream.write_all(&format.encode()).await?;
+        self.format = format;
+        Ok(())
+    }
+
+    pub async fn set_encodings(&mut self, encodings: &[Encoding]) -> anyhow::Result<()> {
+        // 7.5.2. SetEncodings
+        self.stream.write_all(&[2, 0]).await?;
+        self.stream.write_u16(encodings.len().try_into()?).await?;
+        for encoding in encodings {
+            self.stream.write_i32((*encoding).into()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn request_update(
+        &mut self,
+        incremental: bool,
+        position: (u16, u16),
+        size: (u16, u16),
+    ) -> anyhow::Result<()> {
+        // 7.5.3. FramebufferUpdateRequest
+        self.stream.write_all(&[3, incremental.into()]).await?;
+        self.stream.write_u16(position.0).await?;
+        self.stream.write_u16(position.1).await?;
+        self.stream.write_u16(size.0).await?;
+        self.stream.write_u16(size.1).await?;
+        Ok(())
+    }
+
+    pub async fn key_event(&mut self, down: bool, keysym: u32) -> anyhow::Result<()> {
+        // 7.5.4. KeyEvent
+        self.stream.write_all(&[4, down.into(), 0, 0]).await?;
+        self.stream.write_u32(keysym).await?;
+        Ok(())
+    }
+
+    pub async fn enable_continuous_updates(
+        &mut self,
+        enable: bool,
+        position: (u16, u16),
+        size: (u16, u16),
+    ) -> anyhow::Result<()> {
+        // EnableContinuousUpdates
+        self.stream.write_all(&[150, enable.into()]).await?;
+        for value in [position.0, position.1, size.0, size.1] {
+            self.stream.write_u16(value).await?;
+        }
+        Ok(())
+    }
+
+    /// Read messages until a FramebufferUpdate is received and applied.
+    /// Return the encodings of received rectangles.
+    pub async fn read_update(&mut self) -> anyhow::Result<Vec<Encoding>> {
+        loop {
+            match self.stream.read_u8().await? {
+                0 => break,
+                1 => {
+                    // SetColourMapEntries
+                    self.stream.read_u8().await?; // padding
+                    let first = self.stream.read_u16().await? as usize;
+                    let count = self.stream.read_u16().await? as usize;
+                    if self.colour_map.len() < first + count {
+                        self.colour_map.resize(first + count, Rgb([0, 0, 0]));
+                    }
+                    for colour in &mut self.colour_map[first..first + count] {
+                        for channel in colour.0.iter_mut() {
+                            *channel = (self.stream.read_u16().await? >> 8) as u8;
+                        }
+                    }
+                }
+                2 => continue,   // Bell
+                150 => continue, // EndOfContinuousUpdates
+                3 => {
+                    // ServerCutText
+                    let mut padding = [0u8; 3];
+                    self.stream.read_exact(&mut padding).await?;
+                    read_reason(&mut self.stream).await?;
+                }
+                248 => {
+                    // Fence, reply requests with same payload
+                    let mut header = [0u8; 3 + 4 + 1];
+                    self.stream.read_exact(&mut header).await?;
+                    let flags = u32::from_be_bytes([header[3], header[4], header[5], header[6]]);
+                    let mut payload = vec![0u8; header[7].into()];
+                    self.stream.read_exact(&mut payload).await?;
+                    if flags & FENCE_REQUEST != 0 {
+                        self.stream.write_all(&[248, 0, 0, 0]).await?;
+                        self.stream.write_u32(flags & !FENCE_REQUEST).await?;
+                        self.stream.write_u8(header[7]).await?;
+                        self.stream.write_all(&payload).await?;
+                    }
+                }
+                n => bail!("Unsupported server message: {}", n),
+            }
+        }
+        // 7.6.1. FramebufferUpdate
+        self.stream.read_u8().await?; // padding
+        let len = self.stream.read_u16().await?;
+        let mut encodings = Vec::with_capacity(len.into());
+        // Read until LastRect if number of rectangles is unknown
+        for _ in 0..if len == u16::MAX {
+            u32::MAX
+        } else {
+            len.into()
+        } {
+            let x = self.stream.read_u16().await?;
+            let y = self.stream.read_u16().await?;
+            let width = self.stream.read_u16().await?;
+            let height = self.stream.read_u16().await?;
+            let encoding: Encoding = self.stream.read_i32().await?.into();
+            let (x, y, width, height) = (x.into(), y.into(), width.into(), height.into());
+            let out_of_bounds =
+                x + width > self.framebuffer.width() || y + height > self.framebuffer.height();
+            match encoding {
+                Encoding::Raw
+                | Encoding::CopyRect
+                | Encoding::Rre
+                | Encoding::Hextile
+                | Encoding::Tight
+                | Encoding::Trle
+                | Encoding::Zrle
+                    if out_of_bounds =>
+                {
+                    bail!("Rectangle out of framebuffer")
+                }
+                Encoding::Raw => {
+                    let mut buf =
+                        vec![0u8; self.format.bytes_per_pixel() * (width * height) as usize];
+                    self.stream.read_exact(&mut buf).await?;
+                    let mut reader = &buf[..];
+                    for dy in 0..height {
+                        for dx in 0..width {
+                            let pixel = self.format.decode_pixel(&mut reader)?;
+                            self.framebuffer.put_pixel(x + dx, y + dy, pixel);
+                        }
+                    }
+                }
+                Encoding::CopyRect => {
+                    let src_x = self.stream.read_u16().await? as u32;
+                    let src_y = self.stream.read_u16().await? as u32;
+                    if src_x + width > self.framebuffer.width()
+                        || src_y + height > self.framebuffer.height()
+                    {
+                        bail!("CopyRect source out of framebuffer");
+                    }
+                    let src = self
+                        .framebuffer
+                        .view(src_x, src_y, width, height)
+                        .to_image();
+                    self.framebuffer.copy_from(&src, x, y)?;
+                }
+                Encoding::Rre => {
+                    // 7.7.3. RRE
+                    let count = self.stream.read_u32().await?;
+                    let background = self.read_pixel().await?;
+                    for dy in 0..height {
+                        for dx in 0..width {
+                            self.framebuffer.put_pixel(x + dx, y + dy, background);
+                        }
+                    }
+                    for _ in 0..count {
+                        let color = self.read_pixel().await?;
+                        let mut sub = [0u32; 4];
+                        for value in sub.iter_mut() {
+                            *value = self.stream.read_u16().await?.into();
+                        }
+                        let [sub_x, sub_y, sub_width, sub_height] = sub;
+                        if sub_x + sub_width > width || sub_y + sub_height > height {
+                            bail!("Subrectangle out of rectangle");
+                        }
+                        for dy in sub_y..sub_y + sub_height {
+                            for dx in sub_x..sub_x + sub_width {
+                                self.framebuffer.put_pixel(x + dx, y + dy, color);
+                            }
+                        }
+                    }
+                }
+                Encoding::Hextile => self
+                    .decode_hextile((x, y, width, height))
+                    .await
+                    .context("Decode Hextile rectangle")?,
+                Encoding::Tight => self
+                    .decode_tight((x, y, width, height))
+                    .await
+                    .context("Decode Tight rectangle")?,
+                Encoding::Trle => {
+                    let rect = (x, y, width, height);
+                    let framebuffer = &mut self.framebuffer;
+                    decode_tiles(&self.format, framebuffer, &mut self.stream, rect, 16)
+                        .await
+                        .context("Decode TRLE rectangle")?;
+                }
+                Encoding::Zrle => {
+                    let len = self.stream.read_u32().await?;
+                    let mut buf = vec![0u8; len.try_into()?];
+                    self.stream.read_exact(&mut buf).await?;
+                    self.zlib.write_all(&buf)?;
+                    self.zlib.flush()?;
+                    let data = mem::take(self.zlib.get_mut());
+                    self.decode_zrle(&data, (x, y, width, height))
+                        .await
+                        .context("Decode ZRLE rectangle")?;
+                }
+                Encoding::DesktopSize => self.framebuffer = RgbImage::new(width, height),
+                Encoding::PointerPos => (),
+                Encoding::LastRect => break,
+                Encoding::ExtendedDesktopSize => {
+                    let screens = self.stream.read_u8().await?;
+                    let mut buf = vec![0u8; 3 + screens as usize * 16];
+                    self.stream.read_exact(&mut buf).await?;
+                    if (width, height) != self.framebuffer.dimensions() {
+                        self.framebuffer = RgbImage::new(width, height);
+                    }
+                }
+                Encoding::Cursor => {
+                    // Skip pixels & bitmask
+                    let len = self.format.bytes_per_pixel() * (width * height) as usize
+                        + (width.div_ceil(8) * height) as usize;
+                    let mut buf = vec![0u8; len];
+                    self.stream.read_exact(&mut buf).await?;
+                }
+                Encoding::AlphaCursor => {
+                    // Skip raw RGBA pixels
+                    let encoding = self.stream.read_i32().await?;
+                    if Encoding::from(encoding) != Encoding::Raw {
+                        bail!("Unsupported alpha cursor encoding: {}", encoding);
+                    }
+                    let mut buf = vec![0u8; 4 * (width * height) as usize];
+                    self.stream.read_exact(&mut buf).await?;
+                }
+                encoding => bail!("Unsupported encoding: {:?}", encoding),
+            }
+            encodings.push(encoding);
+        }
+        Ok(encodings)
+    }
+
+    async fn decode_hextile(&mut self, rect: (u32, u32, u32, u32)) -> anyhow::Result<()> {
+        // 7.7.4. Hextile
+        let (rect_x, rect_y, rect_width, rect_height) = rect;
+        let mut background = None;
+        let mut foreground = None;
+        for (tile_x, tile_y, width, height) in tiles((rect_width, rect_height), 16) {
+            let (x, y) = (rect_x + tile_x, rect_y + tile_y);
+            let flags = self.stream.read_u8().await?;
+            if flags & 1 > 0 {
+                // Raw
+                for dy in 0..height {
+                    for dx in 0..width {
+                        let pixel = self.read_pixel().await?;
+                        self.framebuffer.put_pixel(x + dx, y + dy, pixel);
+                    }
+                }
+                background = None;
+                foreground = None;
+                continue;
+            }
+            if flags & 2 > 0 {
+                background = Some(self.read_pixel().await?);
+            }
+            let background = background.context("Background not specified")?;
+            for dy in 0..height {
+                for dx in 0..width {
+                    self.framebuffer.put_pixel(x + dx, y + dy, background);
+                }
+            }
+            if flags & 4 > 0 {
+                foreground = Some(self.read_pixel().await?);
+            }
+            if flags & 8 == 0 {
+                continue;
+            }
+            let coloured = flags & 16 > 0;
+            for _ in 0..self.stream.read_u8().await? {
+                let color = if coloured {
+                    self.read_pixel().await?
+                } else {
+                    foreground.context("Foreground not specified")?
+                };
+                let xy = self.stream.read_u8().await? as u32;
+                let wh = self.stream.read_u8().await? as u32;
+                let (sub_x, sub_y) = (xy >> 4, xy & 0xf);
+                let (sub_width, sub_height) = ((wh >> 4) + 1, (wh & 0xf) + 1);
+                if sub_x + sub_width > width || sub_y + sub_height > height {
+                    bail!("Subrectangle out of tile");
+                }
+                for dy in sub_y..sub_y + sub_height {
+                    for dx in sub_x..sub_x + sub_width {
+                        self.framebuffer.put_pixel(x + dx, y + dy, color);
+                    }
+                }
+            }
+            if coloured {
+                foreground = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn decode_tight(
+        &mut self,
+        (x, y, width, height): (u32, u32, u32, u32),
+    ) -> anyhow::Result<()> {
+        let control = self.stream.read_u8().await?;
+        for (i, zlib) in self.tight_zlib.iter_mut().enumerate() {
+            if control & (1 << i) != 0 {
+                *zlib = ZlibDecoder::new(Vec::new());
+            }
+        }
+        let tpixel_len = if self.format.tpixel_packed() {
+            3
+        } else {
+            self.format.bytes_per_pixel()
+        };
+        match control >> 4 {
+            0x8 => {
+                // Fill
+                let mut buf = vec![0u8; tpixel_len];
+                self.stream.read_exact(&mut buf).await?;
+                let color = self.format.decode_tpixel(&mut &buf[..])?;
+                for dy in 0..height {
+                    for dx in 0..width {
+                        self.framebuffer.put_pixel(x + dx, y + dy, color);
+                    }
+                }
+            }
+            0x9 => {
+                // JPEG
+                let len = read_compact_len(&mut self.stream).await?;
+                let mut buf = vec![0u8; len];
+                self.stream.read_exact(&mut buf).await?;
+                let image = image::load_from_memory_with_format(&buf, ImageFormat::Jpeg)?;
+                if image.dimensions() != (width, height) {
+                    bail!("JPEG size mismatches rectangle");
+                }
+                self.framebuffer.copy_from(&image.to_rgb8(), x, y)?;
+            }
+            basic if basic & 0x8 == 0 => {
+                let id = (basic & 0x3) as usize;
+                let filter = if basic & 0x4 != 0 {
+                    self.stream.read_u8().await?
+                } else {
+                    0
+                };
+                let palette = match filter {
+                    0 => Vec::new(),
+                    1 => {
+                        let len = self.stream.read_u8().await? as usize + 1;
+                        let mut buf = vec![0u8; len * tpixel_len];
+                        self.stream.read_exact(&mut buf).await?;
+                        let mut reader = &buf[..];
+                        (0..len)
+                            .map(|_| self.format.decode_tpixel(&mut reader))
+                            .collect::<anyhow::Result<_>>()?
+                    }
+                    n => bail!("Unsupported Tight filter: {}", n),
+                };
+                let len = match palette.len() {
+                    0 => (width * height) as usize * tpixel_len,
+                    2 => (width.div_ceil(8) * height) as usize,
+                    _ => (width * height) as usize,
+                };
+                let mut data = vec![0u8; len];
+                if len < 12 {
+                    self.stream.read_exact(&mut data).await?;
+                } else {
+                    let compressed_len = read_compact_len(&mut self.stream).await?;
+                    let mut buf = vec![0u8; compressed_len];
+                    self.stream.read_exact(&mut buf).await?;
+                    let zlib = &mut self.tight_zlib[id];
+                    zlib.write_all(&buf)?;
+                    zlib.flush()?;
+                    data = mem::take(zlib.get_mut());
+                    if data.len() != len {
+                        bail!("Inflated {} bytes, expect {}", data.len(), len);
+                    }
+                }
+                let mut reader = &data[..];
+                for dy in 0..height {
+                    for dx in 0..width {
+                        let color = match palette.len() {
+                            0 => self.format.decode_tpixel(&mut reader)?,
+                            2 => {
+                                let byte = data[(dy * width.div_ceil(8) + dx / 8) as usize];
+                                palette[(byte >> (7 - dx % 8)) as usize & 1]
+                            }
+                            _ => *palette
+                                .get(data[(dy * width + dx) as usize] as usize)
+                                .context("Palette index out of range")?,
+                        };
+                        self.framebuffer.put_pixel(x + dx, y + dy, color);
+                    }
+                }
+            }
+            _ => bail!("Unsupported Tight compression control: {:#x}", control),
+        }
+        Ok(())
+    }
+
+    async fn read_pixel(&mut self) -> anyhow::Result<Rgb<u8>> {
+        let mut buf = [0u8; 4];
+        let buf = &mut buf[..self.format.bytes_per_pixel()];
+        self.stream.read_exact(buf).await?;
+        self.format.decode_pixel(&mut &buf[..])
+    }
+
+    async fn decode_zrle(&mut self, data: &[u8], rect: (u32, u32, u32, u32)) -> anyhow::Result<()> {
+        // 7.7.6. ZRLE
+        let mut reader = data;
+        let framebuffer = &mut self.framebuffer;
+        decode_tiles(&self.format, framebuffer, &mut reader, rect, ZRLE_TILE_SIZE).await?;
+        if !reader.is_empty() {
+            bail!("{} trailing bytes after ZRLE tiles", reader.len());
+        }
+        Ok(())
+    }
+}
+
+/// Decode TRLE/ZRLE tiles of rect into framebuffer.
+async fn decode_tiles<R: AsyncRead + Unpin>(
+    format: &PixelFormat,
+    framebuffer: &mut RgbImage,
+    reader: &mut R,
+    (rect_x, rect_y, rect_width, rect_height): (u32, u32, u32, u32),
+    tile_size: u32,
+) -> anyhow::Result<()> {
+    for (tile_x, tile_y, width, height) in tiles((rect_width, rect_height), tile_size) {
+        let pixels = decode_tile(format, reader, (width, height)).await?;
+        for (i, pixel) in pixels.into_iter().enumerate() {
+            let i = i as u32;
+            let x = rect_x + tile_x + i % width;
+            let y = rect_y + tile_y + i / width;
+            framebuffer.put_pixel(x, y, pixel);
+        }
+    }
+    Ok(())
+}
+
+/// 7.7.5. TRLE & 7.7.6. ZRLE tile, palette reusing is not supported.
+async fn decode_tile<R: AsyncRead + Unpin>(
+    format: &PixelFormat,
+    reader: &mut R,
+    (width, height): (u32, u32),
+) -> anyhow::Result<Vec<Rgb<u8>>> {
+    let len = (width * height) as usize;
+    let mut pixels = Vec::with_capacity(len);
+    match reader.read_u8().await? {
+        0 => {
+            // Raw
+            for _ in 0..len {
+                pixels.push(read_cpixel(format, reader).await?);
+            }
+        }
+        1 => {
+            // Solid
+            let pixel = read_cpixel(format, reader).await?;
+            pixels.resize(len, pixel);
+        }
+        n @ 2..=16 => {
+            // Packed palette
+            let palette = read_palette(format, reader, n).await?;
+            let bits = match n {
+                2 => 1,
+                3..=4 => 2,
+                _ => 4,
+            };
+            // Each row is padded to whole bytes
+            for _ in 0..height {
+                let mut byte = 0u8;
+                for x in 0..width {
+                    if x * bits % 8 == 0 {
+                        byte = reader.read_u8().await?;
+                    }
+                    let index = byte >> (8 - bits);
+                    byte <<= bits;
+                    let pixel = *palette
+                        .get(index as usize)
+                        .context("Palette index out of range")?;
+                    pixels.push(pixel);
+                }
+            }
+        }
+        128 => {
+            // Plain RLE
+            while pixels.len() < len {
+                let pixel = read_cpixel(format, reader).await?;
+                let run = read_run_length(reader).await?;
+                pixels.extend(iter::repeat_n(pixel, run));
+            }
+        }
+        n @ 130..=255 => {
+            // Palette RLE
+            let palette = read_palette(format, reader, n - 128).await?;
+            while pixels.len() < len {
+                let index = reader.read_u8().await?;
+                let pixel = *palette
+                    .get((index & 0x7f) as usize)
+                    .context("Palette index out of range")?;
+                let run = if index & 0x80 > 0 {
+                    read_run_length(reader).await?
+                } else {
+                    1
+                };
+                pixels.extend(iter::repeat_n(pixel, run));
+            }
+        }
+        n => bail!("Invalid subencoding: {}", n),
+    }
+    if pixels.len() != len {
+        bail!("Run length exceeds tile");
+    }
+    Ok(pixels)
+}
+
+async fn read_cpixel<R: AsyncRead + Unpin>(
+    format: &PixelFormat,
+    reader: &mut R,
+) -> anyhow::Result<Rgb<u8>> {
+    let mut buf = [0u8; 4];
+    let buf = &mut buf[..format.cpixel_len()];
+    reader.read_exact(buf).await?;
+    format.decode_cpixel(&mut &buf[..])
+}
+
+async fn read_palette<R: AsyncRead + Unpin>(
+    format: &PixelFormat,
+    reader: &mut R,
+    len: u8,
+) -> anyhow::Result<Vec<Rgb<u8>>> {
+    let mut palette = Vec::with_capacity(len.into());
+    for _ in 0..len {
+        palette.push(read_cpixel(format, reader).await?);
+    }
+    Ok(palette)
+}
+
+/// Run length of TRLE & ZRLE: sum of bytes until one isn't 255, plus one
+async fn read_run_length<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<usize> {
+    let mut run = 1;
+    loop {
+        let b = reader.read_u8().await?;
+        run += b as usize;
+        if b != 255 {
+            return Ok(run);
+        }
+    }
+}
+
+async fn read_compact_len<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<usize> {
+    let byte = reader.read_u8().await?;
+    let mut len = (byte & 0x7f) as usize;
+    if byte & 0x80 != 0 {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << 7;
+        if byte & 0x80 != 0 {
+            len |= (reader.read_u8().await? as usize) << 14;
+        }
+    }
+    Ok(len)
+}
+
+async fn read_reason<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<String> {
+    let len = stream.read_u32().await?;
+    let mut buf = vec![0u8; len.try_into()?];
+    stream.read_exact(&mut buf).await?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+impl PixelFormat {
+    fn decode_pixel<R: Read>(&self, reader: &mut R) -> anyhow::Result<Rgb<u8>> {
+        let pixel = match self.bits_per_pixel {
+            8 => reader.read_u8()? as u32,
+            16 if self.big_endian_flag => reader.read_u16::<BE>()? as u32,
+            16 => reader.read_u16::<LE>()? as u32,
+            32 if self.big_endian_flag => reader.read_u32::<BE>()?,
+            32 => reader.read_u32::<LE>()?,
+            _ => bail!("bits_per_pixel must be 8, 16, or 32"),
+        };
+        Ok(self.split_pixel(pixel))
+    }
+
+    fn decode_tpixel<R: Read>(&self, reader: &mut R) -> anyhow::Result<Rgb<u8>> {
+        if !self.tpixel_packed() {
+            return self.decode_pixel(reader);
+        }
+        let mut rgb = [0u8; 3];
+        reader.read_exact(&mut rgb)?;
+        Ok(Rgb(rgb))
+    }
+
+    fn cpixel_len(&self) -> usize {
+        match self.cpixel_shift() {
+            Some(_) => 3,
+            None => self.bytes_per_pixel(),
+        }
+    }
+
+    fn decode_cpixel<R: Read>(&self, reader: &mut R) -> anyhow::Result<Rgb<u8>> {
+        let Some(shift) = self.cpixel_shift() else {
+            return self.decode_pixel(reader);
+        };
+        let pixel = if self.big_endian_flag {
+            reader.read_u24::<BE>()?
+        } else {
+            reader.read_u24::<LE>()?
+        };
+        Ok(self.split_pixel(pixel << shift))
+    }
+
+    fn split_pixel(&self, pixel: u32) -> Rgb<u8> {
+        if !self.true_color_flag {
+            return Rgb([pixel as u8, 0, 0]);
+        }
+        let channel = |shift: u8, max: u16| {
+            let max = max.max(1) as u32;
+            let value = (pixel >> shift) & max;
+            (value as f32 / max as f32 * 255.0).round() as u8
+        };
+        Rgb([
+            channel(self.red_shift, self.red_max),
+            channel(self.green_shift, self.green_max),
+            channel(self.blue_shift, self.blue_max),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serve_pipe, Options, Screen};
+
+    #[tokio::test]
+    async fn raw_round_trip() {
+        let image = RgbImage::from_fn(100, 70, |x, y| {
+            Rgb([(x * 2) as u8, (y * 3) as u8, (x + y) as u8])
+        });
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        for version in [RfpVersion::V3_3, RfpVersion::V3_7, RfpVersion::V3_8] {
+            let (pipe, session) = serve_pipe(screen.clone(), Options::default());
+            let mut client = Client::connect(pipe, version).await.unwrap();
+            assert_eq!(client.name, "VNC Display");
+            client.set_encodings(&[Encoding::Raw]).await.unwrap();
+            client
+                .request_update(false, (0, 0), (100, 70))
+                .await
+                .unwrap();
+            assert_eq!(client.read_update().await.unwrap(), [Encoding::Raw]);
+            assert!(client.framebuffer() == &image, "{:?}", version);
+            drop(client);
+            session.await.unwrap().ok();
+        }
+    }
+}
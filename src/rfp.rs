@@ -1,44 +1,96 @@
-use std::io::{self, Read, Write};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{bail, Context};
 use byteorder_lite::{ReadBytesExt, WriteBytesExt, BE, LE};
 use image::Rgb;
 use log::debug;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+
+use crate::{
+    auth::{self, Verifier},
+    screen::Rect,
 };
 
 static SECURITY_TYPE_NO_AUTHENTICATION: u8 = 1;
+static SECURITY_TYPE_VNC_AUTHENTICATION: u8 = 2;
 static SECURITY_RESULT_OK: u32 = 0;
 static SECURITY_RESULT_FAILED: u32 = 1;
 
+// Limits on client-controlled lengths, exceeding them drops the connection
+const MAX_ENCODINGS: usize = 1024;
+pub const MAX_CUT_TEXT_LEN: usize = 1 << 20;
+
+/// How long to wait for bytes following an unknown message
+const READ_AHEAD_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Fence flags
+pub static FENCE_BLOCK_BEFORE: u32 = 1 << 0;
+pub static FENCE_BLOCK_AFTER: u32 = 1 << 1;
+pub static FENCE_SYNC_NEXT: u32 = 1 << 2;
+pub static FENCE_REQUEST: u32 = 1 << 31;
+static FENCE_MAX_PAYLOAD_LEN: u8 = 64;
+
+/// Colour map of indexed pixel format is a 6x6x6 color cube at most
+const MAX_COLOUR_CUBE_LEVELS: u32 = 6;
+
+// ExtendedDesktopSize reasons & status codes
+pub static DESKTOP_SIZE_REASON_SERVER: u16 = 0;
+pub static DESKTOP_SIZE_REASON_CLIENT: u16 = 1;
+pub static DESKTOP_SIZE_STATUS_OK: u16 = 0;
+pub static DESKTOP_SIZE_STATUS_PROHIBITED: u16 = 1;
+
+/// number-of-rectangles of FramebufferUpdate when ended by LastRect
+const LAST_RECT_UNKNOWN_RECTANGLES: u16 = u16::MAX;
+
+/// Limited by the u16 number-of-rectangles field of FramebufferUpdate,
+/// and its max value means unknown for clients supporting LastRect.
+const MAX_RECTANGLES_PER_UPDATE: usize = LAST_RECT_UNKNOWN_RECTANGLES as usize - 1;
+
 static ERROR_REASON_PROTOCOL_VERSION_UNSUPPORTED: &str = "Unsupported protocol version";
-static ERROR_REASON_SECURITY_TYPE_UNSUPPORTED: &str = "Unsupported security type";
+pub(crate) static ERROR_REASON_SECURITY_TYPE_UNSUPPORTED: &str = "Unsupported security type";
+static ERROR_REASON_AUTHENTICATION_FAILED: &str = "Authentication failed";
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-enum RfpVersion {
+pub enum RfpVersion {
     V3_3,
     V3_7,
     V3_8,
 }
 
+impl fmt::Display for RfpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V3_3 => write!(f, "3.3"),
+            Self::V3_7 => write!(f, "3.7"),
+            Self::V3_8 => write!(f, "3.8"),
+        }
+    }
+}
+
 /// RFC6143 §7.4. Pixel Format Data Structure
-#[derive(Debug, Clone, Copy)]
-pub(crate) struct PixelFormat {
-    pub(crate) bits_per_pixel: u8,
-    pub(crate) depth: u8,
-    pub(crate) big_endian_flag: bool,
-    pub(crate) true_color_flag: bool,
-    pub(crate) red_max: u16,
-    pub(crate) green_max: u16,
-    pub(crate) blue_max: u16,
-    pub(crate) red_shift: u8,
-    pub(crate) green_shift: u8,
-    pub(crate) blue_shift: u8,
-}
-
-static PIXEL_FOMRAT_RGB888: &PixelFormat = &PixelFormat {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u8,
+    pub depth: u8,
+    pub big_endian_flag: bool,
+    pub true_color_flag: bool,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+pub static PIXEL_FORMAT_RGB888: &PixelFormat = &PixelFormat {
     bits_per_pixel: 32,
     depth: 24,
     big_endian_flag: false,
@@ -51,14 +103,58 @@ static PIXEL_FOMRAT_RGB888: &PixelFormat = &PixelFormat {
     blue_shift: 0,
 };
 
+pub static PIXEL_FORMAT_BGR888: &PixelFormat = &PixelFormat {
+    red_shift: 0,
+    blue_shift: 16,
+    ..*PIXEL_FORMAT_RGB888
+};
+
+pub static PIXEL_FORMAT_RGB565: &PixelFormat = &PixelFormat {
+    bits_per_pixel: 16,
+    depth: 16,
+    big_endian_flag: false,
+    true_color_flag: true,
+    red_max: 0x1f,
+    green_max: 0x3f,
+    blue_max: 0x1f,
+    red_shift: 11,
+    green_shift: 5,
+    blue_shift: 0,
+};
+
 impl Default for PixelFormat {
     fn default() -> Self {
-        *PIXEL_FOMRAT_RGB888
+        *PIXEL_FORMAT_RGB888
+    }
+}
+
+impl fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "depth {} ({}bpp)", self.depth, self.bits_per_pixel)?;
+        if !self.true_color_flag {
+            return write!(f, " colour map");
+        }
+        let endian = if self.big_endian_flag {
+            "big"
+        } else {
+            "little"
+        };
+        write!(
+            f,
+            " {}-endian max {},{},{} shift {},{},{}",
+            endian,
+            self.red_max,
+            self.green_max,
+            self.blue_max,
+            self.red_shift,
+            self.green_shift,
+            self.blue_shift
+        )
     }
 }
 
 impl PixelFormat {
-    fn read_from<R: Read>(reader: &mut R) -> anyhow::Result<Self> {
+    pub fn read_from<R: Read>(reader: &mut R) -> anyhow::Result<Self> {
         let format = PixelFormat {
             bits_per_pixel: reader.read_u8()?,
             depth: reader.read_u8()?,
@@ -83,59 +179,134 @@ impl PixelFormat {
         Ok(format)
     }
 
-    pub(crate) fn bytes_per_pixel(&self) -> usize {
+    /// Levels of each channel in the color cube used as colour map of
+    /// indexed pixel format, limited by colors that depth can address.
+    pub fn colour_cube_levels(&self) -> u32 {
+        let colours = 1u32 << self.depth.min(24);
+        (1..=MAX_COLOUR_CUBE_LEVELS)
+            .rev()
+            .find(|levels| levels.pow(3) <= colours)
+            .unwrap_or(1)
+    }
+
+    /// Same layout with true color channels cut to at most these bits of
+    /// red, green, and blue, dropping the low bits. Channels whose max isn't
+    /// all ones are kept as is.
+    pub fn with_precision(mut self, bits: [u8; 3]) -> Self {
+        if !self.true_color_flag {
+            return self;
+        }
+        let channels = [
+            (&mut self.red_max, &mut self.red_shift),
+            (&mut self.green_max, &mut self.green_shift),
+            (&mut self.blue_max, &mut self.blue_shift),
+        ];
+        for ((max, shift), bits) in channels.into_iter().zip(bits) {
+            let len = (u16::BITS - max.leading_zeros()) as u8;
+            if max.checked_add(1).is_some_and(u16::is_power_of_two) && len > bits {
+                *max >>= len - bits;
+                *shift += len - bits;
+            }
+        }
+        self
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
         self.bits_per_pixel as usize / 8
     }
 
-    pub(crate) fn encode_compressed_pixels<P, W>(
-        &self,
-        pixels: P,
-        writer: &mut W,
-    ) -> anyhow::Result<()>
+    /// Scale color into each channel's max and shift it into place.
+    fn true_colour_pixel(&self, Rgb(rgb): Rgb<u8>) -> u32 {
+        let rgb_max = [self.red_max, self.green_max, self.blue_max];
+        let rgb_shift = [self.red_shift, self.green_shift, self.blue_shift];
+        (0..3)
+            .map(|i| {
+                let value = (rgb[i] as f32 / 255.0 * rgb_max[i] as f32).round() as u32;
+                value.checked_shl(rgb_shift[i].into()).unwrap_or(0)
+            })
+            .fold(0, |pixel, value| pixel | value)
+    }
+
+    /// 7.7.5. TRLE: CPIXEL is the least or the most significant 3 bytes of
+    /// a 32-bit true color pixel, whichever holds all color bits. Return the
+    /// shift down to those bytes, or none if CPIXEL is just PIXEL.
+    pub fn cpixel_shift(&self) -> Option<u32> {
+        if !self.true_color_flag || self.bits_per_pixel != 32 || self.depth > 24 {
+            return None;
+        }
+        let mask = self.true_colour_pixel(Rgb([0xff; 3]));
+        if mask & 0xff00_0000 == 0 {
+            Some(0)
+        } else if mask & 0xff == 0 {
+            Some(8)
+        } else {
+            None
+        }
+    }
+
+    pub fn encode_compressed_pixels<P, W>(&self, pixels: P, writer: &mut W) -> anyhow::Result<()>
     where
         P: Iterator<Item = Rgb<u8>>,
         W: Write,
     {
-        // 7.7.5. TRLE
-        // Check eligibility
-        if !self.true_color_flag || self.bits_per_pixel != 32 || self.depth > 24 {
-            // FIXME: check bitmask
-            // Fallback to uncompressed pixels
+        let Some(shift) = self.cpixel_shift() else {
+            // CPIXEL is exactly PIXEL for any other format, so ZRLE & TRLE
+            // work with all of them, e.g. 2 bytes each for 16bpp
             return self.encode_pixels(pixels, writer);
-        }
-        // Use compressed pxiel format
-        if self.depth != 24 {
-            bail!("Unimplemented: color depth within (16, 24)");
-        }
-        for Rgb([r, g, b]) in pixels {
+        };
+        // Use compressed pxiel format, in client's byte order
+        for rgb in pixels {
+            let pixel = self.true_colour_pixel(rgb) >> shift;
             if self.big_endian_flag {
-                writer.write_all(&[r, g, b])?;
+                writer.write_u24::<BE>(pixel)?;
             } else {
-                writer.write_all(&[b, g, r])?;
+                writer.write_u24::<LE>(pixel)?;
             }
         }
         Ok(())
     }
 
-    pub(crate) fn encode_pixels<P, W>(&self, pixels: P, writer: &mut W) -> anyhow::Result<()>
+    /// Tight: TPIXEL is 3 bytes of red, green, and blue if all channels are
+    /// 8-bit in a 32-bit true color pixel, otherwise PIXEL.
+    pub fn tpixel_packed(&self) -> bool {
+        self.true_color_flag
+            && self.bits_per_pixel == 32
+            && self.depth == 24
+            && [self.red_max, self.green_max, self.blue_max] == [0xff; 3]
+    }
+
+    pub fn encode_tight_pixels<P, W>(&self, pixels: P, writer: &mut W) -> anyhow::Result<()>
     where
         P: Iterator<Item = Rgb<u8>>,
         W: Write,
     {
-        let rgb_max = [
-            self.red_max as f32,
-            self.green_max as f32,
-            self.blue_max as f32,
-        ];
-        if !self.true_color_flag {
-            bail!("Unimplemented: true color only")
+        if !self.tpixel_packed() {
+            return self.encode_pixels(pixels, writer);
         }
-        let rgb_shift = [self.red_shift, self.green_shift, self.blue_shift];
         for Rgb(rgb) in pixels {
-            let mut pixel = 0u32;
-            for i in 0..3 {
-                pixel |= ((rgb[i] as f32 / 255.0 * rgb_max[i]).round() as u32) << rgb_shift[i]
-            }
+            writer.write_all(&rgb)?;
+        }
+        Ok(())
+    }
+
+    pub fn encode_pixels<P, W>(&self, pixels: P, writer: &mut W) -> anyhow::Result<()>
+    where
+        P: Iterator<Item = Rgb<u8>>,
+        W: Write,
+    {
+        let levels = self.colour_cube_levels();
+        if !self.true_color_flag && levels < 2 {
+            bail!("Colour map too small");
+        }
+        for rgb in pixels {
+            let pixel = if self.true_color_flag {
+                self.true_colour_pixel(rgb)
+            } else {
+                // Index of the nearest color in the cube
+                rgb.0.iter().fold(0, |index, &value| {
+                    index * levels + (value as u32 * (levels - 1) + 127) / 255
+                })
+            };
             match self.bits_per_pixel {
                 8 => writer.write_u8(pixel as u8)?,
                 16 if self.big_endian_flag => writer.write_u16::<BE>(pixel as u16)?,
@@ -151,7 +322,7 @@ impl PixelFormat {
 
 /// RFC6143 §7.5. Client-to-Server Messages
 #[derive(Debug, Clone)]
-pub(crate) enum ClientMessage {
+pub enum ClientMessage {
     SetPixelFormat(PixelFormat),
     SetEncodings(Vec<Encoding>),
     FramebufferUpdateRequest {
@@ -159,17 +330,84 @@ pub(crate) enum ClientMessage {
         position: (u16, u16),
         size: (u16, u16),
     },
-    KeyEvent,
-    PointerEvent,
-    ClientCutText,
+    KeyEvent {
+        down: bool,
+        keysym: u32,
+    },
+    /// KeyEvent with XT scancode, keysym may be 0 for keys lacking one
+    QemuKeyEvent {
+        down: bool,
+        keysym: u32,
+        keycode: u32,
+    },
+    PointerEvent {
+        button_mask: u8,
+        x: u16,
+        y: u16,
+    },
+    /// Latin-1 text decoded, possibly truncated
+    ClientCutText(String),
+    Fence {
+        flags: u32,
+        payload: Vec<u8>,
+    },
+    SetDesktopSize {
+        size: (u16, u16),
+        screens: Vec<ScreenLayout>,
+    },
+    EnableContinuousUpdates {
+        enable: bool,
+        position: (u16, u16),
+        size: (u16, u16),
+    },
+    /// Extension message of this type, known but not supported, with its
+    /// payload skipped
+    Unsupported(u8),
+}
+
+impl ClientMessage {
+    /// Keyboard, pointer or clipboard input from user
+    pub fn is_input(&self) -> bool {
+        matches!(
+            self,
+            Self::KeyEvent { .. }
+                | Self::QemuKeyEvent { .. }
+                | Self::PointerEvent { .. }
+                | Self::ClientCutText(_)
+        )
+    }
+}
+
+/// Screen of ExtendedDesktopSize & SetDesktopSize
+#[derive(Debug, Clone)]
+pub struct ScreenLayout {
+    pub id: u32,
+    pub position: (u16, u16),
+    pub size: (u16, u16),
+    pub flags: u32,
 }
 
 /// RFC6143 §8.4. RFB Encoding Types
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub(crate) enum Encoding {
-    Raw,    // 0
-    Zrle,   // 16
-    Cursor, // -239
+pub enum Encoding {
+    Raw,                  // 0
+    CopyRect,             // 1
+    Rre,                  // 2
+    Hextile,              // 5
+    Tight,                // 7
+    Trle,                 // 15
+    Zrle,                 // 16
+    QualityLevel(u8),     // -32 to -23, JPEG quality of Tight
+    DesktopSize,          // -223
+    LastRect,             // -224
+    PointerPos,           // -232
+    Cursor,               // -239
+    CompressionLevel(u8), // -256 to -247
+    QemuExtendedKeyEvent, // -258
+    ExtendedDesktopSize,  // -308
+    Fence,                // -312
+    ContinuousUpdates,    // -313
+    AlphaCursor,          // -314
     Other(i32),
 }
 
@@ -177,8 +415,23 @@ impl From<i32> for Encoding {
     fn from(value: i32) -> Self {
         match value {
             0 => Self::Raw,
+            1 => Self::CopyRect,
+            2 => Self::Rre,
+            5 => Self::Hextile,
+            7 => Self::Tight,
+            15 => Self::Trle,
             16 => Self::Zrle,
+            -32..=-23 => Self::QualityLevel((value + 32) as u8),
+            -223 => Self::DesktopSize,
+            -224 => Self::LastRect,
+            -232 => Self::PointerPos,
             -239 => Self::Cursor,
+            -256..=-247 => Self::CompressionLevel((value + 256) as u8),
+            -258 => Self::QemuExtendedKeyEvent,
+            -308 => Self::ExtendedDesktopSize,
+            -312 => Self::Fence,
+            -313 => Self::ContinuousUpdates,
+            -314 => Self::AlphaCursor,
             n => Self::Other(n),
         }
     }
@@ -188,58 +441,273 @@ impl From<Encoding> for i32 {
     fn from(val: Encoding) -> Self {
         match val {
             Encoding::Raw => 0,
+            Encoding::CopyRect => 1,
+            Encoding::Rre => 2,
+            Encoding::Hextile => 5,
+            Encoding::Tight => 7,
+            Encoding::Trle => 15,
             Encoding::Zrle => 16,
+            Encoding::QualityLevel(level) => level as i32 - 32,
+            Encoding::DesktopSize => -223,
+            Encoding::LastRect => -224,
+            Encoding::PointerPos => -232,
             Encoding::Cursor => -239,
+            Encoding::CompressionLevel(level) => level as i32 - 256,
+            Encoding::QemuExtendedKeyEvent => -258,
+            Encoding::ExtendedDesktopSize => -308,
+            Encoding::Fence => -312,
+            Encoding::ContinuousUpdates => -313,
+            Encoding::AlphaCursor => -314,
             Encoding::Other(value) => value,
         }
     }
 }
 
-pub(crate) struct FrameRectangle {
+#[derive(Clone)]
+pub struct FrameRectangle {
     position: (u16, u16),
     size: (u16, u16),
     encoding: Encoding,
-    buf: Vec<u8>,
+    /// Shared with other clients if the rectangle is cached
+    buf: Arc<Vec<u8>>,
 }
 
 impl FrameRectangle {
-    pub(crate) fn new_raw_frame(size: (u16, u16), buf: Vec<u8>) -> Self {
+    pub fn new_raw_frame(
+        position: (u16, u16),
+        size: (u16, u16),
+        buf: impl Into<Arc<Vec<u8>>>,
+    ) -> Self {
         Self {
-            position: (0, 0),
+            position,
             encoding: Encoding::Raw,
             size,
-            buf,
+            buf: buf.into(),
         }
     }
 
-    pub(crate) fn new_zrle_frame(size: (u16, u16), buf: Vec<u8>) -> Self {
+    pub fn new_rre_frame(
+        position: (u16, u16),
+        size: (u16, u16),
+        buf: impl Into<Arc<Vec<u8>>>,
+    ) -> Self {
         Self {
-            position: (0, 0),
+            position,
+            encoding: Encoding::Rre,
+            size,
+            buf: buf.into(),
+        }
+    }
+
+    pub fn new_hextile_frame(
+        position: (u16, u16),
+        size: (u16, u16),
+        buf: impl Into<Arc<Vec<u8>>>,
+    ) -> Self {
+        Self {
+            position,
+            encoding: Encoding::Hextile,
+            size,
+            buf: buf.into(),
+        }
+    }
+
+    pub fn new_trle_frame(
+        position: (u16, u16),
+        size: (u16, u16),
+        buf: impl Into<Arc<Vec<u8>>>,
+    ) -> Self {
+        Self {
+            position,
+            encoding: Encoding::Trle,
+            size,
+            buf: buf.into(),
+        }
+    }
+
+    pub fn new_tight_frame(
+        position: (u16, u16),
+        size: (u16, u16),
+        buf: impl Into<Arc<Vec<u8>>>,
+    ) -> Self {
+        Self {
+            position,
+            encoding: Encoding::Tight,
+            size,
+            buf: buf.into(),
+        }
+    }
+
+    pub fn new_zrle_frame(
+        position: (u16, u16),
+        size: (u16, u16),
+        buf: impl Into<Arc<Vec<u8>>>,
+    ) -> Self {
+        Self {
+            position,
             encoding: Encoding::Zrle,
             size,
-            buf,
+            buf: buf.into(),
         }
     }
 
-    pub(crate) fn new_cursor(size: (u16, u16), buf: Vec<u8>) -> Self {
+    /// 7.7.2. CopyRect
+    /// Copy area at src of client's framebuffer to dst.
+    pub fn new_copyrect((x, y, width, height): Rect, src: (u16, u16)) -> Self {
+        let buf = [src.0.to_be_bytes(), src.1.to_be_bytes()].concat();
         Self {
-            position: (size.0 / 2, size.1 / 2),
+            position: (x, y),
+            size: (width, height),
+            encoding: Encoding::CopyRect,
+            buf: buf.into(),
+        }
+    }
+
+    /// DesktopSize pseudo-encoding, announce new framebuffer size.
+    pub fn new_desktop_size(size: (u16, u16)) -> Self {
+        Self {
+            position: (0, 0),
+            size,
+            encoding: Encoding::DesktopSize,
+            buf: Default::default(),
+        }
+    }
+
+    /// QEMU Extended Key Event pseudo-encoding, confirm its support.
+    pub fn new_qemu_extended_key_event() -> Self {
+        Self {
+            position: (0, 0),
+            size: (0, 0),
+            encoding: Encoding::QemuExtendedKeyEvent,
+            buf: Default::default(),
+        }
+    }
+
+    /// ExtendedDesktopSize pseudo-encoding, announce framebuffer size and
+    /// screen layout. Position of rectangle carries reason & status code.
+    pub fn new_extended_desktop_size(
+        reason: u16,
+        status: u16,
+        size: (u16, u16),
+        screens: &[ScreenLayout],
+    ) -> Self {
+        let mut buf = Vec::with_capacity(4 + screens.len() * 16);
+        buf.extend_from_slice(&[screens.len() as u8, 0, 0, 0]); // + padding
+        for screen in screens {
+            buf.extend_from_slice(&screen.id.to_be_bytes());
+            for value in [
+                screen.position.0,
+                screen.position.1,
+                screen.size.0,
+                screen.size.1,
+            ] {
+                buf.extend_from_slice(&value.to_be_bytes());
+            }
+            buf.extend_from_slice(&screen.flags.to_be_bytes());
+        }
+        Self {
+            position: (reason, status),
+            size,
+            encoding: Encoding::ExtendedDesktopSize,
+            buf: buf.into(),
+        }
+    }
+
+    /// LastRect pseudo-encoding, end of rectangles.
+    fn new_last_rect() -> Self {
+        Self {
+            position: (0, 0),
+            size: (0, 0),
+            encoding: Encoding::LastRect,
+            buf: Default::default(),
+        }
+    }
+
+    /// PointerPos pseudo-encoding, move client's cursor to position.
+    pub fn new_pointer_pos(position: (u16, u16)) -> Self {
+        Self {
+            position,
+            size: (0, 0),
+            encoding: Encoding::PointerPos,
+            buf: Default::default(),
+        }
+    }
+
+    /// Cursor pseudo-encoding, position is the hotspot within the shape.
+    pub fn new_cursor(size: (u16, u16), hotspot: (u16, u16), buf: Vec<u8>) -> Self {
+        Self {
+            position: hotspot,
             size,
             encoding: Encoding::Cursor,
-            buf,
+            buf: buf.into(),
+        }
+    }
+
+    /// Cursor With Alpha pseudo-encoding, position is the hotspot.
+    /// Pixels are RGBA with premultiplied alpha, sent as raw.
+    pub fn new_alpha_cursor(size: (u16, u16), hotspot: (u16, u16), rgba: &[u8]) -> Self {
+        let mut buf = Vec::with_capacity(4 + rgba.len());
+        buf.extend_from_slice(&i32::from(Encoding::Raw).to_be_bytes());
+        buf.extend_from_slice(rgba);
+        Self {
+            position: hotspot,
+            size,
+            encoding: Encoding::AlphaCursor,
+            buf: buf.into(),
         }
     }
 }
 
-/// Handshake with client.
+/// Handshake with client, returning the protocol version agreed.
 /// From TCP connection established to initialization messages exchanged.
-pub(crate) async fn handshake(
-    stream: &mut TcpStream,
+/// Server's pixel format in ServerInit is the one used until client sets its.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     screen_dimensions: (u16, u16),
+    format: &PixelFormat,
     name: &str,
-) -> anyhow::Result<()> {
-    // RFC 6143: The Remote Framebuffer Protocol
-    // 7.1.1. ProtocolVersion Handshake
+    verifier: Option<Verifier<'_>>,
+) -> anyhow::Result<RfpVersion> {
+    let version = exchange_version(stream).await?;
+
+    // 7.1.2. Security Handshake
+    let offered = if verifier.is_some() {
+        SECURITY_TYPE_VNC_AUTHENTICATION
+    } else {
+        SECURITY_TYPE_NO_AUTHENTICATION
+    };
+    let security_type = if version == RfpVersion::V3_3 {
+        // A.1. Differences in the Version 3.3 Protocol
+        // Server decides the security type
+        stream.write_u32(offered as u32).await?;
+        offered
+    } else {
+        // Two-way negotiation for V3.7 & V3.8
+        stream.write_all(&[1, offered]).await?;
+        stream.read_u8().await?
+    };
+    let result = match verifier {
+        _ if security_type != offered => Err(ERROR_REASON_SECURITY_TYPE_UNSUPPORTED),
+        Some(verifier) => vnc_authentication(stream, verifier).await?,
+        None => Ok(()),
+    };
+
+    // 7.1.3. SecurityResult
+    match result {
+        // No SecurityResult for None security type before V3.8
+        Ok(())
+            if security_type == SECURITY_TYPE_NO_AUTHENTICATION && version != RfpVersion::V3_8 => {}
+        result => security_result(stream, version, security_type.into(), result).await?,
+    }
+
+    initialization(stream, screen_dimensions, format, name).await?;
+    Ok(version)
+}
+
+/// RFC 6143 §7.1.1. ProtocolVersion Handshake
+pub(crate) async fn exchange_version<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> anyhow::Result<RfpVersion> {
     stream
         .write_all(b"RFB 003.008\n")
         .await
@@ -255,84 +723,100 @@ pub(crate) async fn handshake(
         b"RFB 003.003\n" => RfpVersion::V3_3,
         _ => {
             stream.write_u8(0).await?;
-            stream
-                .write_u32(
-                    ERROR_REASON_PROTOCOL_VERSION_UNSUPPORTED
-                        .len()
-                        .try_into()
-                        .unwrap(),
-                )
-                .await?;
-            stream
-                .write_all(ERROR_REASON_PROTOCOL_VERSION_UNSUPPORTED.as_bytes())
-                .await?;
+            write_reason(stream, ERROR_REASON_PROTOCOL_VERSION_UNSUPPORTED).await?;
             bail!("Unknown client protocol version: {:?}", buf);
         }
     };
     debug!("Protocol version handshake finish: {:?}", version);
+    Ok(version)
+}
 
-    // 7.1.2. Security Handshake
-    let secuirty_type = if version == RfpVersion::V3_3 {
-        // A.1. Differences in the Version 3.3 Protocol
-        stream
-            .write_u32(SECURITY_TYPE_NO_AUTHENTICATION as u32)
-            .await?;
-        SECURITY_TYPE_NO_AUTHENTICATION
+/// RFC 6143 §7.2.2. VNC Authentication, with reason on failure.
+pub(crate) async fn vnc_authentication<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    verifier: Verifier<'_>,
+) -> anyhow::Result<Result<(), &'static str>> {
+    let challenge = auth::new_challenge()?;
+    stream.write_all(&challenge).await?;
+    let mut response = [0u8; auth::CHALLENGE_LEN];
+    stream.read_exact(&mut response).await?;
+    if verifier.verify(&challenge, &response).await {
+        Ok(Ok(()))
     } else {
-        // Two-way negotiation for V3.7 & V3.8
-        stream
-            .write_all(&[1, SECURITY_TYPE_NO_AUTHENTICATION])
-            .await?;
-        stream.read_u8().await?
-    };
-    match version {
-        RfpVersion::V3_3 => (), // No checking
-        RfpVersion::V3_7 if secuirty_type == SECURITY_TYPE_NO_AUTHENTICATION => (), // No SecurityResult
-        RfpVersion::V3_8 if secuirty_type == SECURITY_TYPE_NO_AUTHENTICATION => {
-            // Send SecurityResult (OK)
-            stream.write_u32(SECURITY_RESULT_OK).await?;
-        }
-        _ => {
-            // Send SecurityResult (FAILED)
+        Ok(Err(ERROR_REASON_AUTHENTICATION_FAILED))
+    }
+}
+
+/// RFC 6143 §7.1.3. SecurityResult, failing with the reason given.
+pub(crate) async fn security_result<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    version: RfpVersion,
+    security_type: u32,
+    result: Result<(), &str>,
+) -> anyhow::Result<()> {
+    match result {
+        Ok(()) => stream.write_u32(SECURITY_RESULT_OK).await?,
+        Err(reason) => {
             stream.write_u32(SECURITY_RESULT_FAILED).await?;
             if version == RfpVersion::V3_8 {
-                // Send reason
-                stream
-                    .write_u32(
-                        ERROR_REASON_SECURITY_TYPE_UNSUPPORTED
-                            .len()
-                            .try_into()
-                            .unwrap(),
-                    )
-                    .await?;
-                stream
-                    .write_all(ERROR_REASON_SECURITY_TYPE_UNSUPPORTED.as_bytes())
-                    .await?;
-                bail!("Unsupported security type: {}", secuirty_type);
+                write_reason(stream, reason).await?;
             }
+            bail!("{} (security type {})", reason, security_type);
         }
     }
+    Ok(())
+}
 
+/// RFC 6143 §7.3. Initialization Messages
+pub(crate) async fn initialization<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    screen_dimensions: (u16, u16),
+    format: &PixelFormat,
+    name: &str,
+) -> anyhow::Result<()> {
     // 7.3.1. ClientInit
     let shared = stream.read_u8().await? > 0;
     debug!("Client request shared_flag = {}", shared);
     // Ignored, we always do sharing
 
     // 7.3.2. ServerInit
+    let name = truncate_str(name, u32::MAX as usize);
     stream.write_u16(screen_dimensions.0).await?; // width
     stream.write_u16(screen_dimensions.1).await?; // height
-    stream.write_all(&PIXEL_FOMRAT_RGB888.encode()).await?;
-    let name_len: u32 = name.len().try_into().unwrap_or(u32::MAX);
-    stream.write_u32(name_len).await?;
-    stream
-        .write_all(&name.as_bytes()[..name_len as usize])
-        .await?;
+    stream.write_all(&format.encode()).await?;
+    stream.write_u32(name.len() as u32).await?;
+    stream.write_all(name.as_bytes()).await?;
     Ok(())
 }
 
-pub(crate) async fn read_message(
-    stream: &mut TcpStream,
+/// Longest prefix of text within max_len bytes, ending at a char boundary.
+fn truncate_str(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+    let len = (0..=max_len)
+        .rev()
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(0);
+    &text[..len]
+}
+
+/// Write length-prefixed reason string of failure.
+pub(crate) async fn write_reason<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    reason: &str,
+) -> anyhow::Result<()> {
+    stream.write_u32(reason.len().try_into()?).await?;
+    stream.write_all(reason.as_bytes()).await?;
+    Ok(())
+}
+
+/// Read next message from client. Cut text beyond `max_cut_text_len` bytes
+/// is discarded without buffering.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    stream: &mut R,
     buf: &mut Vec<u8>,
+    max_cut_text_len: usize,
 ) -> anyhow::Result<Option<ClientMessage>> {
     let msg = match stream.read_u8().await {
         Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
@@ -349,12 +833,15 @@ pub(crate) async fn read_message(
             // SetEncodings
             stream.read_u8().await?; // padding
             let len: usize = stream.read_u16().await?.into();
+            if len > MAX_ENCODINGS {
+                bail!("SetEncodings exceeds {} encodings", MAX_ENCODINGS);
+            }
             buf.resize(len * 4, 0);
             stream.read_exact(buf).await?;
             let encodings: Vec<Encoding> = buf
                 .as_slice()
                 .chunks(4)
-                .map(|b| i32::from_be_bytes(b.try_into().unwrap()).into())
+                .map(|b| i32::from_be_bytes([b[0], b[1], b[2], b[3]]).into())
                 .collect();
             ClientMessage::SetEncodings(encodings)
         }
@@ -378,70 +865,697 @@ pub(crate) async fn read_message(
             // KeyEvent
             buf.resize(1 + 2 + 4, 0);
             stream.read_exact(buf).await?;
-            ClientMessage::KeyEvent
+            ClientMessage::KeyEvent {
+                down: buf[0] > 0,
+                keysym: u32::from_be_bytes([buf[3], buf[4], buf[5], buf[6]]),
+            }
         }
         Ok(5) => {
             // PointerEvent
             buf.resize(1 + 2 + 2, 0);
             stream.read_exact(buf).await?;
-            ClientMessage::PointerEvent
+            ClientMessage::PointerEvent {
+                button_mask: buf[0],
+                x: u16::from_be_bytes([buf[1], buf[2]]),
+                y: u16::from_be_bytes([buf[3], buf[4]]),
+            }
         }
         Ok(6) => {
             // ClientCutText
             buf.resize(3, 0);
             stream.read_exact(buf).await?; // drop padding
-            let len = stream.read_u32().await?;
-            buf.resize(len.try_into()?, 0);
+            let len: usize = stream.read_u32().await?.try_into()?;
+            if len > MAX_CUT_TEXT_LEN {
+                bail!("ClientCutText exceeds {} bytes", MAX_CUT_TEXT_LEN);
+            }
+            let kept = len.min(max_cut_text_len);
+            buf.resize(kept, 0);
             stream.read_exact(buf).await?;
-            ClientMessage::ClientCutText
+            if len > kept {
+                let excess = (len - kept) as u64;
+                let mut remaining = (&mut *stream).take(excess);
+                if tokio::io::copy(&mut remaining, &mut tokio::io::sink()).await? < excess {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                debug!("ClientCutText truncated from {} to {} bytes", len, kept);
+            }
+            ClientMessage::ClientCutText(buf.iter().map(|&b| char::from(b)).collect())
+        }
+        Ok(150) => {
+            // EnableContinuousUpdates (RFB community extension)
+            buf.resize(1 + 2 + 2 + 2 + 2, 0);
+            stream.read_exact(buf).await?;
+            ClientMessage::EnableContinuousUpdates {
+                enable: buf[0] > 0,
+                position: (
+                    u16::from_be_bytes([buf[1], buf[2]]),
+                    u16::from_be_bytes([buf[3], buf[4]]),
+                ),
+                size: (
+                    u16::from_be_bytes([buf[5], buf[6]]),
+                    u16::from_be_bytes([buf[7], buf[8]]),
+                ),
+            }
+        }
+        Ok(251) => {
+            // SetDesktopSize (RFB community extension)
+            buf.resize(1 + 2 + 2 + 1 + 1, 0);
+            stream.read_exact(buf).await?;
+            let size = (
+                u16::from_be_bytes([buf[1], buf[2]]),
+                u16::from_be_bytes([buf[3], buf[4]]),
+            );
+            let len: usize = buf[5].into();
+            buf.resize(len * 16, 0);
+            stream.read_exact(buf).await?;
+            let screens = buf
+                .chunks(16)
+                .map(|b| ScreenLayout {
+                    id: u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+                    position: (
+                        u16::from_be_bytes([b[4], b[5]]),
+                        u16::from_be_bytes([b[6], b[7]]),
+                    ),
+                    size: (
+                        u16::from_be_bytes([b[8], b[9]]),
+                        u16::from_be_bytes([b[10], b[11]]),
+                    ),
+                    flags: u32::from_be_bytes([b[12], b[13], b[14], b[15]]),
+                })
+                .collect();
+            ClientMessage::SetDesktopSize { size, screens }
+        }
+        Ok(248) => {
+            // Fence (RFB community extension)
+            buf.resize(3 + 4 + 1, 0);
+            stream.read_exact(buf).await?;
+            let flags = u32::from_be_bytes([buf[3], buf[4], buf[5], buf[6]]);
+            let len = buf[7];
+            if len > FENCE_MAX_PAYLOAD_LEN {
+                bail!("Fence payload exceeds {} bytes", FENCE_MAX_PAYLOAD_LEN);
+            }
+            let mut payload = vec![0u8; len.into()];
+            stream.read_exact(&mut payload).await?;
+            ClientMessage::Fence { flags, payload }
+        }
+        Ok(250) => {
+            // xvp (RFB community extension)
+            buf.resize(1 + 1 + 1, 0);
+            stream.read_exact(buf).await?;
+            ClientMessage::Unsupported(250)
+        }
+        Ok(253) => {
+            // gii (RFB community extension), length in the endian flagged
+            let big_endian = stream.read_u8().await? & 0x80 != 0;
+            let len = if big_endian {
+                stream.read_u16().await?
+            } else {
+                stream.read_u16_le().await?
+            };
+            buf.resize(len.into(), 0);
+            stream.read_exact(buf).await?;
+            ClientMessage::Unsupported(253)
+        }
+        // QEMU client message (RFB community extension)
+        Ok(255) => match stream.read_u8().await? {
+            0 => {
+                // Extended key event
+                buf.resize(2 + 4 + 4, 0);
+                stream.read_exact(buf).await?;
+                ClientMessage::QemuKeyEvent {
+                    down: u16::from_be_bytes([buf[0], buf[1]]) > 0,
+                    keysym: u32::from_be_bytes([buf[2], buf[3], buf[4], buf[5]]),
+                    keycode: u32::from_be_bytes([buf[6], buf[7], buf[8], buf[9]]),
+                }
+            }
+            1 => {
+                // Audio, setting format takes 6 more bytes
+                let len = match stream.read_u16().await? {
+                    2 => 1 + 1 + 4,
+                    _ => 0,
+                };
+                buf.resize(len, 0);
+                stream.read_exact(buf).await?;
+                ClientMessage::Unsupported(255)
+            }
+            sub => bail!("Unknown QEMU client message: {}", sub),
+        },
+        Ok(n) => {
+            // Unknown length, no way to find where the next message starts
+            let ahead = read_ahead(stream).await;
+            bail!("Unknown client message: {}, followed by {:02x?}", n, ahead);
         }
-        Ok(n) => bail!("Unknown client message: {}", n),
     };
     Ok(Some(msg))
 }
 
-pub(crate) async fn write_frame(
-    stream: &mut TcpStream,
+/// Bytes client has already sent, up to 16, for debugging.
+async fn read_ahead<R: AsyncRead + Unpin>(stream: &mut R) -> Vec<u8> {
+    let mut buf = vec![0u8; 16];
+    let len = time::timeout(READ_AHEAD_TIMEOUT, stream.read(&mut buf))
+        .await
+        .map_or(0, |read| read.unwrap_or(0));
+    buf.truncate(len);
+    buf
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    stream: &mut W,
     rectangles: &[FrameRectangle],
 ) -> anyhow::Result<()> {
+    write_frame_iter(stream, rectangles.len(), rectangles.iter().cloned().map(Ok)).await
+}
+
+/// Like [`write_frame`], but with `count` rectangles taken from iterator as
+/// they are written, so they can be encoded one at a time.
+pub async fn write_frame_iter<W, I>(
+    stream: &mut W,
+    count: usize,
+    rectangles: I,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    I: IntoIterator<Item = anyhow::Result<FrameRectangle>>,
+{
     // 7.6.1. FramebufferUpdate
-    stream.write_u16(0).await?; // message-type + padding
-    stream.write_u16(rectangles.len().try_into()?).await?;
+    // number-of-rectangles is a u16, split large updates into multiple messages
+    // rather than failing in the middle of one. Empty update if none.
+    let mut rectangles = rectangles.into_iter();
+    let mut remaining = count;
+    loop {
+        let len = remaining.min(MAX_RECTANGLES_PER_UPDATE);
+        stream.write_u16(0).await?; // message-type + padding
+        stream.write_u16(len as u16).await?;
+        for _ in 0..len {
+            let rect = rectangles.next().context("Missing rectangle")??;
+            write_rectangle(stream, &rect).await?;
+        }
+        remaining -= len;
+        if remaining == 0 {
+            return Ok(());
+        }
+    }
+}
 
+/// Write all rectangles in one FramebufferUpdate without counting them,
+/// for clients supporting LastRect.
+pub async fn write_frame_streaming<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    rectangles: &[FrameRectangle],
+) -> anyhow::Result<()> {
+    write_frame_streaming_iter(stream, rectangles.iter().cloned().map(Ok)).await
+}
+
+/// Like [`write_frame_streaming`], with rectangles taken as they are written.
+pub async fn write_frame_streaming_iter<W, I>(stream: &mut W, rectangles: I) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    I: IntoIterator<Item = anyhow::Result<FrameRectangle>>,
+{
+    // LastRect pseudo-encoding
+    stream.write_u16(0).await?; // message-type + padding
+    stream.write_u16(LAST_RECT_UNKNOWN_RECTANGLES).await?;
     for rect in rectangles {
-        stream.write_u16(rect.position.0).await?;
-        stream.write_u16(rect.position.1).await?;
-        stream.write_u16(rect.size.0).await?;
-        stream.write_u16(rect.size.1).await?;
-        stream.write_i32(rect.encoding.into()).await?;
-        if rect.encoding == Encoding::Zrle {
-            // 7.7.6. ZRLE
-            stream.write_u32(rect.buf.len().try_into()?).await?;
+        write_rectangle(stream, &rect?).await?;
+    }
+    write_rectangle(stream, &FrameRectangle::new_last_rect()).await
+}
+
+async fn write_rectangle<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    rect: &FrameRectangle,
+) -> anyhow::Result<()> {
+    stream.write_u16(rect.position.0).await?;
+    stream.write_u16(rect.position.1).await?;
+    stream.write_u16(rect.size.0).await?;
+    stream.write_u16(rect.size.1).await?;
+    stream.write_i32(rect.encoding.into()).await?;
+    if rect.encoding == Encoding::Zrle {
+        // 7.7.6. ZRLE
+        stream.write_u32(rect.buf.len().try_into()?).await?;
+    }
+    stream.write_all(&rect.buf).await?;
+    Ok(())
+}
+
+/// Send the color cube as colour map, for indexed pixel format.
+pub async fn write_colour_map<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    format: &PixelFormat,
+) -> anyhow::Result<()> {
+    // 7.6.2. SetColourMapEntries
+    let levels = format.colour_cube_levels();
+    let count = levels.pow(3);
+    let mut buf = Vec::with_capacity(6 + count as usize * 6);
+    buf.extend_from_slice(&[1, 0]); // message-type + padding
+    buf.extend_from_slice(&0u16.to_be_bytes()); // first-colour
+    buf.extend_from_slice(&(count as u16).to_be_bytes());
+    for index in 0..count {
+        for level in [
+            index / levels / levels,
+            index / levels % levels,
+            index % levels,
+        ] {
+            let value = level * u16::MAX as u32 / (levels - 1).max(1);
+            buf.extend_from_slice(&(value as u16).to_be_bytes());
         }
-        stream.write_all(&rect.buf).await?;
     }
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Send text to client's clipboard, characters beyond Latin-1 are
+/// replaced with "?".
+pub async fn write_server_cut_text<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    text: &str,
+) -> anyhow::Result<()> {
+    // 7.6.4. ServerCutText
+    let latin1: Vec<u8> = text
+        .chars()
+        .map(|c| u8::try_from(c).unwrap_or(b'?'))
+        .collect();
+    stream.write_all(&[3, 0, 0, 0]).await?; // message-type + padding
+    stream.write_u32(latin1.len().try_into()?).await?;
+    stream.write_all(&latin1).await?;
+    Ok(())
+}
+
+pub async fn write_bell<W: AsyncWrite + Unpin>(stream: &mut W) -> anyhow::Result<()> {
+    // 7.6.3. Bell
+    stream.write_u8(2).await?;
+    Ok(())
+}
+
+/// Confirm support of continuous updates, or that they are stopped.
+pub async fn write_end_of_continuous_updates<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+) -> anyhow::Result<()> {
+    // EndOfContinuousUpdates (RFB community extension)
+    stream.write_u8(150).await?;
+    Ok(())
+}
+
+pub async fn write_fence<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    flags: u32,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    // Fence (RFB community extension)
+    let len: u8 = payload.len().try_into()?;
+    if len > FENCE_MAX_PAYLOAD_LEN {
+        bail!("Fence payload exceeds {} bytes", FENCE_MAX_PAYLOAD_LEN);
+    }
+    stream.write_all(&[248, 0, 0, 0]).await?; // message-type + padding
+    stream.write_u32(flags).await?;
+    stream.write_u8(len).await?;
+    stream.write_all(payload).await?;
     Ok(())
 }
 
 impl PixelFormat {
-    fn encode(&self) -> [u8; 16] {
-        let mut bytes = [0u8; 16];
-        let mut writer = &mut bytes[..];
+    pub fn encode(&self) -> [u8; 16] {
+        let [red_max, green_max, blue_max] =
+            [self.red_max, self.green_max, self.blue_max].map(u16::to_be_bytes);
+        [
+            self.bits_per_pixel,
+            self.depth,
+            self.big_endian_flag.into(),
+            self.true_color_flag.into(),
+            red_max[0],
+            red_max[1],
+            green_max[0],
+            green_max[1],
+            blue_max[0],
+            blue_max[1],
+            self.red_shift,
+            self.green_shift,
+            self.blue_shift,
+            // 3-byte trailing padding
+            0,
+            0,
+            0,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn split_rectangles_over_u16() {
+        let count = 3 * MAX_RECTANGLES_PER_UPDATE / 2;
+        let rects = (0..count).map(|i| Ok(FrameRectangle::new_pointer_pos((i as u16, 0))));
+        let mut buf = Vec::new();
+        write_frame_iter(&mut buf, count, rects).await.unwrap();
+
+        let mut counts = Vec::new();
+        let mut x = 0u16;
+        let mut msg = buf.as_slice();
+        while !msg.is_empty() {
+            assert_eq!(msg[..2], [0, 0]);
+            let len = u16::from_be_bytes([msg[2], msg[3]]) as usize;
+            assert!(len <= MAX_RECTANGLES_PER_UPDATE);
+            msg = &msg[4..];
+            for rect in msg[..len * 12].chunks(12) {
+                assert_eq!(rect[..2], x.to_be_bytes());
+                assert_eq!(rect[8..], i32::from(Encoding::PointerPos).to_be_bytes());
+                x = x.wrapping_add(1);
+            }
+            msg = &msg[len * 12..];
+            counts.push(len);
+        }
+        assert_eq!(
+            counts,
+            [MAX_RECTANGLES_PER_UPDATE, count - MAX_RECTANGLES_PER_UPDATE]
+        );
+    }
+
+    #[tokio::test]
+    async fn too_many_encodings() {
+        let mut buf = Vec::new();
+        let mut msg: &[u8] = &[2, 0, 0xff, 0xff];
+        let err = read_message(&mut msg, &mut buf, MAX_CUT_TEXT_LEN).await;
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("SetEncodings exceeds"));
+        assert_eq!(buf.capacity(), 0);
+    }
+
+    #[tokio::test]
+    async fn too_long_cut_text() {
+        let mut buf = Vec::new();
+        let mut msg: &[u8] = &[6, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        let err = read_message(&mut msg, &mut buf, MAX_CUT_TEXT_LEN).await;
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("ClientCutText exceeds"));
+        assert!(buf.capacity() < 16);
+    }
+
+    #[tokio::test]
+    async fn server_init_name() {
+        for name in ["VNC Display", "日本語の画面"] {
+            let (mut client, mut server) = tokio::io::duplex(256);
+            client.write_u8(1).await.unwrap();
+            initialization(&mut server, (40, 30), &PixelFormat::default(), name)
+                .await
+                .unwrap();
+            drop(server);
+            let mut init = Vec::new();
+            client.read_to_end(&mut init).await.unwrap();
+            assert_eq!(init[..4], [0, 40, 0, 30]);
+            assert_eq!(init[4..20], PixelFormat::default().encode());
+            assert_eq!(init[20..24], (name.len() as u32).to_be_bytes());
+            assert_eq!(&init[24..], name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn truncate_at_char_boundary() {
+        assert_eq!(truncate_str("VNC Display", 64), "VNC Display");
+        assert_eq!(truncate_str("VNC Display", 3), "VNC");
+        assert_eq!(truncate_str("héllo", 2), "h");
+        assert_eq!(truncate_str("héllo", 3), "hé");
+        assert_eq!(truncate_str("日本", 2), "");
+        // Stand-in for a name longer than u32::MAX bytes
+        let name = "画面".repeat(1000);
+        let truncated = truncate_str(&name, 4000);
+        assert_eq!(truncated.len(), 3999);
+        assert!(name.starts_with(truncated));
+    }
+
+    /// Parse one message which must take all the bytes.
+    async fn parse(mut msg: &[u8]) -> anyhow::Result<ClientMessage> {
+        let message = read_message(&mut msg, &mut Vec::new(), MAX_CUT_TEXT_LEN).await?;
+        assert!(msg.is_empty(), "{} bytes left", msg.len());
+        message.context("Closed")
+    }
+
+    #[tokio::test]
+    async fn parse_pointer_event() {
+        let msg = parse(&[5, 3, 1, 2, 0, 7]).await.unwrap();
+        assert!(matches!(
+            msg,
+            ClientMessage::PointerEvent {
+                button_mask: 3,
+                x: 258,
+                y: 7
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn parse_key_event() {
+        let msg = parse(&[4, 1, 0xaa, 0xbb, 0, 0, 0xff, 0xc2]).await.unwrap();
+        assert!(matches!(
+            msg,
+            ClientMessage::KeyEvent {
+                down: true,
+                keysym: 0xffc2
+            }
+        ));
+        let msg = parse(&[4, 0, 0, 0, 0, 0, 0, 0x71]).await.unwrap();
+        assert!(matches!(
+            msg,
+            ClientMessage::KeyEvent {
+                down: false,
+                keysym: 0x71
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn parse_set_desktop_size() {
+        // As sent by TigerVNC: 1920x1080 with one screen covering it
+        #[rustfmt::skip]
+        let msg = parse(&[
+            251, 0, 0x07, 0x80, 0x04, 0x38, 1, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0x07, 0x80, 0x04, 0x38, 0, 0, 0, 0,
+        ])
+        .await
+        .unwrap();
+        let ClientMessage::SetDesktopSize { size, screens } = msg else {
+            panic!("{:?}", msg);
+        };
+        assert_eq!(size, (1920, 1080));
+        assert_eq!(screens.len(), 1);
+        assert_eq!(screens[0].id, 0);
+        assert_eq!(screens[0].position, (0, 0));
+        assert_eq!(screens[0].size, (1920, 1080));
+        assert_eq!(screens[0].flags, 0);
+    }
+
+    #[tokio::test]
+    async fn parse_qemu_key_event() {
+        let msg = parse(&[255, 0, 0, 1, 0, 0, 0xff, 0x1b, 0, 0, 0, 1]).await;
+        assert!(matches!(
+            msg.unwrap(),
+            ClientMessage::QemuKeyEvent {
+                down: true,
+                keysym: 0xff1b,
+                keycode: 1
+            }
+        ));
+        let msg = parse(&[255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1e]).await;
+        assert!(matches!(
+            msg.unwrap(),
+            ClientMessage::QemuKeyEvent {
+                down: false,
+                keysym: 0,
+                keycode: 0x1e
+            }
+        ));
+        let err = parse(&[255, 7]).await.unwrap_err();
+        assert!(err.to_string().contains("Unknown QEMU client message: 7"));
+    }
+
+    fn cpixels(format: PixelFormat, pixels: &[[u8; 3]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let pixels = pixels.iter().map(|&rgb| Rgb(rgb));
+        format.encode_compressed_pixels(pixels, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn cpixel_shift_permutations() {
+        let rgb = [[0x11, 0x22, 0x33]];
+        for (big_endian_flag, shifts, expected) in [
+            (false, (16, 8, 0), [0x33, 0x22, 0x11]),
+            (false, (0, 8, 16), [0x11, 0x22, 0x33]),
+            (false, (8, 16, 0), [0x33, 0x11, 0x22]),
+            (false, (24, 16, 8), [0x33, 0x22, 0x11]),
+            (true, (16, 8, 0), [0x11, 0x22, 0x33]),
+            (true, (0, 8, 16), [0x33, 0x22, 0x11]),
+            (true, (24, 16, 8), [0x11, 0x22, 0x33]),
+        ] {
+            let format = PixelFormat {
+                big_endian_flag,
+                red_shift: shifts.0,
+                green_shift: shifts.1,
+                blue_shift: shifts.2,
+                ..Default::default()
+            };
+            assert_eq!(cpixels(format, &rgb), expected, "{}", format);
+        }
+    }
+
+    #[test]
+    fn cpixel_non_standard_shifts() {
+        // Colour bits in neither the low nor the high 3 bytes, CPIXEL is PIXEL
+        for ((red_shift, green_shift, blue_shift), expected) in [
+            ((24, 8, 0), [0x03, 0x02, 0x00, 0x01]),
+            ((1, 9, 17), [0x02, 0x04, 0x06, 0x00]),
+        ] {
+            let format = PixelFormat {
+                red_shift,
+                green_shift,
+                blue_shift,
+                ..Default::default()
+            };
+            assert_eq!(format.cpixel_shift(), None);
+            assert_eq!(cpixels(format, &[[1, 2, 3]]), expected, "{}", format);
+        }
+    }
+
+    #[test]
+    fn cpixel_mid_depths() {
+        let rgb = [[255, 255, 255], [255, 0, 0], [0, 255, 0], [0, 0, 255]];
+        #[rustfmt::skip]
+        let cases = [
+            (18, 63, 6, [0xff, 0xff, 0x03, 0x00, 0xf0, 0x03, 0xc0, 0x0f, 0x00, 0x3f, 0x00, 0x00]),
+            (21, 127, 7, [0xff, 0xff, 0x1f, 0x00, 0xc0, 0x1f, 0x80, 0x3f, 0x00, 0x7f, 0x00, 0x00]),
+        ];
+        for (depth, max, shift, expected) in cases {
+            let format = PixelFormat {
+                depth,
+                red_max: max,
+                green_max: max,
+                blue_max: max,
+                red_shift: shift * 2,
+                green_shift: shift,
+                blue_shift: 0,
+                ..Default::default()
+            };
+            assert_eq!(format.cpixel_shift(), Some(0));
+            assert_eq!(cpixels(format, &rgb), expected, "{}", format);
+        }
+    }
 
-        writer.write_u8(self.bits_per_pixel).unwrap();
-        writer.write_u8(self.depth).unwrap();
-        writer.write_u8(self.big_endian_flag.into()).unwrap();
-        writer.write_u8(self.true_color_flag.into()).unwrap();
+    #[test]
+    fn pack_rgb565() {
+        let pixels = [
+            [255, 0, 0],
+            [0, 255, 0],
+            [0, 0, 255],
+            [255, 255, 255],
+            [128, 128, 128],
+        ];
+        let mut buf = Vec::new();
+        PIXEL_FORMAT_RGB565
+            .encode_pixels(pixels.into_iter().map(Rgb), &mut buf)
+            .unwrap();
+        assert_eq!(
+            buf,
+            [0x00, 0xf8, 0xe0, 0x07, 0x1f, 0x00, 0xff, 0xff, 0x10, 0x84]
+        );
+        let big_endian = PixelFormat {
+            big_endian_flag: true,
+            ..*PIXEL_FORMAT_RGB565
+        };
+        buf.clear();
+        big_endian
+            .encode_pixels(pixels.into_iter().map(Rgb), &mut buf)
+            .unwrap();
+        assert_eq!(
+            buf,
+            [0xf8, 0x00, 0x07, 0xe0, 0x00, 0x1f, 0xff, 0xff, 0x84, 0x10]
+        );
+    }
 
-        writer.write_u16::<BE>(self.red_max).unwrap();
-        writer.write_u16::<BE>(self.green_max).unwrap();
-        writer.write_u16::<BE>(self.blue_max).unwrap();
+    /// Writer failing on any write.
+    struct Failing;
 
-        writer.write_u8(self.red_shift).unwrap();
-        writer.write_u8(self.green_shift).unwrap();
-        writer.write_u8(self.blue_shift).unwrap();
+    impl Write for Failing {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken writer"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failing_writer_error_propagates() {
+        let pixels = || [Rgb([1, 2, 3])].into_iter();
+        for format in [PixelFormat::default(), *PIXEL_FORMAT_RGB565] {
+            let err = format.encode_pixels(pixels(), &mut Failing).unwrap_err();
+            assert_eq!(err.to_string(), "broken writer");
+            let err = format
+                .encode_compressed_pixels(pixels(), &mut Failing)
+                .unwrap_err();
+            assert_eq!(err.to_string(), "broken writer");
+            let err = format
+                .encode_tight_pixels(pixels(), &mut Failing)
+                .unwrap_err();
+            assert_eq!(err.to_string(), "broken writer");
+        }
+    }
+
+    #[tokio::test]
+    async fn counted_and_last_rect_framing() {
+        let rects = [
+            FrameRectangle::new_raw_frame((0, 0), (1, 1), vec![1, 2, 3, 0]),
+            FrameRectangle::new_pointer_pos((5, 6)),
+            FrameRectangle::new_raw_frame((1, 0), (1, 1), vec![4, 5, 6, 0]),
+        ];
+        let mut counted = Vec::new();
+        write_frame(&mut counted, &rects).await.unwrap();
+        let mut streamed = Vec::new();
+        write_frame_streaming(&mut streamed, &rects).await.unwrap();
+
+        #[rustfmt::skip]
+        let body = [
+            0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 1, 2, 3, 0,
+            0, 5, 0, 6, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x18,
+            0, 1, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 4, 5, 6, 0,
+        ];
+        assert_eq!(counted[..4], [0, 0, 0, 3]);
+        assert_eq!(counted[4..], body);
+        assert_eq!(streamed[..4], [0, 0, 0xff, 0xff]);
+        assert_eq!(streamed[4..4 + body.len()], body);
+        // LastRect, -224
+        let last_rect = [0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x20];
+        assert_eq!(streamed[4 + body.len()..], last_rect);
+    }
+
+    #[tokio::test]
+    async fn fence_round_trip() {
+        let flags = FENCE_REQUEST | FENCE_BLOCK_BEFORE | FENCE_SYNC_NEXT;
+        let mut request = Vec::new();
+        write_fence(&mut request, flags, b"ping").await.unwrap();
+        #[rustfmt::skip]
+        assert_eq!(request, [248, 0, 0, 0, 0x80, 0, 0, 0x05, 4, b'p', b'i', b'n', b'g']);
+
+        let ClientMessage::Fence { flags, payload } = parse(&request).await.unwrap() else {
+            panic!("Not a fence");
+        };
+        assert_eq!(flags, FENCE_REQUEST | FENCE_BLOCK_BEFORE | FENCE_SYNC_NEXT);
+        let mut response = Vec::new();
+        write_fence(&mut response, flags & !FENCE_REQUEST, &payload)
+            .await
+            .unwrap();
+        let ClientMessage::Fence { flags, payload } = parse(&response).await.unwrap() else {
+            panic!("Not a fence");
+        };
+        assert_eq!(flags, FENCE_BLOCK_BEFORE | FENCE_SYNC_NEXT);
+        assert_eq!(payload, b"ping");
 
-        // 3-byte trailing padding
-        bytes
+        let mut too_long = Vec::new();
+        assert!(write_fence(&mut too_long, 0, &[0; 65]).await.is_err());
     }
 }
@@ -1,146 +1,1847 @@
-use std::{io::Write, mem, path::Path, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::{self, Future},
+    io::{self, Write},
+    iter, mem,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{bail, Context};
-use flate2::write::ZlibEncoder;
-use image::{GenericImageView, ImageReader, RgbImage};
+use flate2::{Compress, Compression, FlushCompress};
+use image::{
+    codecs::jpeg::JpegEncoder, imageops, DynamicImage, GenericImageView, ImageReader, Rgb,
+    RgbImage, Rgba, RgbaImage,
+};
+use log::debug;
 
-use crate::rfp::PixelFormat;
+use tokio::{io::AsyncReadExt, sync::watch};
 
-const ZRLE_TILE_SIZE: u32 = 64;
+use crate::{
+    cli::{Args, Aspect, Layer, RectStrategy},
+    overlay,
+    rfp::{Encoding, FrameRectangle, PixelFormat, ScreenLayout},
+    source::Frames,
+};
 
-pub(crate) struct Pointer {
-    image: RgbImage,
+/// Farthest distance deflate refers back
+const ZLIB_WINDOW: usize = 32 * 1024;
+
+/// RFC6143 §7.7.6. ZRLE tiles are fixed at 64x64
+pub const ZRLE_TILE_SIZE: u32 = 64;
+
+/// RFC6143 §7.7.5. TRLE tiles are 16x16 by default
+const TRLE_TILE_SIZE: u32 = 16;
+
+// TRLE & ZRLE subencodings
+const SUBENCODING_RAW: u8 = 0;
+const SUBENCODING_SOLID: u8 = 1;
+const SUBENCODING_PLAIN_RLE: u8 = 128;
+/// Plus palette size
+const SUBENCODING_PALETTE_RLE: u8 = 128;
+
+/// Packed palette holds up to 16 colors
+const MAX_PACKED_PALETTE_LEN: usize = 16;
+/// Palette RLE holds up to 127 colors
+const MAX_RLE_PALETTE_LEN: usize = 127;
+
+/// RFC6143 §7.7.4. Hextile tiles are fixed at 16x16
+const HEXTILE_TILE_SIZE: u32 = 16;
+
+// Hextile subencoding flags
+const HEXTILE_RAW: u8 = 1 << 0;
+const HEXTILE_BACKGROUND_SPECIFIED: u8 = 1 << 1;
+const HEXTILE_FOREGROUND_SPECIFIED: u8 = 1 << 2;
+const HEXTILE_ANY_SUBRECTS: u8 = 1 << 3;
+const HEXTILE_SUBRECTS_COLOURED: u8 = 1 << 4;
+
+/// Tight rectangles are at most 2048 pixels wide, TigerVNC rejects wider
+const TIGHT_MAX_WIDTH: u16 = 2048;
+/// Tight rectangles are split into ones of at most this many pixels
+const TIGHT_MAX_AREA: u32 = 65536;
+/// Tight basic compression leaves data shorter than this uncompressed
+const TIGHT_MIN_TO_COMPRESS: usize = 12;
+const TIGHT_MAX_PALETTE_LEN: usize = 256;
+/// JPEG quality of each quality level, same as TigerVNC
+const TIGHT_JPEG_QUALITY: [u8; 10] = [15, 29, 41, 42, 62, 77, 79, 86, 92, 100];
+
+// Tight compression control, lower 4 bits are stream resets
+const TIGHT_FILL: u8 = 0x80;
+const TIGHT_JPEG: u8 = 0x90;
+const TIGHT_EXPLICIT_FILTER: u8 = 0x40;
+const TIGHT_FILTER_PALETTE: u8 = 1;
+
+// Tight zlib streams, one for each kind of data
+const TIGHT_STREAM_FULL_COLOR: usize = 0;
+const TIGHT_STREAM_MONO: usize = 1;
+const TIGHT_STREAM_INDEXED: usize = 2;
+
+/// Granularity of finding changed area between frames
+const DIRTY_TILE_SIZE: u32 = 64;
+
+/// Send one bounding rectangle when changed tiles fill at least this ratio
+/// of it, otherwise send each changed tile.
+const AUTO_BOUNDING_FILL_RATIO: f64 = 0.5;
+
+/// Farthest distance searched for content moved by scrolling
+const MAX_COPY_DISTANCE: u32 = 256;
+
+/// Number of frames whose encoded rectangles are kept for reuse
+const FRAME_CACHE_LEN: usize = 4;
+
+/// Area on screen as `(x, y, width, height)`
+pub type Rect = (u16, u16, u16, u16);
+
+/// Content served to clients, e.g. a webcam or a terminal renderer.
+/// Each client works on its own clone.
+///
+/// Only `frame` is required, other methods default to a static frame
+/// without pointer, encoded from scratch for every client.
+pub trait FrameSource: Clone + Send + Sync + 'static {
+    /// Current frame.
+    fn frame(&self) -> Arc<RgbImage>;
+
+    fn dimensions(&self) -> (u16, u16) {
+        let (width, height) = self.frame().dimensions();
+        (width as u16, height as u16)
+    }
+
+    /// Called once client is connected, before any other method.
+    fn subscribe(&mut self) {}
+
+    /// Wait for and switch to next frame. Never return for static content.
+    fn changed(&mut self) -> impl Future<Output = ()> + Send {
+        future::pending()
+    }
+
+    /// Switch to latest frame, return whether it's changed.
+    fn refresh(&mut self) -> bool {
+        false
+    }
+
+    /// Re-read content, on client pressing the reload key.
+    fn reload(&mut self) -> anyhow::Result<()> {
+        bail!("Reloading is not supported")
+    }
+
+    /// Encode rect of current frame, which must be within the framebuffer.
+    ///
+    /// ZRLE needs zlib, the stream of the connection. It must live as long
+    /// as the connection, as client inflates all ZRLE rectangles with one
+    /// stream.
+    fn encode(
+        &self,
+        format: &PixelFormat,
+        encoding: Encoding,
+        zlib: Option<&mut ZlibStream>,
+        rect: Rect,
+    ) -> anyhow::Result<FrameRectangle> {
+        let frame = self.frame();
+        let buf = FrameEncoder::new(&frame, format).encode(encoding, rect)?;
+        rectangle(encoding, zlib, rect, buf.into())
+    }
+
+    /// Encode rect of current frame with Tight, split into rectangles small
+    /// enough for clients. Photographic areas are sent as JPEG of quality
+    /// level 0-9 if given, otherwise everything is lossless.
+    fn encode_tight(
+        &self,
+        format: &PixelFormat,
+        quality: Option<u8>,
+        streams: &mut TightStreams,
+        rect: Rect,
+    ) -> anyhow::Result<Vec<FrameRectangle>> {
+        let frame = self.frame();
+        FrameEncoder::new(&frame, format).encode_tight(rect, quality, streams)
+    }
+
+    /// Pointer shape as a Cursor pseudo-rectangle, None for no pointer.
+    fn cursor(&self, _format: &PixelFormat) -> Option<FrameRectangle> {
+        None
+    }
+
+    /// Pointer shape as a Cursor With Alpha pseudo-rectangle, for clients
+    /// supporting it. None to fall back to [`cursor`](Self::cursor).
+    fn alpha_cursor(&self) -> Option<FrameRectangle> {
+        None
+    }
+
+    /// Single screen covering the whole framebuffer
+    fn layout(&self) -> Vec<ScreenLayout> {
+        vec![ScreenLayout {
+            id: 0,
+            position: (0, 0),
+            size: self.dimensions(),
+            flags: 0,
+        }]
+    }
+}
+
+pub struct Pointer {
+    image: RgbaImage,
     bitmask: Box<[u8]>,
+    hotspot: (u16, u16),
 }
 
 #[derive(Clone)]
-pub(crate) struct Screen {
+pub struct Screen {
     background: Arc<RgbImage>,
-    pub(crate) dimensions: (u16, u16),
+    dimensions: (u16, u16),
     pointer: Option<Arc<Pointer>>,
+    /// Live content, None for static background
+    frames: Option<Arc<Frames>>,
+    receiver: Option<watch::Receiver<Arc<RgbImage>>>,
+    /// Shared by all clones, i.e. all clients
+    cache: Arc<Mutex<FrameCache>>,
+    /// Where pictures come from, for reloading
+    args: Option<Arc<Args>>,
+}
+
+/// Zlib stream sync flushed after each rectangle, so client can inflate
+/// all of it, while the dictionary is kept for the next rectangle.
+pub struct ZlibStream {
+    compress: Compress,
+    level: Compression,
+    /// Last input client has inflated, as far as deflate refers back
+    window: Vec<u8>,
+    total_in: u64,
+}
+
+/// Zlib streams of Tight on a connection. Client inflates each with its
+/// own stream, so they must live as long as the connection.
+pub struct TightStreams {
+    level: Compression,
+    streams: [Option<ZlibStream>; 4],
+    /// Streams client must reset before the next rectangle
+    reset: u8,
+}
+
+/// Recently encoded frames, most recent last.
+#[derive(Default)]
+struct FrameCache {
+    entries: Vec<CachedFrame>,
+    hits: u64,
+}
+
+struct CachedFrame {
+    frame: Arc<RgbImage>,
     format: PixelFormat,
+    encoding: Encoding,
+    /// Full frame, or full-width bands of it as Raw updates are split into
+    rects: Vec<(Rect, Arc<Vec<u8>>)>,
 }
 
-impl Screen {
-    pub(crate) fn create<B, P>(background: B, pointer: Option<P>) -> anyhow::Result<Self>
-    where
-        B: AsRef<Path>,
-        P: AsRef<Path>,
-    {
-        // Read background
-        let background = ImageReader::open(background)
-            .context("Read backgroud picture")?
+impl Pointer {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let image = ImageReader::open(path)
+            .context("Read pointer picture")?
             .decode()
-            .context("Decode backgroud picture")?
-            .into_rgb8();
+            .context("Decode pointer picture")?;
+        Self::from_image(image)
+    }
+
+    /// Build pointer from picture. Pixels with alpha above 0x80 are opaque,
+    /// for clients not supporting alpha cursor.
+    pub fn from_image(image: DynamicImage) -> anyhow::Result<Self> {
+        if image.width() > 0xffff || image.height() > 0xffff {
+            bail!("Width & height of poitner picture must less than 65536")
+        }
+        let rgba = image.into_rgba8();
+        let bitmap_row_len = rgba.width().div_ceil(8);
+        let mut bitmask = Vec::with_capacity((bitmap_row_len * rgba.height()) as usize);
+        for row in rgba.rows() {
+            let mut mask = 0u8;
+            for (i, p) in row.enumerate() {
+                mask = (mask << 1) | (p.0[3] > 0x80) as u8;
+                if i % 8 == 7 {
+                    bitmask.push(mask);
+                    mask = 0;
+                }
+            }
+            if !rgba.width().is_multiple_of(8) {
+                bitmask.push(mask);
+            }
+        }
+        let hotspot = (rgba.width() as u16 / 2, rgba.height() as u16 / 2);
+        Ok(Self {
+            image: rgba,
+            bitmask: bitmask.into_boxed_slice(),
+            hotspot,
+        })
+    }
+
+    /// Point of the picture at the pointer position, center by default.
+    pub fn with_hotspot(mut self, (x, y): (u16, u16)) -> anyhow::Result<Self> {
+        if u32::from(x) >= self.image.width() || u32::from(y) >= self.image.height() {
+            bail!("Hotspot ({}, {}) out of pointer picture", x, y);
+        }
+        self.hotspot = (x, y);
+        Ok(self)
+    }
+}
+
+impl Screen {
+    /// Create screen from pictures given in arguments.
+    /// Keep the arguments for reloading.
+    pub fn create(args: &Arc<Args>) -> anyhow::Result<Self> {
+        let background = load_background(args)?;
+
+        let pointer = load_pointer(args)?;
+
+        let mut screen = Self::from_image(background, pointer)?;
+        screen.args = Some(args.clone());
+        Ok(screen)
+    }
+
+    /// Create screen from decoded pictures, without touching filesystem.
+    pub fn from_image(background: RgbImage, pointer: Option<Pointer>) -> anyhow::Result<Self> {
+        Self::new(Arc::new(background), pointer, None)
+    }
+
+    /// Create screen showing live content, starting from its current frame.
+    pub fn from_frames(frames: Arc<Frames>, pointer: Option<Pointer>) -> anyhow::Result<Self> {
+        Self::new(frames.current(), pointer, Some(frames))
+    }
+
+    fn new(
+        background: Arc<RgbImage>,
+        pointer: Option<Pointer>,
+        frames: Option<Arc<Frames>>,
+    ) -> anyhow::Result<Self> {
         let (width, height) = background.dimensions();
         let width: u16 = width.try_into().context("Width must less than 65536")?;
         let height: u16 = height.try_into().context("Height must less than 65536")?;
         let dimensions = (width, height);
-        let background = Arc::new(background);
 
-        // Read pointer
-        let pointer = match pointer {
-            Some(path) => {
-                let image = ImageReader::open(path)
-                    .context("Read pointer picture")?
-                    .decode()
-                    .context("Decode pointer picture")?;
-                if image.width() > 0xffff || image.height() > 0xffff {
-                    bail!("Width & height of poitner picture must less than 65536")
-                }
-                let rgb888 = image.to_rgb8();
-                let rgba = image.into_rgba8();
-                let bitmap_row_len = rgba.width().div_ceil(8);
-                let mut bitmask = Vec::with_capacity((bitmap_row_len * rgba.height()) as usize);
-                for row in rgba.rows() {
-                    let mut mask = 0u8;
-                    for (i, p) in row.enumerate() {
-                        mask = (mask << 1) | (p.0[3] > 0x80) as u8;
-                        if i % 8 == 7 {
-                            bitmask.push(mask);
-                            mask = 0;
-                        }
-                    }
-                    if rgba.width() % 8 > 0 {
-                        bitmask.push(mask);
-                    }
-                }
-                let pointer = Pointer {
-                    image: rgb888,
-                    bitmask: bitmask.into_boxed_slice(),
-                };
-                Some(Arc::new(pointer))
+        Ok(Self {
+            background,
+            dimensions,
+            pointer: pointer.map(Arc::new),
+            frames,
+            receiver: None,
+            cache: Default::default(),
+            args: None,
+        })
+    }
+
+    fn update_dimensions(&mut self) {
+        let (width, height) = self.background.dimensions();
+        self.dimensions = (width as u16, height as u16);
+    }
+
+    /// Encode rect with `draw`, or reuse the result if it's the full frame,
+    /// or a full-width band of it, encoded before in the same pixel format.
+    fn cached(
+        &self,
+        format: &PixelFormat,
+        encoding: Encoding,
+        rect: Rect,
+        draw: impl FnOnce(Rect) -> anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<Arc<Vec<u8>>> {
+        let (x, _, width, _) = rect;
+        if x != 0 || width != self.dimensions.0 {
+            return draw(rect).map(Arc::new);
+        }
+        let matches = |entry: &CachedFrame| {
+            entry.encoding == encoding
+                && entry.format == *format
+                && Arc::ptr_eq(&entry.frame, &self.background)
+        };
+        {
+            let mut cache = self.cache.lock().unwrap();
+            let buf = cache
+                .entries
+                .iter()
+                .find(|entry| matches(entry))
+                .and_then(|entry| entry.rects.iter().find(|(r, _)| *r == rect))
+                .map(|(_, buf)| buf.clone());
+            if let Some(buf) = buf {
+                cache.hits += 1;
+                debug!("Reuse encoded frame ({} hits)", cache.hits);
+                return Ok(buf);
             }
-            None => None,
+        }
+        let buf = Arc::new(draw(rect)?);
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(entry) = cache.entries.iter_mut().find(|entry| matches(entry)) {
+            entry.rects.push((rect, buf.clone()));
+            return Ok(buf);
+        }
+        if cache.entries.len() >= FRAME_CACHE_LEN {
+            cache.entries.remove(0);
+        }
+        cache.entries.push(CachedFrame {
+            frame: self.background.clone(),
+            format: *format,
+            encoding,
+            rects: vec![(rect, buf.clone())],
+        });
+        Ok(buf)
+    }
+}
+
+impl FrameSource for Screen {
+    fn frame(&self) -> Arc<RgbImage> {
+        self.background.clone()
+    }
+
+    fn dimensions(&self) -> (u16, u16) {
+        self.dimensions
+    }
+
+    /// Start watching live content for this client.
+    fn subscribe(&mut self) {
+        if let Some(frames) = self.frames.as_ref() {
+            let mut receiver = frames.subscribe();
+            self.background = receiver.borrow_and_update().clone();
+            self.receiver = Some(receiver);
+        }
+    }
+
+    async fn changed(&mut self) {
+        let Some(receiver) = self.receiver.as_mut() else {
+            return future::pending().await;
         };
+        if receiver.changed().await.is_err() {
+            // Producer has gone, keep the last frame
+            return future::pending().await;
+        }
+        self.background = receiver.borrow_and_update().clone();
+        self.update_dimensions();
+    }
 
-        Ok(Self {
+    fn refresh(&mut self) -> bool {
+        match self.receiver.as_mut() {
+            Some(receiver) if receiver.has_changed().unwrap_or(false) => {
+                self.background = receiver.borrow_and_update().clone();
+                self.update_dimensions();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Re-read pictures given in arguments.
+    fn reload(&mut self) -> anyhow::Result<()> {
+        if self.frames.is_some() {
+            bail!("Live content cannot be reloaded");
+        }
+        let Some(args) = self.args.as_ref() else {
+            bail!("Screen is not created from arguments");
+        };
+        let Self {
             background,
             dimensions,
             pointer,
-            format: Default::default(),
-        })
+            ..
+        } = Self::create(args)?;
+        if dimensions != self.dimensions {
+            bail!("Size of background picture changed");
+        }
+        self.background = background;
+        self.pointer = pointer;
+        Ok(())
     }
 
-    pub(crate) fn set_pixel_format(&mut self, format: PixelFormat) -> anyhow::Result<()> {
-        if !format.true_color_flag {
-            bail!("no true color")
+    /// Reuse full frames encoded for other clients.
+    fn encode(
+        &self,
+        format: &PixelFormat,
+        encoding: Encoding,
+        zlib: Option<&mut ZlibStream>,
+        rect: Rect,
+    ) -> anyhow::Result<FrameRectangle> {
+        let buf = self.cached(format, encoding, rect, |rect| {
+            FrameEncoder::new(&self.background, format).encode(encoding, rect)
+        })?;
+        rectangle(encoding, zlib, rect, buf)
+    }
+
+    fn cursor(&self, format: &PixelFormat) -> Option<FrameRectangle> {
+        let Pointer {
+            image,
+            bitmask,
+            hotspot,
+        } = self.pointer.as_ref()?.as_ref();
+        let mut buf = Vec::with_capacity(format.bytes_per_pixel() * image.len() + bitmask.len());
+        format
+            .encode_pixels(
+                image.pixels().map(|&Rgba([r, g, b, _])| Rgb([r, g, b])),
+                &mut buf,
+            )
+            .ok()?;
+        buf.extend_from_slice(bitmask);
+        let size = (image.width() as u16, image.height() as u16);
+        Some(FrameRectangle::new_cursor(size, *hotspot, buf))
+    }
+
+    fn alpha_cursor(&self) -> Option<FrameRectangle> {
+        let Pointer { image, hotspot, .. } = self.pointer.as_ref()?.as_ref();
+        let rgba: Vec<u8> = image
+            .pixels()
+            .flat_map(|&Rgba([r, g, b, a])| {
+                let premultiply = |c: u8| (c as u16 * a as u16 / 255) as u8;
+                [premultiply(r), premultiply(g), premultiply(b), a]
+            })
+            .collect();
+        let size = (image.width() as u16, image.height() as u16);
+        Some(FrameRectangle::new_alpha_cursor(size, *hotspot, &rgba))
+    }
+}
+
+impl ZlibStream {
+    pub fn new(level: Compression) -> Self {
+        Self {
+            compress: Compress::new(level, true),
+            level,
+            window: Vec::new(),
+            total_in: 0,
         }
-        self.format = format;
+    }
+
+    /// Bytes compressed so far, client has seen the stream if not 0.
+    pub fn total_in(&self) -> u64 {
+        self.total_in
+    }
+
+    /// Change compression level of following data, keeping the stream.
+    pub fn set_level(&mut self, level: Compression) -> io::Result<()> {
+        if level == self.level {
+            return Ok(());
+        }
+        if self.compress.total_out() == 0 {
+            *self = Self::new(level);
+            return Ok(());
+        }
+        // Changing level in place takes deflateParams() flushing into an
+        // output buffer, which zlib-rs doesn't give it. As it's all flushed
+        // at a block boundary, go on with raw deflate blocks, referring back
+        // to the same window client has.
+        let mut compress = Compress::new(level, false);
+        compress
+            .set_dictionary(&self.window)
+            .map_err(io::Error::other)?;
+        self.compress = compress;
+        self.level = level;
+        Ok(())
+    }
+
+    /// Compress data and sync flush all of it.
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let mut input = data;
+        loop {
+            let consumed = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut out, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            input = &input[(self.compress.total_in() - consumed) as usize..];
+            // Flush is done only if it stopped before filling the output
+            if input.is_empty() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity());
+        }
+        self.total_in += data.len() as u64;
+        let keep = ZLIB_WINDOW
+            .saturating_sub(data.len())
+            .min(self.window.len());
+        self.window.drain(..self.window.len() - keep);
+        self.window
+            .extend_from_slice(&data[data.len().saturating_sub(ZLIB_WINDOW)..]);
+        Ok(out)
+    }
+}
+
+impl TightStreams {
+    pub fn new(level: Compression) -> Self {
+        Self {
+            level,
+            streams: Default::default(),
+            reset: 0,
+        }
+    }
+
+    /// Change compression level. Streams in use are restarted, telling
+    /// client to reset them as well.
+    pub fn set_level(&mut self, level: Compression) {
+        if level == self.level {
+            return;
+        }
+        for (i, stream) in self.streams.iter_mut().enumerate() {
+            if stream.take().is_some_and(|stream| stream.total_in() > 0) {
+                self.reset |= 1 << i;
+            }
+        }
+        self.level = level;
+    }
+
+    /// Compression control byte, carrying pending stream resets.
+    fn control(&mut self, control: u8) -> u8 {
+        control | mem::take(&mut self.reset)
+    }
+
+    /// Write data compressed with stream id, with its compact length, or
+    /// as is if it's too short to be compressed.
+    fn compress(&mut self, id: usize, data: &[u8], buf: &mut Vec<u8>) -> io::Result<()> {
+        if data.len() < TIGHT_MIN_TO_COMPRESS {
+            buf.extend_from_slice(data);
+            return Ok(());
+        }
+        let level = self.level;
+        let zlib = self.streams[id].get_or_insert_with(|| ZlibStream::new(level));
+        let compressed = zlib.compress(data)?;
+        write_compact_len(compressed.len(), buf);
+        buf.extend_from_slice(&compressed);
         Ok(())
     }
+}
+
+/// Pixels of a frame in the client's pixel format.
+struct FrameEncoder<'a> {
+    background: &'a RgbImage,
+    format: &'a PixelFormat,
+}
+
+impl<'a> FrameEncoder<'a> {
+    fn new(background: &'a RgbImage, format: &'a PixelFormat) -> Self {
+        Self { background, format }
+    }
 
-    pub(crate) fn pointer_size(&self) -> (u16, u16) {
-        match self.pointer.as_ref() {
-            Some(p) => (p.image.width() as u16, p.image.height() as u16),
-            None => (0, 0),
+    /// Encode rect, which must be within the frame. ZRLE tiles are left
+    /// uncompressed.
+    fn encode(&self, encoding: Encoding, rect: Rect) -> anyhow::Result<Vec<u8>> {
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        let mut buf = Vec::new();
+        match encoding {
+            Encoding::Zrle => self.draw_tiles(rect, ZRLE_TILE_SIZE, &mut buf)?,
+            Encoding::Trle => self.draw_tiles(rect, TRLE_TILE_SIZE, &mut buf)?,
+            Encoding::Rre => buf = self.encode_rre(rect)?,
+            Encoding::Hextile => buf = self.encode_hextile(rect)?,
+            _ => buf = self.encode_raw(rect)?,
         }
+        #[cfg(feature = "metrics")]
+        crate::metrics::encoded(encoding, started.elapsed());
+        Ok(buf)
     }
 
-    pub(crate) fn draw_cursor(&self) -> Option<Vec<u8>> {
-        let Pointer { image, bitmask } = self.pointer.as_ref()?.as_ref();
+    /// Encode rect, which must be within the frame, as Tight rectangles.
+    fn encode_tight(
+        &self,
+        (x, y, width, height): Rect,
+        quality: Option<u8>,
+        streams: &mut TightStreams,
+    ) -> anyhow::Result<Vec<FrameRectangle>> {
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        let chunk_width = width.clamp(1, TIGHT_MAX_WIDTH);
+        let chunk_height = (TIGHT_MAX_AREA / chunk_width as u32).min(u16::MAX.into()) as u16;
+        let mut rects = Vec::new();
+        for dy in (0..height).step_by(chunk_height.into()) {
+            for dx in (0..width).step_by(chunk_width.into()) {
+                let size = (chunk_width.min(width - dx), chunk_height.min(height - dy));
+                let rect = (x + dx, y + dy, size.0, size.1);
+                let buf = self.encode_tight_rect(rect, quality, streams)?;
+                rects.push(FrameRectangle::new_tight_frame((rect.0, rect.1), size, buf));
+            }
+        }
+        #[cfg(feature = "metrics")]
+        crate::metrics::encoded(Encoding::Tight, started.elapsed());
+        Ok(rects)
+    }
+
+    /// Tight with fill for solid color, palette for few colors, JPEG for
+    /// the rest if quality is given, or basic copy otherwise.
+    fn encode_tight_rect(
+        &self,
+        (x, y, width, height): Rect,
+        quality: Option<u8>,
+        streams: &mut TightStreams,
+    ) -> anyhow::Result<Vec<u8>> {
+        let view = self
+            .background
+            .view(x.into(), y.into(), width.into(), height.into());
+        let pixels: Vec<_> = view.pixels().map(|(_, _, p)| p).collect();
+        let mut buf = Vec::new();
+        let indexed = indexed(&pixels, TIGHT_MAX_PALETTE_LEN)
+            .filter(|(palette, _)| palette.len() == 1 || palette.len() <= pixels.len() / 2);
+        if let Some((palette, indices)) = indexed {
+            if let [color] = palette[..] {
+                buf.push(streams.control(TIGHT_FILL));
+                self.format
+                    .encode_tight_pixels(iter::once(color), &mut buf)?;
+                return Ok(buf);
+            }
+            let (id, data) = if palette.len() == 2 {
+                // One bit for each pixel, rows padded to whole bytes
+                let data: Vec<_> = indices
+                    .chunks(width.into())
+                    .flat_map(|row| row.chunks(8))
+                    .map(|bits| {
+                        bits.iter()
+                            .enumerate()
+                            .fold(0u8, |byte, (i, &bit)| byte | (bit << (7 - i)))
+                    })
+                    .collect();
+                (TIGHT_STREAM_MONO, data)
+            } else {
+                (TIGHT_STREAM_INDEXED, indices)
+            };
+            buf.push(streams.control(TIGHT_EXPLICIT_FILTER | (id as u8) << 4));
+            buf.push(TIGHT_FILTER_PALETTE);
+            buf.push((palette.len() - 1) as u8);
+            self.format
+                .encode_tight_pixels(palette.into_iter(), &mut buf)?;
+            streams.compress(id, &data, &mut buf)?;
+            return Ok(buf);
+        }
+        // Clients decode JPEG into true color of 16 bits or more
+        let quality =
+            quality.filter(|_| self.format.true_color_flag && self.format.bits_per_pixel >= 16);
+        if let Some(level) = quality {
+            let quality = TIGHT_JPEG_QUALITY[usize::from(level).min(9)];
+            let mut jpeg = Vec::new();
+            JpegEncoder::new_with_quality(&mut jpeg, quality)
+                .encode_image(&*view)
+                .context("Encode JPEG")?;
+            buf.push(streams.control(TIGHT_JPEG));
+            write_compact_len(jpeg.len(), &mut buf);
+            buf.extend_from_slice(&jpeg);
+            return Ok(buf);
+        }
+        let mut data = Vec::with_capacity(pixels.len() * 4);
+        self.format
+            .encode_tight_pixels(pixels.into_iter(), &mut data)?;
+        buf.push(streams.control((TIGHT_STREAM_FULL_COLOR as u8) << 4));
+        streams.compress(TIGHT_STREAM_FULL_COLOR, &data, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn encode_raw(&self, (x, y, width, height): Rect) -> anyhow::Result<Vec<u8>> {
+        let view = self
+            .background
+            .view(x.into(), y.into(), width.into(), height.into());
         let mut buf =
-            Vec::with_capacity(self.format.bytes_per_pixel() * image.len() + bitmask.len());
+            Vec::with_capacity(self.format.bytes_per_pixel() * width as usize * height as usize);
         self.format
-            .encode_pixels(image.pixels().cloned(), &mut buf)
-            .ok()?;
-        buf.extend_from_slice(bitmask);
-        Some(buf)
+            .encode_pixels(view.pixels().map(|(_, _, p)| p), &mut buf)?;
+        Ok(buf)
     }
 
-    pub(crate) fn draw_raw(&self) -> anyhow::Result<Vec<u8>> {
-        let mut buf = Vec::with_capacity(self.format.bytes_per_pixel() * self.background.len());
+    fn encode_rre(&self, (x, y, width, height): Rect) -> anyhow::Result<Vec<u8>> {
+        // 7.7.3. RRE
+        let view = self
+            .background
+            .view(x.into(), y.into(), width.into(), height.into());
+        let pixels: Vec<_> = view.pixels().map(|(_, _, p)| p).collect();
+        let background = most_common(&pixels);
+        let subrects = subrects(&pixels, width.into(), background);
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&u32::try_from(subrects.len())?.to_be_bytes());
         self.format
-            .encode_pixels(self.background.pixels().cloned(), &mut buf)?;
+            .encode_pixels(iter::once(background), &mut buf)?;
+        for (color, (x, y, width, height)) in subrects {
+            self.format.encode_pixels(iter::once(color), &mut buf)?;
+            for value in [x, y, width, height] {
+                buf.extend_from_slice(&value.to_be_bytes());
+            }
+        }
         Ok(buf)
     }
 
-    pub(crate) fn draw_zrle(&self, encoder: &mut ZlibEncoder<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
-        let screen_width = self.dimensions.0 as u32;
-        let screen_height = self.dimensions.1 as u32;
-        let mut buf = Vec::with_capacity(
-            (ZRLE_TILE_SIZE * ZRLE_TILE_SIZE) as usize * self.format.bytes_per_pixel(),
-        );
+    fn encode_hextile(
+        &self,
+        (rect_x, rect_y, rect_width, rect_height): Rect,
+    ) -> anyhow::Result<Vec<u8>> {
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        let mut buf = Vec::new();
+        let mut tile_buf = Vec::new();
+        // Colors carried over from the previous tile
+        let mut background = None;
+        let mut foreground = None;
+        let dimensions = (rect_width.into(), rect_height.into());
+        for (x, y, width, height) in tiles(dimensions, HEXTILE_TILE_SIZE) {
+            let (x, y) = (rect_x as u32 + x, rect_y as u32 + y);
+            let tile = self.background.view(x, y, width, height);
+            let pixels: Vec<_> = tile.pixels().map(|(_, _, p)| p).collect();
+            let tile_background = most_common(&pixels);
+            let subrects = subrects(&pixels, width, tile_background);
+            let tile_foreground = subrects
+                .first()
+                .map(|&(color, _)| color)
+                .filter(|&color| subrects.iter().all(|&(c, _)| c == color));
+
+            let mut flags = 0;
+            tile_buf.clear();
+            if background != Some(tile_background) {
+                flags |= HEXTILE_BACKGROUND_SPECIFIED;
+                self.format
+                    .encode_pixels(iter::once(tile_background), &mut tile_buf)?;
+            }
+            if !subrects.is_empty() {
+                flags |= HEXTILE_ANY_SUBRECTS;
+                match tile_foreground {
+                    Some(color) if foreground == Some(color) => (),
+                    Some(color) => {
+                        flags |= HEXTILE_FOREGROUND_SPECIFIED;
+                        self.format
+                            .encode_pixels(iter::once(color), &mut tile_buf)?;
+                    }
+                    None => flags |= HEXTILE_SUBRECTS_COLOURED,
+                }
+                tile_buf.push(subrects.len().min(u8::MAX.into()) as u8);
+                for &(color, (x, y, w, h)) in subrects.iter() {
+                    if tile_foreground.is_none() {
+                        self.format
+                            .encode_pixels(iter::once(color), &mut tile_buf)?;
+                    }
+                    tile_buf.push(((x as u8) << 4) | y as u8);
+                    tile_buf.push((((w - 1) as u8) << 4) | (h - 1) as u8);
+                }
+            }
+
+            let raw_len = bytes_per_pixel * pixels.len();
+            if subrects.len() > u8::MAX.into() || tile_buf.len() >= raw_len {
+                // Colors are unspecified after a raw tile
+                buf.push(HEXTILE_RAW);
+                self.format.encode_pixels(pixels.into_iter(), &mut buf)?;
+                background = None;
+                foreground = None;
+            } else {
+                buf.push(flags);
+                buf.extend_from_slice(&tile_buf);
+                background = Some(tile_background);
+                if !subrects.is_empty() {
+                    foreground = tile_foreground;
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Write a TRLE/ZRLE tile with the smallest of raw, solid, packed
+    /// palette, plain RLE, and palette RLE subencodings.
+    fn encode_tile(&self, pixels: &[Rgb<u8>], width: u32, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        let palette = palette(pixels, MAX_RLE_PALETTE_LEN);
+        if let Some(&[color]) = palette.as_deref() {
+            buf.push(SUBENCODING_SOLID);
+            return self.format.encode_compressed_pixels(iter::once(color), buf);
+        }
+
+        let mut raw = vec![SUBENCODING_RAW];
+        self.format
+            .encode_compressed_pixels(pixels.iter().copied(), &mut raw)?;
+
+        let mut rle = vec![SUBENCODING_PLAIN_RLE];
+        for run in pixels.chunk_by(|a, b| a == b) {
+            self.format
+                .encode_compressed_pixels(iter::once(run[0]), &mut rle)?;
+            write_run_length(run.len(), &mut rle);
+        }
+
+        let mut candidates = vec![raw, rle];
+        let Some(palette) = palette else {
+            buf.extend_from_slice(&smallest(candidates));
+            return Ok(());
+        };
+
+        let mut palette_rle = vec![SUBENCODING_PALETTE_RLE + palette.len() as u8];
+        self.format
+            .encode_compressed_pixels(palette.iter().copied(), &mut palette_rle)?;
+        for run in pixels.chunk_by(|a, b| a == b) {
+            let index = palette.iter().position(|c| *c == run[0]).unwrap_or(0) as u8;
+            if run.len() == 1 {
+                palette_rle.push(index);
+            } else {
+                palette_rle.push(index | 0x80);
+                write_run_length(run.len(), &mut palette_rle);
+            }
+        }
+        candidates.push(palette_rle);
+
+        if palette.len() <= MAX_PACKED_PALETTE_LEN {
+            let mut packed = vec![palette.len() as u8];
+            self.format
+                .encode_compressed_pixels(palette.iter().copied(), &mut packed)?;
+            let bits = match palette.len() {
+                2 => 1,
+                3..=4 => 2,
+                _ => 4,
+            };
+            // Each row is padded to whole bytes
+            for row in pixels.chunks(width as usize) {
+                for indices in row.chunks(8 / bits) {
+                    let mut byte = 0u8;
+                    for (i, pixel) in indices.iter().enumerate() {
+                        let index = palette.iter().position(|c| c == pixel).unwrap_or(0) as u8;
+                        byte |= index << (8 - bits * (i + 1));
+                    }
+                    packed.push(byte);
+                }
+            }
+            candidates.push(packed);
+        }
+        buf.extend_from_slice(&smallest(candidates));
+        Ok(())
+    }
 
-        for tile_y in 0..screen_height.div_ceil(ZRLE_TILE_SIZE) {
-            for tile_x in 0..screen_width.div_ceil(ZRLE_TILE_SIZE) {
-                let x = tile_x * ZRLE_TILE_SIZE;
-                let y = tile_y * ZRLE_TILE_SIZE;
-                let width = ZRLE_TILE_SIZE.clamp(0, screen_width - x);
-                let height = ZRLE_TILE_SIZE.clamp(0, screen_height - y);
+    /// Write each tile of TRLE/ZRLE with its best subencoding.
+    #[cfg(not(feature = "rayon"))]
+    fn draw_tiles<W: Write>(
+        &self,
+        (rect_x, rect_y, rect_width, rect_height): Rect,
+        tile_size: u32,
+        writer: &mut W,
+    ) -> anyhow::Result<()> {
+        let mut buf =
+            Vec::with_capacity((tile_size * tile_size) as usize * self.format.bytes_per_pixel());
+        let dimensions = (rect_width.into(), rect_height.into());
+        for (x, y, width, height) in tiles(dimensions, tile_size) {
+            buf.clear();
+            let (x, y) = (rect_x as u32 + x, rect_y as u32 + y);
+            let tile = self.background.view(x, y, width, height);
+            let pixels: Vec<_> = tile.pixels().map(|(_, _, p)| p).collect();
+            self.encode_tile(&pixels, width, &mut buf)?;
+            writer.write_all(&buf)?;
+        }
+        Ok(())
+    }
+
+    /// Write each tile of TRLE/ZRLE with its best subencoding. Tiles are
+    /// encoded in parallel, then written in order.
+    #[cfg(feature = "rayon")]
+    fn draw_tiles<W: Write>(
+        &self,
+        (rect_x, rect_y, rect_width, rect_height): Rect,
+        tile_size: u32,
+        writer: &mut W,
+    ) -> anyhow::Result<()> {
+        use rayon::prelude::*;
 
-                buf.clear();
-                buf.push(0); // no RLE, no palette
+        let dimensions = (rect_width.into(), rect_height.into());
+        let tiles: Vec<_> = tiles(dimensions, tile_size).collect();
+        let encoded = tiles
+            .into_par_iter()
+            .map(|(x, y, width, height)| {
+                let (x, y) = (rect_x as u32 + x, rect_y as u32 + y);
                 let tile = self.background.view(x, y, width, height);
-                let pixels = tile.pixels().map(|(_, _, p)| p);
-                self.format.encode_compressed_pixels(pixels, &mut buf)?;
-                encoder.write_all(&buf).unwrap();
+                let pixels: Vec<_> = tile.pixels().map(|(_, _, p)| p).collect();
+                let mut buf = Vec::new();
+                self.encode_tile(&pixels, width, &mut buf)?;
+                Ok(buf)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for buf in encoded {
+            writer.write_all(&buf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Wrap encoded pixels of rect. ZRLE tiles are compressed into zlib stream,
+/// which is only sync flushed, so the dictionary is kept for the next rect.
+fn rectangle(
+    encoding: Encoding,
+    zlib: Option<&mut ZlibStream>,
+    (x, y, width, height): Rect,
+    buf: Arc<Vec<u8>>,
+) -> anyhow::Result<FrameRectangle> {
+    let (position, size) = ((x, y), (width, height));
+    Ok(match encoding {
+        Encoding::Zrle => {
+            let zlib = zlib.context("ZRLE without zlib stream")?;
+            FrameRectangle::new_zrle_frame(position, size, zlib.compress(&buf)?)
+        }
+        Encoding::Trle => FrameRectangle::new_trle_frame(position, size, buf),
+        Encoding::Rre => FrameRectangle::new_rre_frame(position, size, buf),
+        Encoding::Hextile => FrameRectangle::new_hextile_frame(position, size, buf),
+        _ => FrameRectangle::new_raw_frame(position, size, buf),
+    })
+}
+
+/// The whole framebuffer.
+pub fn full_rect((width, height): (u16, u16)) -> Rect {
+    (0, 0, width, height)
+}
+
+/// Frame as client sees it, after given rects of current frame are
+/// drawn over the previous one.
+pub fn patch(prev: &Arc<RgbImage>, curr: &Arc<RgbImage>, rects: &[Rect]) -> Arc<RgbImage> {
+    let (width, height) = curr.dimensions();
+    let full = (0, 0, width as u16, height as u16);
+    if prev.dimensions() != curr.dimensions() || rects.contains(&full) {
+        return curr.clone();
+    }
+    let width = width as usize;
+    let mut frame = RgbImage::clone(prev);
+    let (buf, curr): (&mut [u8], &[u8]) = (&mut frame, curr);
+    for &(x, y, w, h) in rects {
+        for row in y as usize..(y + h) as usize {
+            let start = (row * width + x as usize) * 3;
+            let end = start + w as usize * 3;
+            buf[start..end].copy_from_slice(&curr[start..end]);
+        }
+    }
+    Arc::new(frame)
+}
+
+/// Find where rect of current frame comes from in the previous frame,
+/// if its content is moved vertically or horizontally, so it can be
+/// sent as a CopyRect.
+pub fn copy_source(prev: &RgbImage, curr: &RgbImage, rect: Rect) -> Option<(u16, u16)> {
+    if prev.dimensions() != curr.dimensions() {
+        return None;
+    }
+    let (width, height) = curr.dimensions();
+    let (x, y, w, h) = (rect.0 as u32, rect.1 as u32, rect.2 as u32, rect.3 as u32);
+    let moved_from = |src_x: u32, src_y: u32| {
+        (0..h).all(|r| row(prev, (src_x, src_y + r), w) == row(curr, (x, y + r), w))
+    };
+    let distance = MAX_COPY_DISTANCE as i64;
+    (1..=distance)
+        .flat_map(|d| [(0, d), (0, -d), (d, 0), (-d, 0)])
+        .map(|(dx, dy)| (x as i64 + dx, y as i64 + dy))
+        .filter(|&(src_x, src_y)| {
+            src_x >= 0
+                && src_y >= 0
+                && src_x + w as i64 <= width as i64
+                && src_y + h as i64 <= height as i64
+        })
+        .find(|&(src_x, src_y)| moved_from(src_x as u32, src_y as u32))
+        .map(|(src_x, src_y)| (src_x as u16, src_y as u16))
+}
+
+/// Changed area within region between two frames, partitioned into
+/// rectangles by given strategy.
+pub fn dirty_rects(
+    prev: &RgbImage,
+    curr: &RgbImage,
+    region: Rect,
+    strategy: RectStrategy,
+) -> Vec<Rect> {
+    changes(prev, curr, strategy)
+        .into_iter()
+        .filter_map(|rect| intersect(rect, region))
+        .collect()
+}
+
+fn changes(prev: &RgbImage, curr: &RgbImage, strategy: RectStrategy) -> Vec<Rect> {
+    let (width, height) = curr.dimensions();
+    if prev.dimensions() != curr.dimensions() {
+        return vec![(0, 0, width as u16, height as u16)];
+    }
+    // Changed tiles, adjacent ones on the same row merged
+    let mut dirty: Vec<Rect> = Vec::new();
+    for (x, y, w, h) in tiles((width, height), DIRTY_TILE_SIZE) {
+        let changed = (y..y + h).any(|row| {
+            let start = ((row * width + x) * 3) as usize;
+            let end = start + w as usize * 3;
+            prev.as_raw()[start..end] != curr.as_raw()[start..end]
+        });
+        if !changed {
+            continue;
+        }
+        let (x, y, w, h) = (x as u16, y as u16, w as u16, h as u16);
+        match dirty.last_mut() {
+            Some(last) if last.1 == y && last.0 + last.2 == x => last.2 += w,
+            _ => dirty.push((x, y, w, h)),
+        }
+    }
+    if dirty.is_empty() {
+        return dirty;
+    }
+
+    let area = |(_, _, w, h): Rect| w as u64 * h as u64;
+    let left = dirty.iter().map(|r| r.0).min().unwrap_or(0);
+    let top = dirty.iter().map(|r| r.1).min().unwrap_or(0);
+    let right = dirty.iter().map(|r| r.0 + r.2).max().unwrap_or(0);
+    let bottom = dirty.iter().map(|r| r.1 + r.3).max().unwrap_or(0);
+    let bounding = (left, top, right - left, bottom - top);
+    let use_bounding = match strategy {
+        RectStrategy::Bounding => true,
+        RectStrategy::Tiles => false,
+        RectStrategy::Auto => {
+            let dirty_area: u64 = dirty.iter().copied().map(area).sum();
+            dirty_area as f64 / area(bounding) as f64 >= AUTO_BOUNDING_FILL_RATIO
+        }
+    };
+    if use_bounding {
+        vec![bounding]
+    } else {
+        dirty
+    }
+}
+
+/// Distinct colors in order of appearance, None if more than max_len.
+fn palette(pixels: &[Rgb<u8>], max_len: usize) -> Option<Vec<Rgb<u8>>> {
+    let mut palette = Vec::with_capacity(max_len);
+    for pixel in pixels {
+        if !palette.contains(pixel) {
+            if palette.len() == max_len {
+                return None;
             }
+            palette.push(*pixel);
         }
+    }
+    Some(palette)
+}
 
-        encoder.flush()?;
-        let buf = mem::take(encoder.get_mut());
-        Ok(buf)
+/// Distinct colors in order of appearance, and the index of each pixel,
+/// or None if there are more than max_len colors.
+fn indexed(pixels: &[Rgb<u8>], max_len: usize) -> Option<(Vec<Rgb<u8>>, Vec<u8>)> {
+    let mut palette = Vec::new();
+    let mut index_of: HashMap<Rgb<u8>, u8> = HashMap::new();
+    let mut indices = Vec::with_capacity(pixels.len());
+    for &pixel in pixels {
+        let index = match index_of.get(&pixel) {
+            Some(&index) => index,
+            None if palette.len() == max_len => return None,
+            None => {
+                let index = palette.len() as u8;
+                palette.push(pixel);
+                index_of.insert(pixel, index);
+                index
+            }
+        };
+        indices.push(index);
+    }
+    Some((palette, indices))
+}
+
+fn smallest(candidates: Vec<Vec<u8>>) -> Vec<u8> {
+    candidates
+        .into_iter()
+        .min_by_key(|candidate| candidate.len())
+        .unwrap_or_default()
+}
+
+/// Run length of TRLE & ZRLE: length minus one, as a sum of bytes where
+/// all but the last are 255.
+fn write_run_length(run: usize, buf: &mut Vec<u8>) {
+    let mut rest = run - 1;
+    while rest >= 255 {
+        buf.push(255);
+        rest -= 255;
+    }
+    buf.push(rest as u8);
+}
+
+/// Tight compact length: 7 bits in each of the first two bytes, least
+/// significant first, with the high bit set if more bytes follow, then 8
+/// bits in the third. Lengths are well below 2^22 as rectangles are split.
+fn write_compact_len(len: usize, buf: &mut Vec<u8>) {
+    buf.push((len & 0x7f) as u8 | if len > 0x7f { 0x80 } else { 0 });
+    if len > 0x7f {
+        buf.push((len >> 7 & 0x7f) as u8 | if len > 0x3fff { 0x80 } else { 0 });
+        if len > 0x3fff {
+            buf.push((len >> 14) as u8);
+        }
+    }
+}
+
+/// Colors of a whole frame, from [`analyze`].
+#[derive(Debug, Clone, Copy)]
+pub struct ColorStats {
+    /// Number of distinct colors
+    pub colors: usize,
+    /// The most frequent color
+    pub dominant: Rgb<u8>,
+}
+
+/// Count distinct colors of frame and find the most frequent one, giving up
+/// with None once there are more than max_colors.
+pub fn analyze(frame: &RgbImage, max_colors: usize) -> Option<ColorStats> {
+    let mut counts: HashMap<Rgb<u8>, usize> = HashMap::new();
+    for &pixel in frame.pixels() {
+        if counts.len() == max_colors && !counts.contains_key(&pixel) {
+            return None;
+        }
+        *counts.entry(pixel).or_default() += 1;
+    }
+    let dominant = counts
+        .iter()
+        .max_by_key(|&(_, count)| count)
+        .map_or(Rgb([0, 0, 0]), |(&pixel, _)| pixel);
+    Some(ColorStats {
+        colors: counts.len(),
+        dominant,
+    })
+}
+
+/// The most frequent color.
+fn most_common(pixels: &[Rgb<u8>]) -> Rgb<u8> {
+    let mut counts: HashMap<Rgb<u8>, usize> = HashMap::new();
+    for &pixel in pixels {
+        *counts.entry(pixel).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(_, count)| count)
+        .map_or(Rgb([0, 0, 0]), |(pixel, _)| pixel)
+}
+
+/// Cover pixels differ from background with solid rectangles.
+/// Runs on each row are merged with identical ones right above them.
+fn subrects(pixels: &[Rgb<u8>], width: u32, background: Rgb<u8>) -> Vec<(Rgb<u8>, Rect)> {
+    let mut rects: Vec<(Rgb<u8>, Rect)> = Vec::new();
+    // Index of rects ending at the previous row, by (x, width, color)
+    let mut above: HashMap<(u16, u16, Rgb<u8>), usize> = HashMap::new();
+    let mut current = HashMap::new();
+    for (y, row) in pixels.chunks(width as usize).enumerate() {
+        let mut x = 0;
+        while x < row.len() {
+            let color = row[x];
+            let run = row[x..].iter().take_while(|&&p| p == color).count();
+            if color != background {
+                let key = (x as u16, run as u16, color);
+                let index = match above.get(&key) {
+                    Some(&index) => {
+                        rects[index].1 .3 += 1;
+                        index
+                    }
+                    None => {
+                        rects.push((color, (x as u16, y as u16, run as u16, 1)));
+                        rects.len() - 1
+                    }
+                };
+                current.insert(key, index);
+            }
+            x += run;
+        }
+        mem::swap(&mut above, &mut current);
+        current.clear();
+    }
+    rects
+}
+
+/// Pixels of a row starting from given position.
+fn row(image: &RgbImage, (x, y): (u32, u32), len: u32) -> &[u8] {
+    let start = ((y * image.width() + x) * 3) as usize;
+    &image.as_raw()[start..start + len as usize * 3]
+}
+
+/// Smallest rectangle covering both.
+pub fn union(a: Rect, b: Rect) -> Rect {
+    let left = a.0.min(b.0);
+    let top = a.1.min(b.1);
+    let right = (a.0 + a.2).max(b.0 + b.2);
+    let bottom = (a.1 + a.3).max(b.1 + b.3);
+    (left, top, right - left, bottom - top)
+}
+
+/// Overlapping area of two rectangles, None if it is empty.
+pub fn intersect(a: Rect, b: Rect) -> Option<Rect> {
+    let left = a.0.max(b.0);
+    let top = a.1.max(b.1);
+    let right = (a.0 as u32 + a.2 as u32).min(b.0 as u32 + b.2 as u32);
+    let bottom = (a.1 as u32 + a.3 as u32).min(b.1 as u32 + b.3 as u32);
+    if right <= left as u32 || bottom <= top as u32 {
+        return None;
+    }
+    Some((
+        left,
+        top,
+        (right - left as u32) as u16,
+        (bottom - top as u32) as u16,
+    ))
+}
+
+/// Read pointer picture given in arguments, if any.
+pub fn load_pointer(args: &Args) -> anyhow::Result<Option<Pointer>> {
+    let Some(path) = args.pointer.as_ref() else {
+        return Ok(None);
+    };
+    let pointer = Pointer::open(path)?;
+    match args.pointer_hotspot {
+        Some(hotspot) => pointer.with_hotspot(hotspot).map(Some),
+        None => Ok(Some(pointer)),
+    }
+}
+
+/// Read or fill background as given in arguments, with overlays drawn.
+pub fn load_background(args: &Args) -> anyhow::Result<RgbImage> {
+    let background = match (args.solid, args.gradient, args.size) {
+        (Some(color), _, Some((width, height))) => {
+            RgbImage::from_pixel(width.into(), height.into(), color).into()
+        }
+        (_, Some((top, bottom)), Some((width, height))) => {
+            gradient((width.into(), height.into()), top, bottom).into()
+        }
+        _ => {
+            let background = args.background.as_ref().context("Missing background")?;
+            match args.background_data.as_deref() {
+                // No file name for stdin & URLs, format is guessed from content
+                Some(data) => image::load_from_memory(data).with_context(|| {
+                    format!("Decode background picture from {}", describe(background))
+                })?,
+                None => ImageReader::open(background)
+                    .context("Read backgroud picture")?
+                    .decode()
+                    .context("Decode backgroud picture")?,
+            }
+        }
+    };
+    compose(background, args)
+}
+
+/// Read background picture from stdin for "-", or fetch it for HTTP(S)
+/// URLs. None for local files, which are read on loading.
+pub async fn fetch_background(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    if path == Path::new("-") {
+        let mut data = Vec::new();
+        tokio::io::stdin()
+            .read_to_end(&mut data)
+            .await
+            .context("Read background picture from stdin")?;
+        return Ok(Some(data));
+    }
+    let Some(url) = path.to_str().filter(|path| is_url(path)) else {
+        return Ok(None);
+    };
+    #[cfg(feature = "remote")]
+    {
+        let response = reqwest::get(url)
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Fetch background picture from {}", url))?;
+        let data = response
+            .bytes()
+            .await
+            .with_context(|| format!("Fetch background picture from {}", url))?;
+        debug!("Fetched {} bytes from {}", data.len(), url);
+        Ok(Some(data.into()))
+    }
+    #[cfg(not(feature = "remote"))]
+    bail!("Fetching {} needs the remote feature", url)
+}
+
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Where background picture comes from, for error messages.
+fn describe(path: &Path) -> Cow<'_, str> {
+    if path == Path::new("-") {
+        "stdin".into()
+    } else {
+        path.to_string_lossy()
+    }
+}
+
+/// Flatten background picture and draw overlays on it, as set in arguments.
+pub fn compose(background: DynamicImage, args: &Args) -> anyhow::Result<RgbImage> {
+    let mut background = match args.flatten_color {
+        Some(color) => flatten(background, color),
+        None => background.into_rgb8(),
+    };
+    if let Some((width, height)) = args.resolution {
+        background = scale(background, (width.into(), height.into()), args);
+    }
+    for layer in args.layer.iter() {
+        draw_layer(&mut background, layer)
+            .with_context(|| format!("Draw layer {}", layer.path.display()))?;
+    }
+    if let Some(url) = args.qr.as_ref() {
+        let url = args.expand_placeholders(url);
+        overlay::draw_qr(&mut background, &url, args.qr_size, args.qr_position)
+            .context("Draw QR code")?;
+    }
+    // Timed text is drawn later, on every tick
+    if let Some(text) = args
+        .overlay_template()
+        .filter(|text| !overlay::is_timed(text))
+    {
+        let size = args.overlay_size.into();
+        overlay::draw_text(
+            &mut background,
+            &text,
+            size,
+            args.overlay_color,
+            args.overlay_position,
+        );
+    }
+    Ok(background)
+}
+
+/// Scale picture to exact size, according to aspect mode in arguments.
+fn scale(image: RgbImage, (width, height): (u32, u32), args: &Args) -> RgbImage {
+    if image.dimensions() == (width, height) {
+        return image;
+    }
+    let image = DynamicImage::ImageRgb8(image);
+    let filter = args.scale_filter.into();
+    match args.aspect {
+        Aspect::Stretch => image.resize_exact(width, height, filter).into_rgb8(),
+        Aspect::Fill => image.resize_to_fill(width, height, filter).into_rgb8(),
+        Aspect::Fit => {
+            let image = image.resize(width, height, filter).into_rgb8();
+            let mut canvas = RgbImage::from_pixel(width, height, args.fill_color);
+            let x = (width - image.width()) / 2;
+            let y = (height - image.height()) / 2;
+            imageops::replace(&mut canvas, &image, x.into(), y.into());
+            canvas
+        }
+    }
+}
+
+/// Fill picture with vertical gradient from top to bottom color.
+fn gradient((width, height): (u32, u32), top: Rgb<u8>, bottom: Rgb<u8>) -> RgbImage {
+    let last = height.saturating_sub(1).max(1);
+    RgbImage::from_fn(width, height, |_, y| {
+        let mix = |top: u8, bottom: u8| {
+            ((top as u32 * (last - y) + bottom as u32 * y + last / 2) / last) as u8
+        };
+        Rgb([
+            mix(top[0], bottom[0]),
+            mix(top[1], bottom[1]),
+            mix(top[2], bottom[2]),
+        ])
+    })
+}
+
+/// Composite picture over a solid color according to its alpha channel.
+fn flatten(image: DynamicImage, color: Rgb<u8>) -> RgbImage {
+    if !image.color().has_alpha() {
+        return image.into_rgb8();
+    }
+    let image = image.into_rgba8();
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        blend(*image.get_pixel(x, y), color)
+    })
+}
+
+/// Composite color over opaque background by its alpha.
+fn blend(Rgba([r, g, b, alpha]): Rgba<u8>, Rgb([bg_r, bg_g, bg_b]): Rgb<u8>) -> Rgb<u8> {
+    let blend = |fg: u8, bg: u8| {
+        let alpha = alpha as u32;
+        ((fg as u32 * alpha + bg as u32 * (255 - alpha) + 127) / 255) as u8
+    };
+    Rgb([blend(r, bg_r), blend(g, bg_g), blend(b, bg_b)])
+}
+
+/// Blend picture onto background by its alpha, clipping what's outside.
+fn draw_layer(background: &mut RgbImage, layer: &Layer) -> anyhow::Result<()> {
+    let (x, y) = (u32::from(layer.position.0), u32::from(layer.position.1));
+    if x >= background.width() || y >= background.height() {
+        bail!("Position ({}, {}) out of background", x, y);
+    }
+    let image = ImageReader::open(&layer.path)?.decode()?.into_rgba8();
+    let width = image.width().min(background.width() - x);
+    let height = image.height().min(background.height() - y);
+    for (dx, dy, color) in image.view(0, 0, width, height).pixels() {
+        let pixel = background.get_pixel_mut(x + dx, y + dy);
+        *pixel = blend(color, *pixel);
+    }
+    Ok(())
+}
+
+/// Split area into `(x, y, width, height)` tiles, left to right then top to
+/// bottom. Tiles on the right and bottom edges may be smaller.
+pub fn tiles(
+    (width, height): (u32, u32),
+    tile_size: u32,
+) -> impl Iterator<Item = (u32, u32, u32, u32)> {
+    (0..height).step_by(tile_size as usize).flat_map(move |y| {
+        (0..width)
+            .step_by(tile_size as usize)
+            .map(move |x| (x, y, tile_size.min(width - x), tile_size.min(height - y)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use flate2::write::ZlibDecoder;
+    use image::ImageFormat;
+
+    use super::*;
+    use crate::{
+        rfb_client::Client,
+        rfp::{write_frame, RfpVersion},
+        serve_pipe, Options,
+    };
+
+    #[test]
+    fn from_image_in_memory() {
+        let image = RgbImage::from_fn(10, 10, |x, y| Rgb([x as u8 * 20, y as u8 * 20, 0]));
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        assert_eq!(screen.dimensions(), (10, 10));
+        assert!(*screen.frame() == image);
+        assert!(screen.cursor(&PixelFormat::default()).is_none());
+
+        let pointer = Pointer::from_image(RgbaImage::new(4, 4).into()).unwrap();
+        let screen = Screen::from_image(image, Some(pointer)).unwrap();
+        assert!(screen.cursor(&PixelFormat::default()).is_some());
+    }
+
+    /// Solid, two-colour, many-colour runs, and gradient tiles side by
+    /// side, with partial tiles at the bottom.
+    fn tiles_picture(tile: u32) -> RgbImage {
+        RgbImage::from_fn(tile * 4, tile * 3 / 2, |x, y| match x / tile {
+            0 => Rgb([1, 2, 3]),
+            1 if (x * x + y) % 7 == 0 => Rgb([255, 0, 0]),
+            1 => Rgb([0, 0, 255]),
+            2 => Rgb([(x / 4 * 13 + y * 11) as u8, 0, 40]),
+            _ => Rgb([(x * 5) as u8, (y * 7) as u8, ((x + y) * 3) as u8]),
+        })
+    }
+
+    /// Send whole picture in encoding and decode it back.
+    async fn round_trip(image: &RgbImage, encodings: &[Encoding]) -> RgbImage {
+        let (width, height) = image.dimensions();
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        let (pipe, _session) = serve_pipe(screen, Options::default());
+        let mut client = Client::connect(pipe, RfpVersion::V3_8).await.unwrap();
+        client.set_encodings(encodings).await.unwrap();
+        let size = (width as u16, height as u16);
+        client.request_update(false, (0, 0), size).await.unwrap();
+        let sent = client.read_update().await.unwrap();
+        assert!(sent.iter().all(|&e| e == encodings[0]), "{:?}", sent);
+        client.framebuffer().clone()
+    }
+
+    #[tokio::test]
+    async fn hextile_round_trip() {
+        let image = tiles_picture(HEXTILE_TILE_SIZE);
+        assert!(round_trip(&image, &[Encoding::Hextile]).await == image);
+    }
+
+    #[tokio::test]
+    async fn trle_round_trip() {
+        let image = tiles_picture(TRLE_TILE_SIZE);
+        assert!(round_trip(&image, &[Encoding::Trle]).await == image);
+    }
+
+    #[tokio::test]
+    async fn zrle_round_trip() {
+        let image = tiles_picture(ZRLE_TILE_SIZE);
+        assert!(round_trip(&image, &[Encoding::Zrle]).await == image);
+    }
+
+    #[tokio::test]
+    async fn tight_round_trip() {
+        let solid = RgbImage::from_pixel(50, 33, Rgb([9, 99, 199]));
+        for image in [solid, tiles_picture(32)] {
+            assert!(round_trip(&image, &[Encoding::Tight]).await == image);
+        }
+    }
+
+    #[tokio::test]
+    async fn big_endian_zrle_round_trip() {
+        let image = tiles_picture(ZRLE_TILE_SIZE);
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        for (red_shift, green_shift, blue_shift) in [(16, 8, 0), (0, 8, 16), (24, 16, 8)] {
+            let format = PixelFormat {
+                big_endian_flag: true,
+                red_shift,
+                green_shift,
+                blue_shift,
+                ..Default::default()
+            };
+            let (pipe, _session) = serve_pipe(screen.clone(), Options::default());
+            let mut client = Client::connect(pipe, RfpVersion::V3_8).await.unwrap();
+            client.set_pixel_format(format).await.unwrap();
+            client.set_encodings(&[Encoding::Zrle]).await.unwrap();
+            client
+                .request_update(false, (0, 0), screen.dimensions())
+                .await
+                .unwrap();
+            assert_eq!(client.read_update().await.unwrap(), [Encoding::Zrle]);
+            assert!(client.framebuffer() == &image, "{}", format);
+        }
+    }
+
+    #[test]
+    fn reuse_encoded_full_frame() {
+        let screen = Screen::from_image(tiles_picture(16), None).unwrap();
+        let format = PixelFormat::default();
+        let full = full_rect(screen.dimensions());
+        let draw =
+            |rect| FrameEncoder::new(&screen.background, &format).encode(Encoding::Raw, rect);
+        let first = screen.cached(&format, Encoding::Raw, full, draw).unwrap();
+        // Another client's request, must not encode again
+        let second = screen
+            .clone()
+            .cached(&format, Encoding::Raw, full, |_| unreachable!())
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let bgr = *crate::rfp::PIXEL_FORMAT_BGR888;
+        let other = screen.cached(&bgr, Encoding::Raw, full, draw).unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+    }
+
+    #[test]
+    fn flatten_over_white() {
+        let mut image = RgbaImage::from_pixel(3, 1, Rgba([255, 0, 0, 128]));
+        image.put_pixel(1, 0, Rgba([0, 0, 0, 0]));
+        image.put_pixel(2, 0, Rgba([0, 0, 255, 255]));
+        let mut png = io::Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageFormat::Png).unwrap();
+        let image = image::load_from_memory(png.get_ref()).unwrap();
+
+        let args = Args::parse_from(["vncdisplay", "-b", "a.png", "--flatten-color", "ffffff"]);
+        let flattened = compose(image, &args).unwrap();
+        assert_eq!(flattened.get_pixel(0, 0), &Rgb([255, 127, 127]));
+        assert_eq!(flattened.get_pixel(1, 0), &Rgb([255, 255, 255]));
+        assert_eq!(flattened.get_pixel(2, 0), &Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn qr_code_in_corner() {
+        let grey = Rgb([50, 60, 70]);
+        let background = RgbImage::from_pixel(400, 300, grey);
+        let args = Args::parse_from(["vncdisplay", "-b", "a.png", "--qr", "vnc://host:5900"]);
+        let composed = compose(background.into(), &args).unwrap();
+        // Quiet zone of the code is light on the right edge
+        let size = (0..300).filter(|&y| composed[(399, y)] != grey).count() as u32;
+        assert!(size > 0 && size < 300);
+        // Bottom right corner is the QR code, the rest is untouched
+        let corner = imageops::crop_imm(&composed, 400 - size, 300 - size, size, size);
+        let dark = corner.pixels().filter(|(_, _, p)| p.0 == [0; 3]).count();
+        let light = corner.pixels().filter(|(_, _, p)| p.0 == [255; 3]).count();
+        assert!(dark > 0 && light > 0);
+        assert_eq!(dark + light, (size * size) as usize);
+        let mut outside = composed
+            .enumerate_pixels()
+            .filter(|(x, y, _)| *x < 400 - size || *y < 300 - size);
+        assert!(outside.all(|(_, _, pixel)| *pixel == grey));
+    }
+
+    #[test]
+    fn letterbox_portrait_into_landscape() {
+        let portrait = RgbImage::from_pixel(50, 100, Rgb([255, 0, 0]));
+        #[rustfmt::skip]
+        let args = Args::parse_from([
+            "vncdisplay", "-b", "a.png", "--resolution", "200x100", "--aspect", "fit",
+            "--fill-color", "00ff00",
+        ]);
+        let composed = compose(portrait.into(), &args).unwrap();
+        assert_eq!(composed.dimensions(), (200, 100));
+        for (x, y, pixel) in composed.enumerate_pixels() {
+            let expected = if (75..125).contains(&x) {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 255, 0])
+            };
+            assert_eq!(pixel, &expected, "({}, {})", x, y);
+        }
+        let screen = Screen::from_image(composed, None).unwrap();
+        assert_eq!(screen.dimensions(), (200, 100));
+    }
+
+    #[test]
+    fn composite_layers() {
+        // Opaque blue, transparent, and half-transparent red pixels
+        let mut layer = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 128]));
+        layer.put_pixel(0, 0, Rgba([0, 0, 255, 255]));
+        layer.put_pixel(1, 0, Rgba([0, 0, 0, 0]));
+        let path =
+            std::env::temp_dir().join(format!("vncdisplay-layer-{}.png", std::process::id()));
+        layer.save(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let at = format!("{}@4,4", path);
+        #[rustfmt::skip]
+        let args = Args::parse_from([
+            "vncdisplay", "--solid", "00ff00", "--size", "6x6", "--layer", &at, "--layer", path,
+        ]);
+        let composed = load_background(&args).unwrap();
+        let green = Rgb([0, 255, 0]);
+        let blue = Rgb([0, 0, 255]);
+        let red_on_green = Rgb([128, 127, 0]);
+        #[rustfmt::skip]
+        let expected = [
+            [blue, green, red_on_green, red_on_green, green, green],
+            [red_on_green, red_on_green, red_on_green, red_on_green, green, green],
+            [red_on_green, red_on_green, red_on_green, red_on_green, green, green],
+            [red_on_green, red_on_green, red_on_green, red_on_green, green, green],
+            // Second layer is clipped to 2x2
+            [green, green, green, green, blue, green],
+            [green, green, green, green, red_on_green, red_on_green],
+        ];
+        for (x, y, pixel) in composed.enumerate_pixels() {
+            assert_eq!(pixel, &expected[y as usize][x as usize], "({}, {})", x, y);
+        }
+
+        let outside = format!("{}@6,0", path);
+        #[rustfmt::skip]
+        let args = Args::parse_from([
+            "vncdisplay", "--solid", "00ff00", "--size", "6x6", "--layer", &outside,
+        ]);
+        assert!(load_background(&args).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sparse_and_dense_changes() {
+        let prev = RgbImage::new(300, 200);
+        let whole = full_rect((300, 200));
+        let changed = |points: &[(u32, u32)]| {
+            let mut curr = prev.clone();
+            for &(x, y) in points {
+                curr.put_pixel(x, y, Rgb([1, 1, 1]));
+            }
+            curr
+        };
+        assert!(dirty_rects(&prev, &prev, whole, RectStrategy::Auto).is_empty());
+
+        // Opposite corners
+        let sparse = changed(&[(5, 5), (290, 190)]);
+        let tiles = vec![(0, 0, 64, 64), (256, 128, 44, 64)];
+        assert_eq!(
+            dirty_rects(&prev, &sparse, whole, RectStrategy::Auto),
+            tiles
+        );
+        assert_eq!(
+            dirty_rects(&prev, &sparse, whole, RectStrategy::Tiles),
+            tiles
+        );
+        let bounding = dirty_rects(&prev, &sparse, whole, RectStrategy::Bounding);
+        assert_eq!(bounding, [(0, 0, 300, 192)]);
+
+        // Four adjacent tiles
+        let dense = changed(&[(5, 5), (70, 5), (5, 70), (70, 70)]);
+        let tiles = vec![(0, 0, 128, 64), (0, 64, 128, 64)];
+        assert_eq!(
+            dirty_rects(&prev, &dense, whole, RectStrategy::Tiles),
+            tiles
+        );
+        let auto = dirty_rects(&prev, &dense, whole, RectStrategy::Auto);
+        assert_eq!(auto, [(0, 0, 128, 128)]);
+        let region = dirty_rects(&prev, &dense, (0, 0, 100, 100), RectStrategy::Auto);
+        assert_eq!(region, [(0, 0, 100, 100)]);
+    }
+
+    #[tokio::test]
+    async fn zrle_frames_share_zlib_stream() {
+        let format = PixelFormat::default();
+        let first = tiles_picture(ZRLE_TILE_SIZE);
+        let second = imageops::flip_horizontal(&first);
+        let rect = full_rect((first.width() as u16, first.height() as u16));
+        let mut zlib = ZlibStream::new(Compression::default());
+        let mut inflate = ZlibDecoder::new(Vec::new());
+        for (i, frame) in [first, second].into_iter().enumerate() {
+            let tiles = FrameEncoder::new(&frame, &format)
+                .encode(Encoding::Zrle, rect)
+                .unwrap();
+            let screen = Screen::from_image(frame, None).unwrap();
+            let encoded = screen.encode(&format, Encoding::Zrle, Some(&mut zlib), rect);
+            let mut msg = Vec::new();
+            write_frame(&mut msg, &[encoded.unwrap()]).await.unwrap();
+            // Skip headers of update, rectangle, and ZRLE length
+            let compressed = &msg[4 + 12 + 4..];
+            if i > 0 {
+                // Continues the stream, no zlib header
+                let mut fresh = ZlibDecoder::new(Vec::new());
+                assert!(fresh.write_all(compressed).is_err());
+            }
+            inflate.write_all(compressed).unwrap();
+            inflate.flush().unwrap();
+            assert_eq!(mem::take(inflate.get_mut()), tiles, "frame {}", i);
+        }
+    }
+
+    /// Minimal frame source, with defaults for everything but the frame.
+    #[derive(Clone)]
+    struct Checkerboard;
+
+    impl FrameSource for Checkerboard {
+        fn frame(&self) -> Arc<RgbImage> {
+            Arc::new(RgbImage::from_fn(2, 2, |x, y| {
+                if (x + y) % 2 == 0 {
+                    Rgb([0, 0, 0])
+                } else {
+                    Rgb([255, 255, 255])
+                }
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_checkerboard_source() {
+        assert_eq!(Checkerboard.dimensions(), (2, 2));
+        assert!(Checkerboard.cursor(&PixelFormat::default()).is_none());
+        for encoding in [Encoding::Raw, Encoding::Zrle] {
+            let (pipe, _session) = serve_pipe(Checkerboard, Options::default());
+            let mut client = Client::connect(pipe, RfpVersion::V3_8).await.unwrap();
+            client.set_encodings(&[encoding]).await.unwrap();
+            client.request_update(false, (0, 0), (2, 2)).await.unwrap();
+            assert_eq!(client.read_update().await.unwrap(), [encoding]);
+            assert!(client.framebuffer() == &*Checkerboard.frame());
+        }
+    }
+
+    #[tokio::test]
+    async fn reuse_raw_bands_across_clients() {
+        // Taller than one band, so Raw is sent as 256 + 44 rows
+        let image = tiles_picture(200);
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        for hits in [0, 2] {
+            let (pipe, _session) = serve_pipe(screen.clone(), Options::default());
+            let mut client = Client::connect(pipe, RfpVersion::V3_8).await.unwrap();
+            client
+                .request_update(false, (0, 0), (800, 300))
+                .await
+                .unwrap();
+            let encodings = client.read_update().await.unwrap();
+            assert_eq!(encodings, [Encoding::Raw, Encoding::Raw]);
+            assert!(client.framebuffer() == &image);
+            assert_eq!(screen.cache.lock().unwrap().hits, hits);
+        }
+    }
+
+    #[test]
+    fn zlib_stream_flushes_everything() {
+        let mut seed = 1u64;
+        let mut noise = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|i| {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    if i % 3 == 0 {
+                        (seed >> 33) as u8
+                    } else {
+                        0
+                    }
+                })
+                .collect()
+        };
+        for level in 0..=9 {
+            let mut zlib = ZlibStream::new(Compression::new(level));
+            let mut inflate = ZlibDecoder::new(Vec::new());
+            for len in [0, 150_000, 400_000] {
+                let data = noise(len);
+                let compressed = zlib.compress(&data).unwrap();
+                inflate.write_all(&compressed).unwrap();
+                inflate.flush().unwrap();
+                assert!(*inflate.get_ref() == data, "level {} len {}", level, len);
+                inflate.get_mut().clear();
+            }
+        }
+    }
+
+    #[test]
+    fn zlib_stream_changes_level_in_place() {
+        let mut zlib = ZlibStream::new(Compression::new(9));
+        let mut inflate = ZlibDecoder::new(Vec::new());
+        let data: Vec<u8> = (0..50_000u32)
+            .map(|i| ((i % 251) ^ (i / 1000)) as u8)
+            .collect();
+        let mut sizes = Vec::new();
+        for level in [9, 0, 1, 6, 6] {
+            zlib.set_level(Compression::new(level)).unwrap();
+            let compressed = zlib.compress(&data).unwrap();
+            sizes.push(compressed.len());
+            inflate.write_all(&compressed).unwrap();
+            inflate.flush().unwrap();
+            assert!(*inflate.get_ref() == data, "level {}", level);
+            inflate.get_mut().clear();
+        }
+        // Stored at level 0, compressed again after
+        assert!(sizes[1] > data.len(), "{:?}", sizes);
+        assert!(sizes[2] < data.len() / 4, "{:?}", sizes);
+        assert_eq!(zlib.total_in(), 5 * data.len() as u64);
     }
 }
@@ -0,0 +1,1945 @@
+//! Serving a screen to VNC clients.
+
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    future::{self, Future},
+    io::Write,
+    mem,
+    path::{Path, PathBuf},
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{self, ready, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context};
+use flate2::Compression;
+use image::{Rgb, RgbImage};
+use log::{debug, info, warn};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    net::TcpStream,
+    sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore},
+    task::{JoinHandle, JoinSet},
+    time::{self, MissedTickBehavior},
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics;
+#[cfg(feature = "tls")]
+use crate::vencrypt;
+use crate::{
+    auth::{self, Verifier},
+    cli::{ClientProfile, Profile, RectStrategy},
+    listener::{Listener, Stream},
+    rfp::{self, ClientMessage, FrameRectangle, PixelFormat},
+    screen::{self, FrameSource, Rect, Screen, TightStreams, ZlibStream},
+};
+
+/// Interval of fences for measuring round-trip time
+const FENCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Deadline for clients to finish handshaking, long enough for typing
+/// password but not for dribbling the protocol
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bits of red, green, and blue sent if `force_16bpp`
+const FORCED_PRECISION: [u8; 3] = [5, 6, 5];
+
+/// Frames with up to this colors are sent as RRE if `auto_encoding`
+const AUTO_RRE_MAX_COLORS: usize = 16;
+
+/// Bytes written at once under `max_bandwidth`, as this long of it
+const BANDWIDTH_BURST: Duration = Duration::from_millis(100);
+
+/// Capacity of each direction of [`serve_pipe`]
+const PIPE_BUFFER_LEN: usize = 64 * 1024;
+
+/// Delays between attempts to connect the viewer, doubled on each failure
+const CONNECT_RETRY_MIN: Duration = Duration::from_secs(1);
+const CONNECT_RETRY_MAX: Duration = Duration::from_secs(60);
+/// Pause accepting after failures like running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Server settings, see the command line options of the same names.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub name: String,
+    pub name_file: Option<PathBuf>,
+    pub password: Option<String>,
+    /// Check VNC authentication by this shell command instead of password
+    pub auth_command: Option<String>,
+    pub clipboard: Option<String>,
+    pub dump_clipboard: Option<PathBuf>,
+    pub max_clipboard_len: usize,
+    pub max_clients: Option<usize>,
+    pub accept_queue: usize,
+    pub queue_timeout: Option<u64>,
+    pub bell_interval: Option<u64>,
+    pub cursor_pos: Option<(u16, u16)>,
+    pub reload_key: Option<u32>,
+    pub quit_key: Option<u32>,
+    pub read_only: bool,
+    /// Disconnect clients sending unsupported messages instead of skipping
+    pub strict: bool,
+    pub client_profile: ClientProfile,
+    pub rect_strategy: RectStrategy,
+    /// Use RRE for clients supporting it if the screen has few colors
+    pub auto_encoding: bool,
+    pub raw_band_rows: u16,
+    /// Sent in ServerInit, used until client sets its own
+    pub pixel_format: PixelFormat,
+    /// Send colors at RGB565 precision in whatever format clients ask
+    pub force_16bpp: bool,
+    pub max_fps: Option<u32>,
+    /// Pace writes to each client at this bytes per second
+    pub max_bandwidth: Option<u64>,
+    pub idle_timeout: u64,
+    /// Show a solid frame to clients sending no input for this seconds
+    pub blank_after: Option<u64>,
+    pub blank_color: Rgb<u8>,
+    pub shutdown_timeout: u64,
+    pub connect: Option<String>,
+    pub connect_once: bool,
+    pub once: bool,
+    /// Offer VeNCrypt only, upgrading clients to TLS
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<tokio_rustls::rustls::ServerConfig>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            name: "VNC Display".into(),
+            name_file: None,
+            password: None,
+            auth_command: None,
+            clipboard: None,
+            dump_clipboard: None,
+            max_clipboard_len: rfp::MAX_CUT_TEXT_LEN,
+            max_clients: None,
+            accept_queue: 0,
+            queue_timeout: None,
+            bell_interval: None,
+            cursor_pos: None,
+            reload_key: None,
+            quit_key: None,
+            read_only: false,
+            strict: false,
+            client_profile: Default::default(),
+            rect_strategy: Default::default(),
+            auto_encoding: false,
+            raw_band_rows: 256,
+            pixel_format: Default::default(),
+            force_16bpp: false,
+            max_fps: None,
+            max_bandwidth: None,
+            idle_timeout: 300,
+            blank_after: None,
+            blank_color: Rgb([0, 0, 0]),
+            shutdown_timeout: 10,
+            connect: None,
+            connect_once: false,
+            once: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// Accept & serve clients with the screen until listener fails.
+pub async fn serve<F: FrameSource>(listener: impl Into<Listener>, screen: F) -> anyhow::Result<()> {
+    serve_with([listener.into()], screen, Default::default()).await
+}
+
+/// Like [`serve`] but with given settings, accepting clients from all
+/// listeners until any of them fails.
+pub async fn serve_with<F: FrameSource>(
+    listeners: impl IntoIterator<Item = Listener>,
+    screen: F,
+    options: Options,
+) -> anyhow::Result<()> {
+    serve_until(listeners, screen, options, future::pending()).await
+}
+
+/// Serve a client over the returned in-memory pipe, as if accepted from a
+/// listener, for driving the server within the same process. The task ends
+/// with the session's result once the pipe is dropped.
+pub fn serve_pipe<F: FrameSource>(
+    screen: F,
+    options: Options,
+) -> (DuplexStream, JoinHandle<anyhow::Result<()>>) {
+    let (client, server) = io::duplex(PIPE_BUFFER_LEN);
+    let session = tokio::spawn(async move {
+        // Never stopping, but the sender must outlive the session
+        let (_stop, stopping) = watch::channel(false);
+        let blanks = BlankScreens::default();
+        handle_client(server, "pipe", screen, &options, &blanks, stopping).await
+    });
+    (client, session)
+}
+
+/// Like [`serve_with`] but stop accepting clients once shutdown resolves,
+/// then disconnect clients and wait for them at most `shutdown_timeout`.
+/// Also returns after the session with `connect` viewer if `connect_once`,
+/// or after the first accepted client with its result if `once`.
+pub async fn serve_until<F: FrameSource>(
+    listeners: impl IntoIterator<Item = Listener>,
+    screen: F,
+    options: Options,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    if options
+        .password
+        .as_ref()
+        .is_some_and(|password| password.len() > auth::MAX_PASSWORD_LEN)
+    {
+        warn!(
+            "Only the first {} bytes of password are used",
+            auth::MAX_PASSWORD_LEN
+        );
+    }
+    let options = Arc::new(options);
+    let blanks = BlankScreens::default();
+    // Each client holds a receiver until disconnected
+    let (stop, stopping) = watch::channel(false);
+
+    // Connections wait in the queue for a permit when max clients reached
+    let permits = Arc::new(Semaphore::new(
+        options.max_clients.unwrap_or(Semaphore::MAX_PERMITS),
+    ));
+    let (queue, dequeuing) = if options.accept_queue > 0 {
+        let (queue, mut queued) =
+            mpsc::channel::<(Box<dyn Stream>, String, time::Instant)>(options.accept_queue);
+        let permits = permits.clone();
+        let screen = screen.clone();
+        let options = options.clone();
+        let blanks = blanks.clone();
+        let stopping = stopping.clone();
+        let dequeuing = tokio::spawn(async move {
+            let timeout = options.queue_timeout.map(Duration::from_secs);
+            while let Some((stream, peer, queued_at)) = queued.recv().await {
+                // First in first out, so the head is always the first to expire
+                let acquire = permits.clone().acquire_owned();
+                let permit = match timeout {
+                    Some(timeout) => match time::timeout_at(queued_at + timeout, acquire).await {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            info!("Queued too long, drop connection from {}", peer);
+                            continue;
+                        }
+                    },
+                    None => acquire.await,
+                }
+                .unwrap();
+                debug!("Dequeue connection from {}", peer);
+                let (screen, options, blanks) = (screen.clone(), options.clone(), blanks.clone());
+                spawn_client(
+                    stream,
+                    peer,
+                    permit,
+                    screen,
+                    options,
+                    blanks,
+                    stopping.clone(),
+                );
+            }
+        });
+        (Some(queue), Some(dequeuing))
+    } else {
+        (None, None)
+    };
+
+    // Accept on their own tasks, aborted once we return
+    let (sender, mut accepted) = mpsc::channel(1);
+    let mut accepting = JoinSet::new();
+    for listener in listeners {
+        let sender = sender.clone();
+        accepting.spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok(conn) => _ = sender.send(conn).await,
+                    // Socket is not listening, retrying won't help
+                    Err(err) if err.kind() == io::ErrorKind::InvalidInput => return err,
+                    // Only this connection failed
+                    Err(err) if is_connection_error(&err) => {
+                        debug!("Failed to accept client: {}", err)
+                    }
+                    // Mostly EMFILE, wait for some clients to leave
+                    Err(err) => {
+                        warn!("Failed to accept client: {}", err);
+                        time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let mut connecting = options.connect.clone().map(|addr| {
+        let (screen, options, blanks) = (screen.clone(), options.clone(), blanks.clone());
+        tokio::spawn(connect(addr, screen, options, blanks, stopping.clone()))
+    });
+    // The only client if `once`, whose result is ours
+    let mut serving = None;
+    let mut result = Ok(());
+    let mut shutdown = pin!(shutdown);
+    loop {
+        let connected = async {
+            match connecting.as_mut() {
+                Some(connecting) => connecting.await,
+                None => future::pending().await,
+            }
+        };
+        let served = async {
+            match serving.as_mut() {
+                Some(serving) => serving.await,
+                None => future::pending().await,
+            }
+        };
+        let (stream, peer) = tokio::select! {
+            Some(conn) = accepted.recv() => conn,
+            Some(stopped) = accepting.join_next() => {
+                warn!("Stop accepting on a listener: {}", stopped?);
+                if accepting.is_empty() && connecting.is_none() && serving.is_none() {
+                    bail!("No listener to accept clients");
+                }
+                continue;
+            }
+            result = connected => {
+                result?;
+                break;
+            }
+            served = served => {
+                result = served?;
+                break;
+            }
+            () = &mut shutdown => break,
+            else => bail!("No listener to accept clients"),
+        };
+        debug!("Connected with {}", peer);
+
+        if options.once {
+            if serving.is_some() {
+                info!("Serving one client only, drop connection from {}", peer);
+                continue;
+            }
+            let (screen, options) = (screen.clone(), options.clone());
+            let (blanks, stopping) = (blanks.clone(), stopping.clone());
+            serving = Some(tokio::spawn(async move {
+                handle_client(stream, &peer, screen, &options, &blanks, stopping)
+                    .await
+                    .with_context(|| format!("Serve {}", peer))
+            }));
+            continue;
+        }
+
+        let permit = match permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                match queue
+                    .as_ref()
+                    .map(|queue| queue.try_send((stream, peer.clone(), time::Instant::now())))
+                {
+                    Some(Ok(())) => info!("Max clients reached, queue connection from {}", peer),
+                    _ => info!("Max clients reached, drop connection from {}", peer),
+                }
+                continue;
+            }
+        };
+        let (screen, options, blanks) = (screen.clone(), options.clone(), blanks.clone());
+        spawn_client(
+            stream,
+            peer,
+            permit,
+            screen,
+            options,
+            blanks,
+            stopping.clone(),
+        );
+    }
+
+    // Close listeners & queued connections, then tell clients to leave
+    accepting.shutdown().await;
+    if let Some(dequeuing) = dequeuing {
+        dequeuing.abort();
+        _ = dequeuing.await;
+    }
+    drop(stopping);
+    stop.send_replace(true);
+    let clients = stop.receiver_count();
+    if clients > 0 {
+        info!("Waiting for {} clients to disconnect", clients);
+        let timeout = Duration::from_secs(options.shutdown_timeout);
+        if time::timeout(timeout, stop.closed()).await.is_err() {
+            warn!("{} clients still connected", stop.receiver_count());
+        }
+    }
+    result
+}
+
+/// Errors of accept(2) caused by the client, not the listener.
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NetworkDown
+    )
+}
+
+/// Serve the viewer listening at `addr`, reconnecting with backoff until
+/// stopping, or until the first session ends if `connect_once`.
+async fn connect<F: FrameSource>(
+    addr: String,
+    screen: F,
+    options: Arc<Options>,
+    blanks: BlankScreens,
+    mut stopping: watch::Receiver<bool>,
+) {
+    let mut retry = CONNECT_RETRY_MIN;
+    loop {
+        let connected = tokio::select! {
+            result = TcpStream::connect(&addr) => result,
+            _ = stopping.changed() => return,
+        };
+        match connected {
+            Ok(stream) => {
+                info!("Connected to viewer {}", addr);
+                let stopping = stopping.clone();
+                let screen = screen.clone();
+                match handle_client(stream, &addr, screen, &options, &blanks, stopping).await {
+                    Ok(()) => info!("Disconnected with viewer {}", addr),
+                    Err(err) => info!("Error on handle viewer {}: {}", addr, err),
+                }
+                if options.connect_once {
+                    return;
+                }
+                retry = CONNECT_RETRY_MIN;
+            }
+            Err(err) => info!("Failed to connect {}: {}", addr, err),
+        }
+        debug!("Reconnect {} in {:?}", addr, retry);
+        tokio::select! {
+            () = time::sleep(retry) => (),
+            _ = stopping.changed() => return,
+        }
+        retry = (retry * 2).min(CONNECT_RETRY_MAX);
+    }
+}
+
+fn spawn_client<S: Stream, F: FrameSource>(
+    stream: S,
+    peer: String,
+    permit: OwnedSemaphorePermit,
+    screen: F,
+    options: Arc<Options>,
+    blanks: BlankScreens,
+    stopping: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        match handle_client(stream, &peer, screen, &options, &blanks, stopping).await {
+            Ok(()) => debug!("Disconnected with {}", peer),
+            Err(err) => info!("Error on handle {}: {}", peer, err),
+        }
+        drop(permit);
+    });
+}
+
+async fn handle_client<S: Stream, F: FrameSource>(
+    stream: S,
+    peer: &str,
+    mut screen: F,
+    options: &Options,
+    blanks: &BlankScreens,
+    stopping: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "metrics")]
+    let _connection = metrics::Connection::open();
+    let started = Instant::now();
+    let traffic = Arc::new(Traffic::default());
+    let mut stream = Counted {
+        inner: stream,
+        traffic: traffic.clone(),
+    };
+    let dims = screen.dimensions();
+    let profile = options.client_profile.overrides();
+    let format = profile
+        .pixel_format
+        .map_or(options.pixel_format, Into::into);
+    let name = desktop_name(options);
+    let verifier = verifier(options);
+    let handshake = async {
+        #[cfg(feature = "tls")]
+        if let Some(config) = options.tls.clone() {
+            let (stream, version) =
+                vencrypt::handshake(stream, config, dims, &format, &name, verifier).await?;
+            return anyhow::Ok((Box::new(stream) as Box<dyn Stream>, version));
+        }
+        let version = rfp::handshake(&mut stream, dims, &format, &name, verifier).await?;
+        anyhow::Ok((Box::new(stream) as Box<dyn Stream>, version))
+    };
+    let (stream, version) = time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .context("Handshake timed out")?
+        .context("RFP handshaking with client")?;
+    screen.subscribe();
+
+    // Read messages on its own task, so we can wait for new frames meanwhile
+    let (mut reader, writer) = io::split(stream);
+    let mut writer = Paced::new(writer, options.max_bandwidth, peer);
+    let (sender, mut messages) = mpsc::channel(1);
+    // Skip the cut text payload entirely if it would be dropped anyway
+    let read_only = options.read_only;
+    let max_cut_text_len = if read_only {
+        0
+    } else {
+        options.max_clipboard_len
+    };
+    let idle_timeout = Duration::from_secs(options.idle_timeout);
+    // Client waiting on an update from us is not idle
+    let (awaiting, mut awaited) = watch::channel(false);
+    let reader = tokio::spawn(async move {
+        let mut buf = vec![0u8; 0];
+        loop {
+            let mut read = pin!(rfp::read_message(&mut reader, &mut buf, max_cut_text_len));
+            let msg = loop {
+                let waiting = idle_timeout.is_zero() || *awaited.borrow_and_update();
+                tokio::select! {
+                    msg = &mut read => break msg,
+                    // Restart idle time once the update is sent
+                    Ok(()) = awaited.changed() => continue,
+                    () = time::sleep(idle_timeout), if !waiting => {
+                        break Err(anyhow!("Idle for {:?}", idle_timeout));
+                    }
+                }
+            };
+            let Some(msg) = msg.transpose() else {
+                break;
+            };
+            if read_only && matches!(msg, Ok(ref msg) if msg.is_input()) {
+                continue;
+            }
+            let failed = msg.is_err();
+            if sender.send(msg).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    let mut encoder = Encoder::new(
+        screen.dimensions(),
+        encoding_format(format, options),
+        options.raw_band_rows,
+    );
+    let screen = Blanking {
+        inner: screen,
+        blanks: blanks.clone(),
+        blank: None,
+        switched: false,
+    };
+    let result = serve_client(
+        &mut writer,
+        &mut messages,
+        &mut encoder,
+        screen,
+        options,
+        stopping,
+        &awaiting,
+    )
+    .await;
+    reader.abort();
+    info!(
+        peer,
+        version:% = version,
+        encoding:? = encoder.encoding,
+        format:% = encoder.format,
+        bytes_read = traffic.read.load(Ordering::Relaxed),
+        bytes_written = traffic.written.load(Ordering::Relaxed),
+        rtt_ms = encoder.rtt.map(|rtt| rtt.as_millis() as u64),
+        duration_ms = started.elapsed().as_millis() as u64;
+        "Session ended"
+    );
+    result
+}
+
+async fn serve_client<W: AsyncWrite + Unpin, F: FrameSource>(
+    stream: &mut W,
+    messages: &mut mpsc::Receiver<anyhow::Result<ClientMessage>>,
+    encoder: &mut Encoder,
+    mut screen: Blanking<F>,
+    options: &Options,
+    mut stopping: watch::Receiver<bool>,
+    awaiting: &watch::Sender<bool>,
+) -> anyhow::Result<()> {
+    let mut last_input = time::Instant::now();
+    let profile = options.client_profile.overrides();
+    // Flat pictures are covered by few solid rectangles
+    let flat = options.auto_encoding && {
+        let stats = screen::analyze(&screen.frame(), AUTO_RRE_MAX_COLORS);
+        debug!("Colors on screen: {:?}", stats);
+        stats.is_some()
+    };
+    let mut zlib_level = Compression::default();
+    let mut copyrect_supported = false;
+    // Region of incremental update requested but nothing changed yet
+    let mut pending: Option<Rect> = None;
+    // Region to push changes without requests
+    let mut continuous: Option<Rect> = None;
+    let mut continuous_supported = false;
+    let mut qemu_key_events_supported = false;
+    // Pointer buttons & keys pressed on client
+    let mut buttons = 0u8;
+    let mut keys_down = HashSet::new();
+    // Framebuffer content on client, for finding changes
+    let mut sent = screen.frame();
+    // Measure round-trip time with fences carrying send time
+    let start = Instant::now();
+    let mut fence_supported = false;
+    let mut fence_timer = time::interval(FENCE_INTERVAL);
+    fence_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Full updates requested too soon after the last one, sent together later
+    let min_full_interval = options.max_fps.map(|fps| Duration::from_secs(1) / fps);
+    let mut last_full: Option<time::Instant> = None;
+    let mut throttled: Option<Rect> = None;
+    let mut bell_timer = options.bell_interval.map(|secs| {
+        let period = Duration::from_secs(secs);
+        time::interval_at(time::Instant::now() + period, period)
+    });
+    if let Some(text) = options.clipboard.as_ref() {
+        rfp::write_server_cut_text(stream, text).await?;
+    }
+    // Pushed once client has set its pixel format & encodings, which it
+    // sends right after initialization
+    let mut push_initial_frame = profile.push_initial_frame;
+    loop {
+        awaiting.send_if_modified(|waiting| {
+            let was = mem::replace(
+                waiting,
+                pending.is_some() || continuous.is_some() || throttled.is_some(),
+            );
+            was != *waiting
+        });
+        let throttled_until = last_full
+            .zip(min_full_interval)
+            .map_or_else(time::Instant::now, |(last, interval)| last + interval);
+        let blank_at = options
+            .blank_after
+            .filter(|_| screen.blank.is_none())
+            .map(|secs| last_input + Duration::from_secs(secs));
+        let msg = tokio::select! {
+            msg = messages.recv() => match msg {
+                Some(msg) => msg?,
+                None => break,
+            },
+            _ = stopping.changed() => {
+                debug!("Disconnect on server shutdown");
+                break;
+            }
+            _ = fence_timer.tick(), if fence_supported => {
+                let timestamp = start.elapsed().as_micros() as u64;
+                rfp::write_fence(stream, rfp::FENCE_REQUEST, &timestamp.to_be_bytes()).await?;
+                continue;
+            }
+            Some(_) = async { Some(bell_timer.as_mut()?.tick().await) }, if bell_timer.is_some() => {
+                rfp::write_bell(stream).await?;
+                continue;
+            }
+            () = time::sleep_until(throttled_until), if throttled.is_some() => {
+                let region = throttled.take().and_then(|region| {
+                    screen::intersect(region, screen::full_rect(screen.dimensions()))
+                });
+                if let Some(region) = region {
+                    pending = None;
+                    last_full = Some(time::Instant::now());
+                    screen.refresh();
+                    send_update(stream, &screen, &[region], None, encoder).await?;
+                    sent = screen::patch(&sent, &screen.frame(), &[region]);
+                }
+                continue;
+            }
+            () = time::sleep_until(blank_at.unwrap_or_else(time::Instant::now)), if blank_at.is_some() => {
+                debug!("Blank screen on idle");
+                screen.blank(options.blank_color)?;
+                continue;
+            }
+            () = screen.changed(), if pending.is_some() || continuous.is_some() => {
+                let region = if sent.dimensions() == screen.frame().dimensions() {
+                    continuous.or(pending).unwrap_or(screen::full_rect(screen.dimensions()))
+                } else {
+                    // Resized, regions of the old size no longer apply
+                    let full = screen::full_rect(screen.dimensions());
+                    continuous = continuous.map(|_| full);
+                    full
+                };
+                let rects = screen::dirty_rects(&sent, &screen.frame(), region, options.rect_strategy);
+                if rects.is_empty() {
+                    continue;
+                }
+                pending = None;
+                let prev = copyrect_supported.then_some(&*sent);
+                send_update(stream, &screen, &rects, prev, encoder).await?;
+                sent = screen::patch(&sent, &screen.frame(), &rects);
+                continue;
+            }
+        };
+        if msg.is_input() {
+            last_input = time::Instant::now();
+            if screen.blank.is_some() {
+                debug!("Wake screen on input");
+                screen.wake();
+            }
+        }
+        match msg {
+            ClientMessage::SetPixelFormat(format) => {
+                debug!("Client set pixel format: {:?}", format);
+                if !format.true_color_flag && format.colour_cube_levels() < 2 {
+                    bail!("Unsupported pixel format: colour map too small");
+                }
+                encoder.format = encoding_format(format, options);
+                if !format.true_color_flag {
+                    rfp::write_colour_map(stream, &format).await?;
+                }
+            }
+            ClientMessage::SetEncodings(encodings) => {
+                debug!("Client set encodings: {:?}", encodings);
+                let level = compression_level(&encodings).unwrap_or(zlib_level);
+                encoder.encoding = preferred_encoding(&encodings, &profile);
+                if flat && !profile.raw_only && encodings.contains(&rfp::Encoding::Rre) {
+                    debug!("Prefer RRE for few colors on screen");
+                    encoder.encoding = rfp::Encoding::Rre;
+                }
+                if !encodings.contains(&rfp::Encoding::Raw) {
+                    debug!("Client omits Raw encoding, assume it supported anyway");
+                }
+                if encoder.encoding == rfp::Encoding::Zrle {
+                    debug!("Use zlib compression level {}", level.level());
+                    match encoder.zlib.as_mut() {
+                        // ZRLE uses a single zlib stream for the whole connection,
+                        // it cannot be recreated once client has started inflating it.
+                        Some(zlib) => zlib.set_level(level)?,
+                        None => encoder.zlib = Some(ZlibStream::new(level)),
+                    }
+                    zlib_level = level;
+                }
+                if encoder.encoding == rfp::Encoding::Tight {
+                    // Streams in use are reset by client as well
+                    encoder.tight.set_level(level);
+                    zlib_level = level;
+                }
+                encoder.quality = encodings.iter().find_map(|encoding| match encoding {
+                    rfp::Encoding::QualityLevel(level) => Some(*level),
+                    _ => None,
+                });
+                encoder.pointer_supported = encodings.contains(&rfp::Encoding::Cursor);
+                encoder.alpha_cursor_supported = encodings.contains(&rfp::Encoding::AlphaCursor);
+                encoder.desktop_size_supported = encodings.contains(&rfp::Encoding::DesktopSize);
+                let extended = encodings.contains(&rfp::Encoding::ExtendedDesktopSize);
+                // Confirm the support with current layout on next update
+                encoder.layout_pending |= extended && !encoder.extended_desktop_size_supported;
+                encoder.extended_desktop_size_supported = extended;
+                let pointer_pos = encodings.contains(&rfp::Encoding::PointerPos);
+                if pointer_pos && !encoder.pointer_pos_supported {
+                    encoder.pointer_pos = options.cursor_pos;
+                }
+                encoder.pointer_pos_supported = pointer_pos;
+                encoder.last_rect_supported = encodings.contains(&rfp::Encoding::LastRect);
+                copyrect_supported =
+                    !profile.raw_only && encodings.contains(&rfp::Encoding::CopyRect);
+                let fence = encodings.contains(&rfp::Encoding::Fence);
+                if fence && !fence_supported {
+                    // Fence extension: server confirms the support by sending
+                    // a fence, send our first one right away.
+                    fence_timer.reset_immediately();
+                }
+                fence_supported = fence;
+                let continuous_updates = encodings.contains(&rfp::Encoding::ContinuousUpdates);
+                if continuous_updates && !continuous_supported {
+                    // Confirm the support
+                    rfp::write_end_of_continuous_updates(stream).await?;
+                }
+                continuous_supported = continuous_updates;
+                let qemu_key_events = encodings.contains(&rfp::Encoding::QemuExtendedKeyEvent);
+                if qemu_key_events && !qemu_key_events_supported {
+                    // Confirm the support, so client sends keycodes too
+                    let confirm = FrameRectangle::new_qemu_extended_key_event();
+                    rfp::write_frame(stream, &[confirm]).await?;
+                }
+                qemu_key_events_supported = qemu_key_events;
+                if mem::take(&mut push_initial_frame) {
+                    let full = screen::full_rect(screen.dimensions());
+                    send_update(stream, &screen, &[full], None, encoder).await?;
+                }
+            }
+            ClientMessage::FramebufferUpdateRequest {
+                incremental,
+                position: (x, y),
+                size: (width, height),
+            } => {
+                debug!("Receive client message: {:?}", msg);
+                // Requested before we push it, no need any more
+                push_initial_frame = false;
+                if incremental && continuous.is_some() {
+                    // Changes are pushed already
+                    continue;
+                }
+                let Some(region) = screen::intersect(
+                    (x, y, width, height),
+                    screen::full_rect(screen.dimensions()),
+                ) else {
+                    // Zero-area or off-screen request, nothing to draw
+                    pending = None;
+                    rfp::write_frame(stream, &[]).await?;
+                    continue;
+                };
+                if !incremental && min_full_interval.is_some() {
+                    if throttled.is_some() || time::Instant::now() < throttled_until {
+                        debug!("Throttle full update");
+                        throttled = Some(throttled.map_or(region, |r| screen::union(r, region)));
+                        continue;
+                    }
+                    last_full = Some(time::Instant::now());
+                }
+                screen.refresh();
+                let rects = if incremental {
+                    screen::dirty_rects(&sent, &screen.frame(), region, options.rect_strategy)
+                } else {
+                    vec![region]
+                };
+                if rects.is_empty() {
+                    // Wait for next frame
+                    pending = Some(region);
+                    continue;
+                }
+                pending = None;
+                let prev = (incremental && copyrect_supported).then_some(&*sent);
+                send_update(stream, &screen, &rects, prev, encoder).await?;
+                sent = screen::patch(&sent, &screen.frame(), &rects);
+            }
+            ClientMessage::EnableContinuousUpdates {
+                enable,
+                position: (x, y),
+                size: (width, height),
+            } => {
+                debug!("Receive client message: {:?}", msg);
+                if !enable {
+                    continuous = None;
+                    rfp::write_end_of_continuous_updates(stream).await?;
+                    continue;
+                }
+                // Start with the whole region, then its changes
+                pending = None;
+                continuous = screen::intersect(
+                    (x, y, width, height),
+                    screen::full_rect(screen.dimensions()),
+                );
+                if let Some(region) = continuous {
+                    screen.refresh();
+                    send_update(stream, &screen, &[region], None, encoder).await?;
+                    sent = screen::patch(&sent, &screen.frame(), &[region]);
+                }
+            }
+            ClientMessage::Fence { flags, payload } => {
+                if flags & rfp::FENCE_REQUEST != 0 {
+                    // Messages are handled & replied in order, so all flags
+                    // are satisfied already.
+                    let flags = flags
+                        & (rfp::FENCE_BLOCK_BEFORE | rfp::FENCE_BLOCK_AFTER | rfp::FENCE_SYNC_NEXT);
+                    rfp::write_fence(stream, flags, &payload).await?;
+                } else if let Ok(timestamp) = payload.try_into() {
+                    // Response to our fence
+                    let sent = Duration::from_micros(u64::from_be_bytes(timestamp));
+                    let rtt = start.elapsed().saturating_sub(sent);
+                    debug!("Round-trip time: {:?}", rtt);
+                    encoder.rtt = Some(rtt);
+                    #[cfg(feature = "metrics")]
+                    metrics::round_trip(rtt);
+                }
+            }
+            ClientMessage::SetDesktopSize { size, screens } => {
+                debug!(
+                    "Reject resizing to {:?} with {} screens",
+                    size,
+                    screens.len()
+                );
+                let reject = FrameRectangle::new_extended_desktop_size(
+                    rfp::DESKTOP_SIZE_REASON_CLIENT,
+                    rfp::DESKTOP_SIZE_STATUS_PROHIBITED,
+                    screen.dimensions(),
+                    &screen.layout(),
+                );
+                rfp::write_frame(stream, &[reject]).await?;
+            }
+            ClientMessage::Unsupported(message_type) => {
+                if options.strict {
+                    bail!("Unsupported client message: {}", message_type);
+                }
+                debug!("Skip unsupported client message: {}", message_type);
+            }
+            ClientMessage::ClientCutText(text) => {
+                debug!("Client cut text: {} chars", text.chars().count());
+                if let Some(path) = options.dump_clipboard.as_ref() {
+                    dump_clipboard(path, &text).context("Dump client cut text")?;
+                }
+            }
+            ClientMessage::PointerEvent { button_mask, x, y } => {
+                if button_mask != buttons {
+                    debug!("Pointer buttons {:#010b} at ({}, {})", button_mask, x, y);
+                    buttons = button_mask;
+                }
+                // Keep the cursor drawn by client at where it was pointed,
+                // reported along with the next update
+                let shaped = encoder.pointer_supported || encoder.alpha_cursor_supported;
+                if shaped && encoder.pointer_pos_supported {
+                    encoder.pointer_pos = Some((x, y));
+                }
+            }
+            ClientMessage::KeyEvent {
+                down: false,
+                keysym,
+            }
+            | ClientMessage::QemuKeyEvent {
+                down: false,
+                keysym,
+                ..
+            } => {
+                keys_down.remove(&keysym);
+            }
+            ClientMessage::KeyEvent { down: true, keysym }
+            | ClientMessage::QemuKeyEvent {
+                down: true, keysym, ..
+            } => {
+                if !keys_down.insert(keysym) {
+                    continue; // auto-repeat
+                }
+                if Some(keysym) == options.quit_key {
+                    debug!("Quit key pressed");
+                    break;
+                }
+                if Some(keysym) != options.reload_key {
+                    continue;
+                }
+                if let Err(err) = screen.reload() {
+                    warn!("Failed to reload pictures: {:#}", err);
+                    continue;
+                }
+                info!("Pictures reloaded");
+                let Some(region) = continuous.or(pending) else {
+                    continue;
+                };
+                let rects =
+                    screen::dirty_rects(&sent, &screen.frame(), region, options.rect_strategy);
+                if rects.is_empty() {
+                    continue;
+                }
+                pending = None;
+                let prev = copyrect_supported.then_some(&*sent);
+                send_update(stream, &screen, &rects, prev, encoder).await?;
+                sent = screen::patch(&sent, &screen.frame(), &rects);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How to check VNC authentication, if required.
+fn verifier(options: &Options) -> Option<Verifier<'_>> {
+    match (&options.auth_command, &options.password) {
+        (Some(command), _) => Some(Verifier::Command(command)),
+        (None, Some(password)) => Some(Verifier::Password(password)),
+        (None, None) => None,
+    }
+}
+
+/// Desktop name read from `name_file` if any, or just `name`, marked if
+/// `read_only`.
+fn desktop_name(options: &Options) -> Cow<'_, str> {
+    let name = base_desktop_name(options);
+    if options.read_only {
+        format!("{} (read-only)", name).into()
+    } else {
+        name
+    }
+}
+
+fn base_desktop_name(options: &Options) -> Cow<'_, str> {
+    let Some(path) = options.name_file.as_ref() else {
+        return options.name.as_str().into();
+    };
+    match fs::read_to_string(path) {
+        Ok(text) if !text.trim().is_empty() => text.trim().to_string().into(),
+        Ok(_) => {
+            warn!("Empty name file {}", path.display());
+            options.name.as_str().into()
+        }
+        Err(err) => {
+            warn!("Failed to read name file {}: {}", path.display(), err);
+            options.name.as_str().into()
+        }
+    }
+}
+
+/// Format to encode pixels in for client asking this one, with colors cut
+/// to RGB565 precision if `force_16bpp`.
+fn encoding_format(format: PixelFormat, options: &Options) -> PixelFormat {
+    if options.force_16bpp {
+        format.with_precision(FORCED_PRECISION)
+    } else {
+        format
+    }
+}
+
+/// Append text with a timestamp line to file.
+fn dump_clipboard(path: &Path, text: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let timestamp = humantime::format_rfc3339_seconds(SystemTime::now());
+    file.write_all(format!("--- {}\n{}\n", timestamp, text).as_bytes())
+}
+
+/// Screen seen by a client, replaced by a solid frame while blanked.
+#[derive(Clone)]
+struct Blanking<F> {
+    inner: F,
+    blanks: BlankScreens,
+    blank: Option<Screen>,
+    /// Switched between blank & content, not yet reported by `changed`
+    switched: bool,
+}
+
+/// Blank screens of recent colors & dimensions, most recent last. Shared by
+/// the clients of a `serve*` call, so a blank frame is encoded once per
+/// format, not per client, and dropped once serving ends.
+#[derive(Clone, Default)]
+struct BlankScreens(Arc<Mutex<Vec<BlankScreen>>>);
+
+type BlankScreen = (Rgb<u8>, Screen);
+
+const BLANK_SCREENS_MAX: usize = 4;
+
+impl BlankScreens {
+    fn get(&self, color: Rgb<u8>, dimensions: (u16, u16)) -> anyhow::Result<Screen> {
+        let mut screens = self.0.lock().unwrap();
+        let found = screens
+            .iter()
+            .find(|(c, screen)| *c == color && screen.dimensions() == dimensions);
+        if let Some((_, screen)) = found {
+            return Ok(screen.clone());
+        }
+        let (width, height) = dimensions;
+        let frame = RgbImage::from_pixel(width.into(), height.into(), color);
+        let screen = Screen::from_image(frame, None)?;
+        if screens.len() >= BLANK_SCREENS_MAX {
+            screens.remove(0);
+        }
+        screens.push((color, screen.clone()));
+        Ok(screen)
+    }
+}
+
+impl<F: FrameSource> Blanking<F> {
+    fn blank(&mut self, color: Rgb<u8>) -> anyhow::Result<()> {
+        self.blank = Some(self.blanks.get(color, self.inner.dimensions())?);
+        self.switched = true;
+        Ok(())
+    }
+
+    fn wake(&mut self) {
+        if self.blank.take().is_some() {
+            self.inner.refresh();
+            self.switched = true;
+        }
+    }
+}
+
+/// Cursor comes from content even while blanked, all else from the active one.
+impl<F: FrameSource> FrameSource for Blanking<F> {
+    fn frame(&self) -> Arc<RgbImage> {
+        match &self.blank {
+            Some(blank) => blank.frame(),
+            None => self.inner.frame(),
+        }
+    }
+
+    fn dimensions(&self) -> (u16, u16) {
+        match &self.blank {
+            Some(blank) => blank.dimensions(),
+            None => self.inner.dimensions(),
+        }
+    }
+
+    fn subscribe(&mut self) {
+        self.inner.subscribe();
+    }
+
+    async fn changed(&mut self) {
+        if mem::take(&mut self.switched) {
+            return;
+        }
+        match self.blank {
+            Some(_) => future::pending().await,
+            None => self.inner.changed().await,
+        }
+    }
+
+    fn refresh(&mut self) -> bool {
+        self.blank.is_none() && self.inner.refresh()
+    }
+
+    fn reload(&mut self) -> anyhow::Result<()> {
+        self.inner.reload()
+    }
+
+    fn encode(
+        &self,
+        format: &PixelFormat,
+        encoding: rfp::Encoding,
+        zlib: Option<&mut ZlibStream>,
+        rect: Rect,
+    ) -> anyhow::Result<FrameRectangle> {
+        match &self.blank {
+            Some(blank) => blank.encode(format, encoding, zlib, rect),
+            None => self.inner.encode(format, encoding, zlib, rect),
+        }
+    }
+
+    fn encode_tight(
+        &self,
+        format: &PixelFormat,
+        quality: Option<u8>,
+        streams: &mut TightStreams,
+        rect: Rect,
+    ) -> anyhow::Result<Vec<FrameRectangle>> {
+        match &self.blank {
+            Some(blank) => blank.encode_tight(format, quality, streams, rect),
+            None => self.inner.encode_tight(format, quality, streams, rect),
+        }
+    }
+
+    fn cursor(&self, format: &PixelFormat) -> Option<FrameRectangle> {
+        self.inner.cursor(format)
+    }
+
+    fn alpha_cursor(&self) -> Option<FrameRectangle> {
+        self.inner.alpha_cursor()
+    }
+
+    fn layout(&self) -> Vec<rfp::ScreenLayout> {
+        match &self.blank {
+            Some(blank) => blank.layout(),
+            None => self.inner.layout(),
+        }
+    }
+}
+
+/// How rectangles are sent to a client, as negotiated by SetEncodings.
+struct Encoder {
+    format: PixelFormat,
+    encoding: rfp::Encoding,
+    /// ZRLE needs the zlib stream, never replaced once used
+    zlib: Option<ZlibStream>,
+    tight: TightStreams,
+    /// JPEG quality level for Tight, None for lossless
+    quality: Option<u8>,
+    pointer_supported: bool,
+    alpha_cursor_supported: bool,
+    desktop_size_supported: bool,
+    extended_desktop_size_supported: bool,
+    /// Screen layout not yet sent since client enabled ExtendedDesktopSize
+    layout_pending: bool,
+    pointer_pos_supported: bool,
+    /// Cursor position to send on next update
+    pointer_pos: Option<(u16, u16)>,
+    last_rect_supported: bool,
+    /// Framebuffer size known by client
+    dimensions: (u16, u16),
+    /// Split Raw rectangles into bands of at most this rows
+    raw_band_rows: u16,
+    /// Last round-trip time measured by fences
+    rtt: Option<Duration>,
+}
+
+impl Encoder {
+    /// Raw encoding in server's pixel format until client asks otherwise.
+    fn new(dimensions: (u16, u16), format: PixelFormat, raw_band_rows: u16) -> Self {
+        Self {
+            format,
+            encoding: rfp::Encoding::Raw,
+            zlib: None,
+            tight: TightStreams::new(Compression::default()),
+            quality: None,
+            pointer_supported: false,
+            alpha_cursor_supported: false,
+            desktop_size_supported: false,
+            extended_desktop_size_supported: false,
+            layout_pending: false,
+            pointer_pos_supported: false,
+            pointer_pos: None,
+            last_rect_supported: false,
+            dimensions,
+            raw_band_rows,
+            rtt: None,
+        }
+    }
+}
+
+/// First encoding of rectangles in client's order of preference that we
+/// can send. Pseudo-encodings are capabilities, not choices here.
+///
+/// RFC6143 §7.7.1: Raw must be supported by all clients, fall back to it
+/// when nothing else is listed, even if client omits it.
+fn preferred_encoding(encodings: &[rfp::Encoding], profile: &Profile) -> rfp::Encoding {
+    if profile.raw_only {
+        return rfp::Encoding::Raw;
+    }
+    encodings
+        .iter()
+        .copied()
+        .find(|encoding| {
+            matches!(
+                encoding,
+                rfp::Encoding::Zrle
+                    | rfp::Encoding::Tight
+                    | rfp::Encoding::Trle
+                    | rfp::Encoding::Hextile
+                    | rfp::Encoding::Rre
+                    | rfp::Encoding::Raw
+            )
+        })
+        .unwrap_or(rfp::Encoding::Raw)
+}
+
+/// zlib level asked by the CompressionLevel pseudo-encoding, if listed.
+fn compression_level(encodings: &[rfp::Encoding]) -> Option<Compression> {
+    encodings
+        .iter()
+        .find_map(|encoding| match encoding {
+            rfp::Encoding::CompressionLevel(level) => Some(*level),
+            _ => None,
+        })
+        .map(|level| Compression::new(level.into()))
+}
+
+/// Bytes transferred on a connection.
+#[derive(Default)]
+struct Traffic {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+/// Stream counting bytes through it, shared by its split halves.
+struct Counted<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let len = (buf.filled().len() - filled) as u64;
+        self.traffic.read.fetch_add(len, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let len = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.traffic
+            .written
+            .fetch_add(len as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::bytes_sent(len as u64);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Writer held back by a token bucket to `rate` bytes per second, if any.
+/// Bucket holds [`BANDWIDTH_BURST`] of it, so large updates go out in
+/// chunks of that size.
+struct Paced<W> {
+    inner: W,
+    rate: Option<u64>,
+    /// Bytes allowed to write right away
+    tokens: u64,
+    refilled: time::Instant,
+    sleep: Pin<Box<time::Sleep>>,
+    peer: String,
+    /// Whether any write has been held back, logged the first time
+    held: bool,
+}
+
+impl<W> Paced<W> {
+    fn new(inner: W, rate: Option<u64>, peer: &str) -> Self {
+        let mut paced = Self {
+            inner,
+            rate,
+            tokens: 0,
+            refilled: time::Instant::now(),
+            sleep: Box::pin(time::sleep(Duration::ZERO)),
+            peer: peer.into(),
+            held: false,
+        };
+        paced.tokens = paced.burst();
+        paced
+    }
+
+    fn burst(&self) -> u64 {
+        let rate = self.rate.unwrap_or(u64::MAX);
+        ((rate as f64 * BANDWIDTH_BURST.as_secs_f64()) as u64).max(1)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Paced<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(rate) = this.rate else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        let burst = this.burst();
+        let wanted = (buf.len() as u64).min(burst);
+        loop {
+            let now = time::Instant::now();
+            let refill = (now - this.refilled).as_secs_f64() * rate as f64;
+            // Keep accumulating time until a whole byte is earned
+            if refill >= 1.0 {
+                this.tokens = this.tokens.saturating_add(refill as u64).min(burst);
+                this.refilled = now;
+            }
+            if this.tokens >= wanted {
+                break;
+            }
+            if !this.held {
+                info!("Pacing writes to {} at {} bytes/s", this.peer, rate);
+                this.held = true;
+            }
+            let wait = (wanted - this.tokens) as f64 / rate as f64;
+            let deadline = now + Duration::from_secs_f64(wait);
+            this.sleep.as_mut().reset(deadline);
+            ready!(this.sleep.as_mut().poll(cx));
+        }
+        let len = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..wanted as usize]))?;
+        this.tokens -= len as u64;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Send given areas of screen, preceded by new framebuffer size if changed.
+/// Areas moved from elsewhere of prev, the client's framebuffer, are sent
+/// as CopyRect if prev is given.
+async fn send_update<W: AsyncWrite + Unpin, F: FrameSource>(
+    stream: &mut W,
+    screen: &F,
+    rects: &[Rect],
+    prev: Option<&RgbImage>,
+    encoder: &mut Encoder,
+) -> anyhow::Result<()> {
+    let mut frame = Vec::with_capacity(rects.len() + 2);
+    let dimensions = screen.dimensions();
+    let resized = encoder.dimensions != dimensions;
+    if encoder.extended_desktop_size_supported && (resized || encoder.layout_pending) {
+        frame.push(FrameRectangle::new_extended_desktop_size(
+            rfp::DESKTOP_SIZE_REASON_SERVER,
+            rfp::DESKTOP_SIZE_STATUS_OK,
+            dimensions,
+            &screen.layout(),
+        ));
+        encoder.layout_pending = false;
+        encoder.dimensions = dimensions;
+    } else if encoder.desktop_size_supported && resized {
+        frame.push(FrameRectangle::new_desktop_size(dimensions));
+        encoder.dimensions = dimensions;
+    }
+    // CopyRects go first, reading client's framebuffer before other rects
+    // change it. Skip sources already overwritten by previous CopyRects.
+    let curr = screen.frame();
+    let mut copied: Vec<Rect> = Vec::new();
+    let mut rest = Vec::with_capacity(rects.len());
+    for &rect in rects {
+        let src = prev
+            .and_then(|prev| screen::copy_source(prev, &curr, rect))
+            .filter(|&(x, y)| {
+                let src = (x, y, rect.2, rect.3);
+                copied
+                    .iter()
+                    .all(|&dst| screen::intersect(src, dst).is_none())
+            });
+        match src {
+            Some(src) => {
+                frame.push(FrameRectangle::new_copyrect(rect, src));
+                copied.push(rect);
+            }
+            None => rest.push(rect),
+        }
+    }
+    // Raw is encoded band by band on writing, so neither whole frame nor
+    // single write is as large as the framebuffer
+    let mut bands = Vec::new();
+    for rect in rest {
+        if encoder.encoding == rfp::Encoding::Raw {
+            let (x, y, width, height) = rect;
+            let rows = encoder.raw_band_rows.max(1);
+            bands.extend(
+                (0..height)
+                    .step_by(rows.into())
+                    .map(|dy| (x, y + dy, width, rows.min(height - dy))),
+            );
+            continue;
+        }
+        if encoder.encoding == rfp::Encoding::Tight {
+            let (format, quality) = (&encoder.format, encoder.quality);
+            frame.extend(screen.encode_tight(format, quality, &mut encoder.tight, rect)?);
+            continue;
+        }
+        let zlib = encoder.zlib.as_mut();
+        frame.push(screen.encode(&encoder.format, encoder.encoding, zlib, rect)?);
+    }
+    // Prefer alpha cursor, falling back to the 1-bit mask one
+    let cursor = encoder
+        .alpha_cursor_supported
+        .then(|| screen.alpha_cursor())
+        .flatten()
+        .or_else(|| {
+            encoder
+                .pointer_supported
+                .then(|| screen.cursor(&encoder.format))
+                .flatten()
+        });
+    let mut tail = Vec::with_capacity(2);
+    if let Some(cursor) = cursor {
+        tail.push(cursor);
+    }
+    // After the shape, if both are sent
+    if let Some(position) = encoder.pointer_pos.take() {
+        tail.push(FrameRectangle::new_pointer_pos(position));
+    }
+    let count = frame.len() + bands.len() + tail.len();
+    let format = encoder.format;
+    let bands = bands
+        .into_iter()
+        .map(|band| screen.encode(&format, rfp::Encoding::Raw, None, band));
+    let rects = frame
+        .into_iter()
+        .map(Ok)
+        .chain(bands)
+        .chain(tail.into_iter().map(Ok));
+    if encoder.last_rect_supported {
+        rfp::write_frame_streaming_iter(stream, rects).await?;
+    } else {
+        rfp::write_frame_iter(stream, count, rects).await?;
+    }
+    #[cfg(feature = "metrics")]
+    metrics::frame_sent();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::rfb_client::Client;
+
+    fn gradient(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 6) as u8, (y * 8) as u8, ((x + y) * 3) as u8])
+        })
+    }
+
+    #[tokio::test]
+    async fn pipe_raw_update() {
+        let image = gradient(40, 30);
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        let (mut pipe, session) = serve_pipe(screen, Options::default());
+
+        let mut version = [0; 12];
+        pipe.read_exact(&mut version).await.unwrap();
+        assert_eq!(&version, b"RFB 003.008\n");
+        pipe.write_all(&version).await.unwrap();
+        // Security types, only None offered without password
+        assert_eq!(pipe.read_u8().await.unwrap(), 1);
+        assert_eq!(pipe.read_u8().await.unwrap(), 1);
+        pipe.write_u8(1).await.unwrap();
+        assert_eq!(pipe.read_u32().await.unwrap(), 0);
+        // ClientInit, shared
+        pipe.write_u8(1).await.unwrap();
+
+        // ServerInit
+        assert_eq!(pipe.read_u16().await.unwrap(), 40);
+        assert_eq!(pipe.read_u16().await.unwrap(), 30);
+        let mut format = [0; 16];
+        pipe.read_exact(&mut format).await.unwrap();
+        let [bits_per_pixel, _, big_endian, true_color, ..] = format;
+        assert_eq!((bits_per_pixel, big_endian, true_color), (32, 0, 1));
+        let shifts = [format[10], format[11], format[12]];
+        let mut name = vec![0; pipe.read_u32().await.unwrap() as usize];
+        pipe.read_exact(&mut name).await.unwrap();
+        assert_eq!(name, b"VNC Display");
+
+        // FramebufferUpdateRequest, non-incremental, whole screen
+        pipe.write_all(&[3, 0, 0, 0, 0, 0, 0, 40, 0, 30])
+            .await
+            .unwrap();
+
+        // FramebufferUpdate with one raw rectangle
+        assert_eq!(pipe.read_u8().await.unwrap(), 0);
+        pipe.read_u8().await.unwrap();
+        assert_eq!(pipe.read_u16().await.unwrap(), 1);
+        let mut header = [0; 12];
+        pipe.read_exact(&mut header).await.unwrap();
+        assert_eq!(header, [0, 0, 0, 0, 0, 40, 0, 30, 0, 0, 0, 0]);
+        let mut pixels = vec![0; 40 * 30 * 4];
+        pipe.read_exact(&mut pixels).await.unwrap();
+        let decoded = RgbImage::from_fn(40, 30, |x, y| {
+            let offset = (y * 40 + x) as usize * 4;
+            let pixel = u32::from_le_bytes(pixels[offset..offset + 4].try_into().unwrap());
+            Rgb(shifts.map(|shift| (pixel >> shift) as u8))
+        });
+        assert!(decoded == image);
+
+        drop(pipe);
+        session.await.unwrap().ok();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paced_frame() {
+        // 4 + 12 + 4984 bytes, the first 100ms of it is sent at once
+        let rect = FrameRectangle::new_raw_frame((0, 0), (89, 14), vec![0; 4984]);
+        let mut writer = Paced::new(io::sink(), Some(10_000), "test");
+        let started = time::Instant::now();
+        rfp::write_frame(&mut writer, &[rect]).await.unwrap();
+        writer.flush().await.unwrap();
+        // The rest 4000 bytes at 10 bytes/ms, timers rounding up to a ms
+        let elapsed = started.elapsed();
+        assert!(
+            (Duration::from_millis(400)..=Duration::from_millis(401)).contains(&elapsed),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    async fn connect(screen: Screen, options: Options) -> Client<DuplexStream> {
+        let (pipe, _session) = serve_pipe(screen, options);
+        Client::connect(pipe, rfp::RfpVersion::V3_8).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn change_compression_level() {
+        // Many colors, but repeating for zlib to find
+        let image = RgbImage::from_fn(200, 100, |x, y| {
+            Rgb([(x % 16 * 16) as u8, (y % 8 * 32) as u8, (x / 16) as u8])
+        });
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        for encoding in [rfp::Encoding::Zrle, rfp::Encoding::Tight] {
+            let (pipe, _session) = serve_pipe(screen.clone(), Options::default());
+            // Count bytes of each update to see the level in effect
+            let traffic = Arc::new(Traffic::default());
+            let pipe = Counted {
+                inner: pipe,
+                traffic: traffic.clone(),
+            };
+            let mut client = Client::connect(pipe, rfp::RfpVersion::V3_8).await.unwrap();
+            let mut sizes = Vec::new();
+            for level in [9, 0, 6] {
+                let encodings = [encoding, rfp::Encoding::CompressionLevel(level)];
+                client.set_encodings(&encodings).await.unwrap();
+                client
+                    .request_update(false, (0, 0), (200, 100))
+                    .await
+                    .unwrap();
+                let read = traffic.read.load(Ordering::Relaxed);
+                client.read_update().await.unwrap();
+                assert!(client.framebuffer() == &image, "{:?} {}", encoding, level);
+                sizes.push(traffic.read.load(Ordering::Relaxed) - read);
+            }
+            // Stored as is at level 0
+            assert!(sizes[1] > sizes[0] * 2, "{:?} {:?}", encoding, sizes);
+            assert!(sizes[1] > sizes[2] * 2, "{:?} {:?}", encoding, sizes);
+        }
+    }
+
+    #[test]
+    fn compression_level_pseudo_encodings() {
+        let level = |value| compression_level(&[rfp::Encoding::Zrle, rfp::Encoding::from(value)]);
+        // -256 to -247 for levels 0 to 9, taken as is
+        for n in 0..=9 {
+            assert_eq!(level(-256 + n), Some(Compression::new(n as u32)));
+        }
+        assert_eq!(compression_level(&[rfp::Encoding::Zrle]), None);
+    }
+
+    #[tokio::test]
+    async fn zrle_at_level_one() {
+        let image = gradient(300, 200);
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        let mut client = connect(screen, Options::default()).await;
+        let encodings = [rfp::Encoding::Zrle, rfp::Encoding::CompressionLevel(1)];
+        client.set_encodings(&encodings).await.unwrap();
+        for _ in 0..3 {
+            client
+                .request_update(false, (0, 0), (300, 200))
+                .await
+                .unwrap();
+            assert_eq!(client.read_update().await.unwrap(), [rfp::Encoding::Zrle]);
+            assert!(client.framebuffer() == &image);
+        }
+    }
+
+    #[test]
+    fn client_order_of_encodings() {
+        use rfp::Encoding::*;
+        let profile = ClientProfile::Default.overrides();
+        let encodings = [Cursor, Hextile, Zrle, Raw];
+        assert_eq!(preferred_encoding(&encodings, &profile), Hextile);
+        assert_eq!(preferred_encoding(&[Zrle, Hextile], &profile), Zrle);
+        assert_eq!(
+            preferred_encoding(&[Other(99), Tight, Zrle], &profile),
+            Tight
+        );
+    }
+
+    /// Framebuffer decoded by client of pixel format & encodings.
+    async fn decoded(
+        screen: &Screen,
+        format: PixelFormat,
+        encodings: &[rfp::Encoding],
+    ) -> (Vec<rfp::Encoding>, RgbImage) {
+        let mut client = connect(screen.clone(), Options::default()).await;
+        client.set_pixel_format(format).await.unwrap();
+        client.set_encodings(encodings).await.unwrap();
+        client
+            .request_update(false, (0, 0), screen.dimensions())
+            .await
+            .unwrap();
+        let sent = client.read_update().await.unwrap();
+        (sent, client.framebuffer().clone())
+    }
+
+    #[tokio::test]
+    async fn raw_implicitly_supported() {
+        use rfp::Encoding::*;
+        let profile = ClientProfile::Default.overrides();
+        assert_eq!(preferred_encoding(&[], &profile), Raw);
+        assert_eq!(preferred_encoding(&[CopyRect, LastRect], &profile), Raw);
+
+        let screen = Screen::from_image(gradient(70, 40), None).unwrap();
+        let indexed = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            true_color_flag: false,
+            ..Default::default()
+        };
+        let (sent, raw) = decoded(&screen, indexed, &[CopyRect]).await;
+        assert_eq!(sent, [Raw]);
+        // Colour map pixels are not packed as CPIXEL, nothing falls back
+        let (sent, zrle) = decoded(&screen, indexed, &[Zrle]).await;
+        assert_eq!(sent, [Zrle]);
+        assert!(zrle == raw);
+    }
+
+    #[tokio::test]
+    async fn zrle_of_16bpp() {
+        let screen = Screen::from_image(gradient(70, 40), None).unwrap();
+        let format = *rfp::PIXEL_FORMAT_RGB565;
+        let (sent, raw) = decoded(&screen, format, &[rfp::Encoding::Raw]).await;
+        assert_eq!(sent, [rfp::Encoding::Raw]);
+        // CPIXEL is the 2-byte PIXEL, not 3 bytes cut from it
+        let (sent, zrle) = decoded(&screen, format, &[rfp::Encoding::Zrle]).await;
+        assert_eq!(sent, [rfp::Encoding::Zrle]);
+        assert!(zrle == raw);
+    }
+
+    /// Frame source counting encoded rectangles.
+    #[derive(Clone)]
+    struct Counting {
+        screen: Screen,
+        encoded: Arc<AtomicU64>,
+    }
+
+    impl FrameSource for Counting {
+        fn frame(&self) -> Arc<RgbImage> {
+            self.screen.frame()
+        }
+
+        fn encode(
+            &self,
+            format: &PixelFormat,
+            encoding: rfp::Encoding,
+            zlib: Option<&mut ZlibStream>,
+            rect: Rect,
+        ) -> anyhow::Result<FrameRectangle> {
+            self.encoded.fetch_add(1, Ordering::Relaxed);
+            self.screen.encode(format, encoding, zlib, rect)
+        }
+    }
+
+    #[tokio::test]
+    async fn encode_once_after_negotiation() {
+        let encoded = Arc::new(AtomicU64::new(0));
+        let source = Counting {
+            screen: Screen::from_image(gradient(16, 16), None).unwrap(),
+            encoded: encoded.clone(),
+        };
+        let (pipe, _session) = serve_pipe(source, Options::default());
+        let mut client = Client::connect(pipe, rfp::RfpVersion::V3_8).await.unwrap();
+        for _ in 0..2 {
+            client
+                .set_pixel_format(*rfp::PIXEL_FORMAT_RGB565)
+                .await
+                .unwrap();
+            client.set_encodings(&[rfp::Encoding::Zrle]).await.unwrap();
+            client
+                .set_pixel_format(*rfp::PIXEL_FORMAT_RGB888)
+                .await
+                .unwrap();
+            client
+                .set_encodings(&[rfp::Encoding::Hextile])
+                .await
+                .unwrap();
+        }
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(encoded.load(Ordering::Relaxed), 0);
+        client
+            .request_update(false, (0, 0), (16, 16))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.read_update().await.unwrap(),
+            [rfp::Encoding::Hextile]
+        );
+        assert_eq!(encoded.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn auto_encoding_of_two_colors() {
+        let image = RgbImage::from_fn(32, 32, |x, _| {
+            if x < 10 {
+                Rgb([1, 2, 3])
+            } else {
+                Rgb([200, 0, 0])
+            }
+        });
+        let stats = screen::analyze(&image, AUTO_RRE_MAX_COLORS).unwrap();
+        assert_eq!((stats.colors, stats.dominant), (2, Rgb([200, 0, 0])));
+        assert!(screen::analyze(&gradient(32, 32), AUTO_RRE_MAX_COLORS).is_none());
+
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        let encodings = [rfp::Encoding::Zrle, rfp::Encoding::Raw, rfp::Encoding::Rre];
+        for (auto_encoding, expected) in [(true, rfp::Encoding::Rre), (false, rfp::Encoding::Zrle)]
+        {
+            let options = Options {
+                auto_encoding,
+                ..Default::default()
+            };
+            let mut client = connect(screen.clone(), options).await;
+            client.set_encodings(&encodings).await.unwrap();
+            client
+                .request_update(false, (0, 0), (32, 32))
+                .await
+                .unwrap();
+            assert_eq!(client.read_update().await.unwrap(), [expected]);
+            assert!(client.framebuffer() == &image);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_over_max_clients() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let screen = Screen::from_image(gradient(8, 8), None).unwrap();
+        let options = Options {
+            max_clients: Some(2),
+            ..Default::default()
+        };
+        let listeners = [Listener::Tcp(listener)];
+        let server = tokio::spawn(serve_until(listeners, screen, options, future::pending()));
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let client = Client::connect(stream, rfp::RfpVersion::V3_8).await;
+            clients.push(client.unwrap());
+        }
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(Client::connect(stream, rfp::RfpVersion::V3_8)
+            .await
+            .is_err());
+        server.abort();
+    }
+
+    #[test]
+    fn share_blank_screen() {
+        let blanks = BlankScreens::default();
+        let blanking = |image, blanks: &BlankScreens| Blanking {
+            inner: Screen::from_image(image, None).unwrap(),
+            blanks: blanks.clone(),
+            blank: None,
+            switched: false,
+        };
+        let mut a = blanking(gradient(24, 16), &blanks);
+        let mut b = blanking(RgbImage::new(24, 16), &blanks);
+        a.blank(Rgb([1, 2, 250])).unwrap();
+        b.blank(Rgb([1, 2, 250])).unwrap();
+        assert!(Arc::ptr_eq(&a.frame(), &b.frame()));
+        assert_eq!(a.frame().get_pixel(23, 15), &Rgb([1, 2, 250]));
+
+        b.wake();
+        b.blank(Rgb([1, 2, 251])).unwrap();
+        assert!(!Arc::ptr_eq(&a.frame(), &b.frame()));
+        let mut c = blanking(gradient(16, 24), &blanks);
+        c.blank(Rgb([1, 2, 250])).unwrap();
+        assert_eq!(c.dimensions(), (16, 24));
+
+        // Nothing shared across serve calls
+        let mut d = blanking(gradient(24, 16), &BlankScreens::default());
+        d.blank(Rgb([1, 2, 250])).unwrap();
+        assert!(!Arc::ptr_eq(&a.frame(), &d.frame()));
+        drop((a, b, c, d));
+        assert_eq!(Arc::strong_count(&blanks.0), 1);
+    }
+
+    #[tokio::test]
+    async fn not_idle_while_update_pending() {
+        let options = Options {
+            idle_timeout: 1,
+            ..Default::default()
+        };
+        let screen = Screen::from_image(gradient(16, 16), None).unwrap();
+        let (pipe, session) = serve_pipe(screen, options);
+        let mut client = Client::connect(pipe, rfp::RfpVersion::V3_8).await.unwrap();
+        // Nothing changes on a still screen, so the request stays pending
+        client.request_update(true, (0, 0), (16, 16)).await.unwrap();
+        time::sleep(Duration::from_millis(1500)).await;
+        assert!(!session.is_finished());
+
+        client
+            .request_update(false, (0, 0), (16, 16))
+            .await
+            .unwrap();
+        client.read_update().await.unwrap();
+        time::sleep(Duration::from_millis(1500)).await;
+        assert!(session.is_finished());
+        let err = session.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Idle for 1s");
+    }
+
+    /// Unix socket accept(2) fails with EINVAL, as it is not listening.
+    #[cfg(unix)]
+    fn broken_listener() -> Listener {
+        let (socket, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let listener = std::os::unix::net::UnixListener::from(std::os::fd::OwnedFd::from(socket));
+        listener.set_nonblocking(true).unwrap();
+        Listener::Unix(tokio::net::UnixListener::from_std(listener).unwrap(), None)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn keep_other_listeners_on_failure() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let screen = Screen::from_image(gradient(8, 8), None).unwrap();
+        let listeners = [broken_listener(), Listener::Tcp(listener)];
+        let options = Options::default();
+        let server = tokio::spawn(serve_until(
+            listeners,
+            screen.clone(),
+            options,
+            future::pending(),
+        ));
+        time::sleep(Duration::from_millis(50)).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        Client::connect(stream, rfp::RfpVersion::V3_8)
+            .await
+            .unwrap();
+        assert!(!server.is_finished());
+        server.abort();
+
+        let options = Options::default();
+        let err = serve_until([broken_listener()], screen, options, future::pending())
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "No listener to accept clients");
+    }
+
+    #[tokio::test]
+    async fn macos_profile() {
+        let image = gradient(32, 24);
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        let options = Options {
+            client_profile: ClientProfile::Macos,
+            pixel_format: *rfp::PIXEL_FORMAT_RGB565,
+            ..Default::default()
+        };
+        let mut client = connect(screen, options).await;
+        assert_eq!(client.format(), rfp::PIXEL_FORMAT_RGB888);
+        let encodings = [rfp::Encoding::Zrle, rfp::Encoding::CopyRect];
+        client.set_encodings(&encodings).await.unwrap();
+        // Pushed without request
+        assert_eq!(client.read_update().await.unwrap(), [rfp::Encoding::Raw]);
+        assert!(client.framebuffer() == &image);
+        client.request_update(true, (0, 0), (32, 24)).await.unwrap();
+        client
+            .request_update(false, (0, 0), (32, 24))
+            .await
+            .unwrap();
+        assert_eq!(client.read_update().await.unwrap(), [rfp::Encoding::Raw]);
+    }
+
+    #[tokio::test]
+    async fn push_initial_frame_in_client_format() {
+        // Exact in RGB565 as well
+        let image = RgbImage::from_fn(32, 24, |x, _| Rgb([0xff * (x % 2) as u8; 3]));
+        let screen = Screen::from_image(image.clone(), None).unwrap();
+        let options = Options {
+            client_profile: ClientProfile::Tigervnc,
+            ..Default::default()
+        };
+        let mut client = connect(screen, options).await;
+        client
+            .set_pixel_format(*rfp::PIXEL_FORMAT_RGB565)
+            .await
+            .unwrap();
+        client.set_encodings(&[rfp::Encoding::Zrle]).await.unwrap();
+        assert_eq!(client.read_update().await.unwrap(), [rfp::Encoding::Zrle]);
+        assert!(client.framebuffer() == &image);
+    }
+}
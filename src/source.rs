@@ -0,0 +1,550 @@
+//! Live content published by a producer, e.g. a child process.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    pin::pin,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    imageops, AnimationDecoder, DynamicImage, ImageFormat, ImageReader, RgbImage, RgbaImage,
+};
+use log::{error, info, warn};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::{Child, ChildStdout, Command},
+    sync::{mpsc, watch, Notify},
+    task, time,
+};
+
+/// Delay before restarting an exited command
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Wait for this quiet period after a watched file changes, so it's
+/// completely written before reading
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+/// Retry reading a changed file a few times, in case it's still written
+const RELOAD_RETRIES: u32 = 3;
+const RELOAD_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Like browsers, animation frames shorter than this are shown for
+/// `DEFAULT_FRAME_DELAY` instead
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+/// Latest frame, shared between the producer and all clients.
+pub struct Frames {
+    sender: watch::Sender<Arc<RgbImage>>,
+    subscribed: Notify,
+}
+
+impl Frames {
+    pub fn new(first: impl Into<Arc<RgbImage>>) -> Self {
+        Self {
+            sender: watch::Sender::new(first.into()),
+            subscribed: Notify::new(),
+        }
+    }
+
+    pub fn current(&self) -> Arc<RgbImage> {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<RgbImage>> {
+        let receiver = self.sender.subscribe();
+        self.subscribed.notify_waiters();
+        receiver
+    }
+
+    /// Wait until at least one client is watching.
+    pub async fn wait_for_subscriber(&self) {
+        loop {
+            let mut notified = pin!(self.subscribed.notified());
+            notified.as_mut().enable();
+            if self.sender.receiver_count() > 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Publish new frame, return the previous one.
+    pub fn publish(&self, frame: impl Into<Arc<RgbImage>>) -> Arc<RgbImage> {
+        self.sender.send_replace(frame.into())
+    }
+}
+
+/// Run command and publish binary PPM (P6) frames written to its stdout.
+/// Return after the first frame is received.
+pub async fn exec(command: String, restart: bool) -> anyhow::Result<Arc<Frames>> {
+    let (child, mut stdout) = spawn(&command)?;
+    let first = read_ppm(&mut stdout)
+        .await
+        .context("Read first frame from command")?
+        .context("Command exited without any frame")?;
+    let frames = Arc::new(Frames::new(first));
+    tokio::spawn(pump(frames.clone(), command, restart, child, stdout));
+    Ok(frames)
+}
+
+async fn pump(
+    frames: Arc<Frames>,
+    command: String,
+    restart: bool,
+    mut child: Child,
+    mut stdout: BufReader<ChildStdout>,
+) {
+    let dimensions = frames.current().dimensions();
+    loop {
+        // Stop reading when no one is watching, the child will then block
+        // on writing its stdout.
+        frames.wait_for_subscriber().await;
+        match read_ppm(&mut stdout).await {
+            Ok(Some(frame)) if frame.dimensions() == dimensions => {
+                frames.publish(frame);
+            }
+            Ok(Some(frame)) => warn!(
+                "Drop frame of {:?} from command, expect {:?}",
+                frame.dimensions(),
+                dimensions
+            ),
+            result => {
+                if let Err(err) = result {
+                    warn!("Invalid frame from command: {:#}", err);
+                    let _ = child.start_kill();
+                }
+                match child.wait().await {
+                    Ok(status) => info!("Command exited with {}", status),
+                    Err(err) => warn!("Wait for command: {}", err),
+                }
+                if !restart {
+                    info!("Keep serving the last frame");
+                    return;
+                }
+                time::sleep(RESTART_DELAY).await;
+                match spawn(&command) {
+                    Ok(spawned) => (child, stdout) = spawned,
+                    Err(err) => {
+                        warn!("Restart command: {:#}", err);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Publish copies of background with `draw` applied every second.
+pub fn clock(
+    background: RgbImage,
+    mut draw: impl FnMut(&mut RgbImage, SystemTime) + Send + 'static,
+) -> Arc<Frames> {
+    let mut first = background.clone();
+    draw(&mut first, SystemTime::now());
+    let frames = Arc::new(Frames::new(first));
+    tokio::spawn(tick(frames.clone(), background, draw));
+    frames
+}
+
+async fn tick(
+    frames: Arc<Frames>,
+    background: RgbImage,
+    mut draw: impl FnMut(&mut RgbImage, SystemTime),
+) {
+    let mut spare: Option<Arc<RgbImage>> = None;
+    loop {
+        // At the start of next second
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let subsec = Duration::from_nanos(since_epoch.subsec_nanos().into());
+        time::sleep(Duration::from_secs(1) - subsec).await;
+        frames.wait_for_subscriber().await;
+        // Reuse the frame before the last one, if no client holds it
+        let mut frame = match spare.take().map(Arc::try_unwrap) {
+            Some(Ok(mut frame)) => {
+                frame.copy_from_slice(&background);
+                frame
+            }
+            _ => background.clone(),
+        };
+        draw(&mut frame, SystemTime::now());
+        spare = Some(frames.publish(frame));
+    }
+}
+
+/// Publish picture processed by `compose`, again every time it's changed.
+pub fn watch(
+    path: PathBuf,
+    compose: impl Fn(DynamicImage) -> anyhow::Result<RgbImage> + Send + Sync + 'static,
+) -> anyhow::Result<Arc<Frames>> {
+    let path = fs::canonicalize(path)?;
+    let first = compose(decode(&path)?)?;
+    let frames = Arc::new(Frames::new(first));
+    // Watch the directory, as editors may replace the file instead of
+    // writing to it
+    let (sender, changes) = mpsc::unbounded_channel();
+    let name = path.file_name().map(ToOwned::to_owned);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else { return };
+        if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && event.paths.iter().any(|p| p.file_name() == name.as_deref())
+        {
+            let _ = sender.send(());
+        }
+    })?;
+    let dir = path.parent().context("Picture without parent directory")?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    info!("Watch {} for changes", path.display());
+    tokio::spawn(reload(frames.clone(), path, compose, watcher, changes));
+    Ok(frames)
+}
+
+async fn reload(
+    frames: Arc<Frames>,
+    path: PathBuf,
+    compose: impl Fn(DynamicImage) -> anyhow::Result<RgbImage> + Send + Sync + 'static,
+    _watcher: RecommendedWatcher,
+    mut changes: mpsc::UnboundedReceiver<()>,
+) {
+    let compose = Arc::new(compose);
+    while changes.recv().await.is_some() {
+        loop {
+            match time::timeout(WATCH_DEBOUNCE, changes.recv()).await {
+                Ok(Some(())) => continue,
+                Ok(None) => return,
+                Err(_) => break,
+            }
+        }
+        for attempt in 1..=RELOAD_RETRIES {
+            let (picture, compose) = (path.clone(), compose.clone());
+            let result = task::spawn_blocking(move || compose(decode(&picture)?))
+                .await
+                .unwrap_or_else(|err| Err(err.into()));
+            match result {
+                Ok(frame) => {
+                    info!("Reloaded {}", path.display());
+                    frames.publish(frame);
+                    break;
+                }
+                Err(err) if attempt < RELOAD_RETRIES => {
+                    warn!("Reload {}: {:#}, retry", path.display(), err);
+                    time::sleep(RELOAD_RETRY_DELAY).await;
+                }
+                Err(err) => error!(
+                    "Reload {}: {:#}, keep the previous one",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+    }
+}
+
+fn decode(path: &Path) -> anyhow::Result<DynamicImage> {
+    let picture = ImageReader::open(path)
+        .context("Read picture")?
+        .with_guessed_format()?
+        .decode()
+        .context("Decode picture")?;
+    if picture.width() > 0xffff || picture.height() > 0xffff {
+        bail!("Width & height must less than 65536");
+    }
+    Ok(picture)
+}
+
+/// Play frames of animated GIF, APNG, or WebP picture, each processed by
+/// `compose`. Return None if the picture is not animated.
+pub fn animate(
+    data: &[u8],
+    mut compose: impl FnMut(DynamicImage) -> anyhow::Result<RgbImage>,
+) -> anyhow::Result<Option<Arc<Frames>>> {
+    let format = image::guess_format(data).ok();
+    // Frames are decoded onto the full canvas, whatever their own sizes
+    let decoded = match format {
+        Some(ImageFormat::Gif) => GifDecoder::new(io::Cursor::new(&data))?.into_frames(),
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(io::Cursor::new(&data))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            decoder.apng()?.into_frames()
+        }
+        Some(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(io::Cursor::new(&data))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    let mut animation = Vec::new();
+    for frame in decoded {
+        let frame = frame?;
+        let mut delay = Duration::from(frame.delay());
+        if delay < MIN_FRAME_DELAY {
+            delay = DEFAULT_FRAME_DELAY;
+        }
+        let image = compose(DynamicImage::ImageRgba8(frame.into_buffer()))?;
+        animation.push((Arc::new(image), delay));
+    }
+    if animation.len() < 2 {
+        return Ok(None);
+    }
+    let plays = plays(data, format);
+    info!(
+        "Play animation of {} frames {:?} times",
+        animation.len(),
+        plays
+    );
+    let frames = Arc::new(Frames::new(animation[0].0.clone()));
+    tokio::spawn(play(frames.clone(), animation, plays));
+    Ok(Some(frames))
+}
+
+/// Cycle through pictures in directory, each processed by `compose`.
+pub fn slideshow(
+    dir: &Path,
+    interval: Duration,
+    mut compose: impl FnMut(DynamicImage) -> anyhow::Result<RgbImage>,
+) -> anyhow::Result<Arc<Frames>> {
+    let mut paths = fs::read_dir(dir)
+        .context("Read slideshow directory")?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+    let mut pictures = Vec::new();
+    for path in paths.into_iter().filter(|path| path.is_file()) {
+        let reader = ImageReader::open(&path)?.with_guessed_format()?;
+        if reader.format().is_none() {
+            warn!("Skip non-picture {}", path.display());
+            continue;
+        }
+        let picture = reader
+            .decode()
+            .with_context(|| format!("Decode {}", path.display()))?;
+        if picture.width() > 0xffff || picture.height() > 0xffff {
+            bail!("Width & height of {} must less than 65536", path.display());
+        }
+        pictures.push(picture);
+    }
+    let width = pictures.iter().map(|p| p.width()).max();
+    let height = pictures.iter().map(|p| p.height()).max();
+    let (Some(width), Some(height)) = (width, height) else {
+        bail!("No picture in slideshow directory");
+    };
+    let mut slides = Vec::with_capacity(pictures.len());
+    for picture in pictures {
+        // Letterbox with transparent pixels, to be flattened by `compose`
+        let mut canvas = RgbaImage::new(width, height);
+        let x = (width - picture.width()) / 2;
+        let y = (height - picture.height()) / 2;
+        imageops::overlay(&mut canvas, &picture.into_rgba8(), x.into(), y.into());
+        let slide = compose(DynamicImage::ImageRgba8(canvas))?;
+        slides.push((Arc::new(slide), interval));
+    }
+    info!("Show slideshow of {} pictures", slides.len());
+    let frames = Arc::new(Frames::new(slides[0].0.clone()));
+    if slides.len() > 1 {
+        tokio::spawn(play(frames.clone(), slides, None));
+    }
+    Ok(frames)
+}
+
+/// Number of times to play animation, None for forever.
+fn plays(data: &[u8], format: Option<ImageFormat>) -> Option<u32> {
+    let find = |needle: &[u8]| {
+        let start = data.windows(needle.len()).position(|w| w == needle)?;
+        Some(&data[start + needle.len()..])
+    };
+    if format == Some(ImageFormat::Gif) {
+        // NETSCAPE2.0 application extension: sub-block of 3 bytes, id 1,
+        // then loop count after the first play, 0 for forever.
+        match find(b"NETSCAPE2.0\x03\x01") {
+            Some([lo, hi, ..]) => match u16::from_le_bytes([*lo, *hi]) {
+                0 => None,
+                n => Some(u32::from(n) + 1),
+            },
+            _ => Some(1),
+        }
+    } else if format == Some(ImageFormat::WebP) {
+        // ANIM chunk: chunk size, background color, then loop count, 0 for
+        // forever.
+        let anim = find(b"ANIM")?;
+        let plays = u16::from_le_bytes(anim.get(8..10)?.try_into().unwrap());
+        (plays > 0).then_some(plays.into())
+    } else {
+        // acTL chunk: num_frames, then num_plays, 0 for forever.
+        let actl = find(b"acTL")?;
+        let plays = u32::from_be_bytes(actl.get(4..8)?.try_into().unwrap());
+        (plays > 0).then_some(plays)
+    }
+}
+
+async fn play(frames: Arc<Frames>, animation: Vec<(Arc<RgbImage>, Duration)>, plays: Option<u32>) {
+    let mut played = 0;
+    for (index, (_, delay)) in animation.iter().enumerate().cycle() {
+        // Pause when no one is watching
+        frames.wait_for_subscriber().await;
+        time::sleep(*delay).await;
+        let next = (index + 1) % animation.len();
+        if next == 0 {
+            played += 1;
+            if plays.is_some_and(|plays| played >= plays) {
+                // Stay at the last frame
+                return;
+            }
+        }
+        frames.publish(animation[next].0.clone());
+    }
+}
+
+fn spawn(command: &str) -> anyhow::Result<(Child, BufReader<ChildStdout>)> {
+    let mut child = shell(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Spawn command")?;
+    let stdout = child.stdout.take().context("Open stdout of command")?;
+    info!("Command started: {}", command);
+    Ok((child, BufReader::new(stdout)))
+}
+
+/// Command line run by the system shell.
+pub(crate) fn shell(command: &str) -> Command {
+    #[cfg(unix)]
+    let mut shell = Command::new("sh");
+    #[cfg(unix)]
+    shell.arg("-c");
+    #[cfg(windows)]
+    let mut shell = Command::new("cmd");
+    #[cfg(windows)]
+    shell.arg("/C");
+    shell.arg(command);
+    shell
+}
+
+/// Read one binary PPM picture, or None if reached EOF before it.
+async fn read_ppm<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<RgbImage>> {
+    let Some(magic) = read_token(reader).await? else {
+        return Ok(None);
+    };
+    if magic != b"P6" {
+        bail!("Not a binary PPM picture");
+    }
+    let mut header = [0u32; 3];
+    for value in header.iter_mut() {
+        let token = read_token(reader).await?.context("Truncated PPM header")?;
+        *value = std::str::from_utf8(&token)?.parse()?;
+    }
+    let [width, height, max] = header;
+    if width > 0xffff || height > 0xffff {
+        bail!("Width & height must less than 65536");
+    }
+    if !(1..=255).contains(&max) {
+        bail!("Unsupported PPM maxval {}", max);
+    }
+    let mut buf = vec![0u8; width as usize * height as usize * 3];
+    reader.read_exact(&mut buf).await?;
+    if max < 255 {
+        for value in buf.iter_mut() {
+            *value = (*value as u32 * 255 / max).min(255) as u8;
+        }
+    }
+    let image = RgbImage::from_raw(width, height, buf).context("Invalid PPM size")?;
+    Ok(Some(image))
+}
+
+/// Read a whitespace-separated token of PPM header, skipping comments.
+/// Exactly one whitespace after the token is consumed.
+async fn read_token<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut token = Vec::new();
+    loop {
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && token.is_empty() => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        match byte {
+            b'#' if token.is_empty() => {
+                let mut comment = Vec::new();
+                reader.read_until(b'\n', &mut comment).await?;
+            }
+            byte if byte.is_ascii_whitespace() => {
+                if !token.is_empty() {
+                    return Ok(Some(token));
+                }
+            }
+            byte => token.push(byte),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, Rgba};
+
+    use super::*;
+
+    /// APNG of a red frame, then a blue square drawn onto it.
+    fn two_frame_apng() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 4, 4);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_animated(2, 1).unwrap();
+        encoder.set_frame_delay(1, 10).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(&RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255])))
+            .unwrap();
+        // Smaller than the canvas
+        writer.set_frame_dimension(2, 2).unwrap();
+        writer.set_frame_position(1, 1).unwrap();
+        writer
+            .write_image_data(&RgbaImage::from_pixel(2, 2, Rgba([0, 0, 255, 255])))
+            .unwrap();
+        writer.finish().unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn animate_apng() {
+        let frames = animate(&two_frame_apng(), |frame| Ok(frame.into_rgb8()))
+            .unwrap()
+            .unwrap();
+        let red = Rgb([255, 0, 0]);
+        assert!(frames.current().pixels().all(|&pixel| pixel == red));
+
+        let mut receiver = frames.subscribe();
+        time::timeout(Duration::from_secs(1), receiver.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        let second = receiver.borrow_and_update().clone();
+        assert_eq!(second.dimensions(), (4, 4));
+        for (x, y, pixel) in second.enumerate_pixels() {
+            let inside = (1..3).contains(&x) && (1..3).contains(&y);
+            let expected = if inside { Rgb([0, 0, 255]) } else { red };
+            assert_eq!(pixel, &expected, "({}, {})", x, y);
+        }
+
+        // Still pictures are not animated
+        let mut still = io::Cursor::new(Vec::new());
+        RgbaImage::new(4, 4)
+            .write_to(&mut still, ImageFormat::Png)
+            .unwrap();
+        assert!(animate(still.get_ref(), |frame| Ok(frame.into_rgb8()))
+            .unwrap()
+            .is_none());
+    }
+}
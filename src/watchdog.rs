@@ -0,0 +1,49 @@
+//! Detect stuck event loop, e.g. starved by encoding a huge frame.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use tokio::time::{self, MissedTickBehavior};
+
+/// Spawn a heartbeat task on current runtime, and a thread that warns if
+/// the heartbeat stops for longer than `threshold`.
+pub fn spawn(threshold: Duration) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let heartbeat = Arc::new(AtomicU64::new(0)); // ms since start
+
+    let beat = heartbeat.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(threshold / 2);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            beat.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+    });
+
+    thread::Builder::new()
+        .name("watchdog".into())
+        .spawn(move || {
+            let mut stalled = false;
+            loop {
+                thread::sleep(threshold / 2);
+                let last = Duration::from_millis(heartbeat.load(Ordering::Relaxed));
+                let elapsed = start.elapsed().saturating_sub(last);
+                if elapsed > threshold && !stalled {
+                    warn!("Event loop made no progress for {:?}", elapsed);
+                    stalled = true;
+                } else if elapsed <= threshold && stalled {
+                    info!("Event loop recovered");
+                    stalled = false;
+                }
+            }
+        })?;
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! RFB over WebSocket, for noVNC and other browser clients.
+//!
+//! Clients pick the framing with the `Sec-WebSocket-Protocol` header:
+//! - "binary", or no subprotocol as noVNC since 1.0: RFB bytes are carried
+//!   in binary messages.
+//! - "base64", used by noVNC before 1.0: RFB bytes are base64-encoded in
+//!   text messages.
+//!
+//! "binary" is chosen if both are offered. Message boundaries mean nothing
+//! to RFB, so the RFB layer just sees a byte stream.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures_util::{Sink, Stream};
+use log::debug;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        error::ProtocolError,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue, StatusCode},
+        Bytes, Error, Message,
+    },
+    WebSocketStream,
+};
+
+type Upgrading = Pin<Box<dyn Future<Output = io::Result<Open>> + Send>>;
+
+/// Byte stream over a WebSocket connection, upgraded from HTTP on first
+/// read or write.
+pub struct WebSocket {
+    state: State,
+}
+
+enum State {
+    Upgrading(Upgrading),
+    Open(Box<Open>),
+    Closed,
+}
+
+struct Open {
+    ws: WebSocketStream<TcpStream>,
+    base64: bool,
+    /// Rest of the last received message
+    received: Bytes,
+    /// Length of the sent message being flushed
+    sending: Option<usize>,
+}
+
+impl WebSocket {
+    /// Wrap a newly accepted connection, still speaking HTTP.
+    pub fn accept(stream: TcpStream) -> Self {
+        Self {
+            state: State::Upgrading(Box::pin(upgrade(stream))),
+        }
+    }
+
+    fn poll_open(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&mut Open>> {
+        if let State::Upgrading(upgrading) = &mut self.state {
+            self.state = match ready!(upgrading.as_mut().poll(cx)) {
+                Ok(open) => State::Open(Box::new(open)),
+                Err(err) => {
+                    self.state = State::Closed;
+                    return Poll::Ready(Err(err));
+                }
+            };
+        }
+        match &mut self.state {
+            State::Open(open) => Poll::Ready(Ok(open)),
+            _ => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        }
+    }
+}
+
+async fn upgrade(stream: TcpStream) -> io::Result<Open> {
+    let mut base64 = false;
+    // Error response type is up to tungstenite
+    #[allow(clippy::result_large_err)]
+    let negotiate = |request: &Request, mut response: Response| {
+        let offered: Vec<_> = request
+            .headers()
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        let protocol = if offered.contains(&"binary") {
+            "binary"
+        } else if offered.contains(&"base64") {
+            base64 = true;
+            "base64"
+        } else if offered.is_empty() {
+            return Ok(response);
+        } else {
+            debug!("Unsupported WebSocket subprotocols {:?}", offered);
+            let mut response = ErrorResponse::new(Some("Unsupported subprotocol".into()));
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return Err(response);
+        };
+        response
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+        Ok(response)
+    };
+    let ws = tokio_tungstenite::accept_hdr_async(stream, negotiate)
+        .await
+        .map_err(into_io)?;
+    Ok(Open {
+        ws,
+        base64,
+        received: Bytes::new(),
+        sending: None,
+    })
+}
+
+fn into_io(err: Error) -> io::Error {
+    match err {
+        Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+impl AsyncRead for WebSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let open = ready!(self.get_mut().poll_open(cx))?;
+        while open.received.is_empty() {
+            // Ping & pong are answered by tungstenite itself
+            open.received = match ready!(Pin::new(&mut open.ws).poll_next(cx)) {
+                None
+                | Some(Err(
+                    Error::ConnectionClosed
+                    | Error::AlreadyClosed
+                    | Error::Protocol(ProtocolError::ResetWithoutClosingHandshake),
+                )) => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(into_io(err))),
+                Some(Ok(Message::Close(_))) => return Poll::Ready(Ok(())),
+                Some(Ok(Message::Binary(data))) if !open.base64 => data,
+                Some(Ok(Message::Text(text))) if open.base64 => BASE64
+                    .decode(text.as_str())
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+                    .into(),
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(_)) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Message type mismatches subprotocol",
+                    )));
+                }
+            };
+        }
+        let len = buf.remaining().min(open.received.len());
+        buf.put_slice(&open.received.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WebSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let open = ready!(self.get_mut().poll_open(cx))?;
+        // Callers never flush as TCP doesn't need it, so send each write
+        // right away. Being retried after pending means the same buffer.
+        if open.sending.is_none() {
+            ready!(Pin::new(&mut open.ws).poll_ready(cx)).map_err(into_io)?;
+            let msg = if open.base64 {
+                Message::text(BASE64.encode(buf))
+            } else {
+                Message::binary(buf.to_vec())
+            };
+            Pin::new(&mut open.ws).start_send(msg).map_err(into_io)?;
+            open.sending = Some(buf.len());
+        }
+        ready!(Pin::new(&mut open.ws).poll_flush(cx)).map_err(into_io)?;
+        Poll::Ready(Ok(open.sending.take().unwrap()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let open = ready!(self.get_mut().poll_open(cx))?;
+        Pin::new(&mut open.ws).poll_flush(cx).map_err(into_io)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let open = ready!(self.get_mut().poll_open(cx))?;
+        Pin::new(&mut open.ws).poll_close(cx).map_err(into_io)
+    }
+}